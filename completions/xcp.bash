@@ -21,12 +21,23 @@ _xcp() {
     -v
     -w
     -L
+    -a
+    -p
+    -u
     "$(_parse_help "$1" -h)" # long options will be parsed from `--help`
   )
   local units='B K M G' # in line with most completions prefer M to MB/MiB
   local drivers='parfile parblock'
   local reflink='auto always never'
+  local sparse='auto always never'
   local backup='none numbered auto'
+  local hash='sha256 blake3 xxh3'
+  local verify='none cached uncached'
+  local update='all none older'
+  local dryrun='human json'
+  local progress='bar lines none json'
+  local ionice='realtime best-effort idle'
+  local preserve='mode ownership timestamps links xattr acl flags all'
 
   case "$prev" in
   -h | --help) return ;;
@@ -42,11 +53,65 @@ _xcp() {
     return
     ;;
 
+  --bwlimit | --read-bwlimit | --write-bwlimit | --min-size | --max-size)
+    local num="${cur%%[^0-9]*}"
+    local unit="${cur##*[0-9]}"
+    [[ -n $num ]] && COMPREPLY=($(compgen -P "$num" -W "$units" -- "$unit"))
+    return
+    ;;
+
+  --ionice)
+    COMPREPLY=($(compgen -W "$ionice" -- "$cur"))
+    return
+    ;;
+
   --reflink)
     COMPREPLY=($(compgen -W "$reflink" -- "$cur"))
     return
     ;;
 
+  --sparse)
+    COMPREPLY=($(compgen -W "$sparse" -- "$cur"))
+    return
+    ;;
+
+  --preserve | --no-preserve)
+    local prefix="" # complete the last element of the comma-separated list
+    [[ $cur == *,* ]] && prefix="${cur%,*},"
+    COMPREPLY=($(compgen -P "$prefix" -W "$preserve" -- "${cur##*,}"))
+    return
+    ;;
+
+  --hash)
+    COMPREPLY=($(compgen -W "$hash" -- "$cur"))
+    return
+    ;;
+
+  --manifest | --journal | --backup-dir | --exclude-from | --newer-than | --older-than)
+    _filedir
+    return
+    ;;
+
+  --verify)
+    COMPREPLY=($(compgen -W "$verify" -- "$cur"))
+    return
+    ;;
+
+  --update)
+    COMPREPLY=($(compgen -W "$update" -- "$cur"))
+    return
+    ;;
+
+  --dry-run)
+    COMPREPLY=($(compgen -W "$dryrun" -- "$cur"))
+    return
+    ;;
+
+  --progress)
+    COMPREPLY=($(compgen -W "$progress" -- "$cur"))
+    return
+    ;;
+
   --backup)
     COMPREPLY=($(compgen -W "$backup" -- "$cur"))
     return
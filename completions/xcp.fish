@@ -9,6 +9,32 @@ set -l reflinks '
   never\t"always perform a full data copy"
 '
 
+set -l sparse '
+  auto\t"preserve holes in the source (default)"
+  always\t"also convert blocks of zeros into holes"
+  never\t"fully allocate the target file"
+'
+
+set -l preserve 'mode ownership timestamps links xattr acl flags all'
+
+set -l verify '
+  none\t"do not verify (default)"
+  cached\t"compare checksums, possibly reading from the page cache"
+  uncached\t"compare checksums, bypassing the page cache"
+'
+
+set -l hash '
+  sha256\t"compatible with sha256sum (default)"
+  blake3\t"compatible with b3sum"
+  xxh3\t"compatible with xxhsum -H3"
+'
+
+set -l update '
+  all\t"replace all existing files (default)"
+  none\t"never replace existing files"
+  older\t"replace files older than the source"
+'
+
 set -l backup '
   none\t"no backups (default)"
   numbered\t"follow the semantics of cp numbered backups"
@@ -24,19 +50,66 @@ complete -c xcp -s f -l force -d 'Compatibility only option'
 complete -c xcp -s r -l recursive -d 'Copy directories recursively'
 complete -c xcp -s v -l verbose -d 'Increase verbosity (can be repeated)'
 complete -c xcp -s w -l workers -d 'Workers for recursive copies (0=auto)' -x -a '(seq 0 (getconf _NPROCESSORS_ONLN))'
-complete -c xcp -s L -l dereference -d 'Dereference symlinks in source'
+complete -c xcp -s L -l dereference -d 'Dereference all symlinks in source'
+complete -c xcp -s P -l no-dereference -d 'Never dereference symlinks in source'
+complete -c xcp -s H -d 'Dereference symlinks given as sources'
+complete -c xcp -l copy-unsafe-links -d 'Copy the targets of unsafe symlinks'
+complete -c xcp -l safe-links -d 'Skip unsafe symlinks'
 complete -c xcp -s o -l ownership -d 'Copy ownship (user/group)'
+complete -c xcp -s a -l archive -d 'Copy recursively and preserve all attributes'
+complete -c xcp -s p -d 'Preserve mode, ownership and timestamps'
+complete -c xcp -s u -d 'Only replace files older than the source'
 
 # long
 complete -c xcp -l fsync -d 'Sync each file to disk after it is written'
 complete -c xcp -l target-directory -d 'Copy into a subdirectory of the target'
-complete -c xcp -l gitignore -d 'Use .gitignore if present'
+complete -c xcp -l gitignore -d 'Honour .gitignore and other ignore files'
+complete -c xcp -l exclude -d 'Exclude files matching PATTERN' -x
+complete -c xcp -l include -d 'Include files matching PATTERN' -x
+complete -c xcp -l exclude-from -d 'Read exclude patterns from FILE' -r -F
+complete -c xcp -l filter -d 'Add a filter rule' -x
+complete -c xcp -s m -l prune-empty-dirs -d 'Do not create empty directories'
+complete -c xcp -l min-size -d 'Only copy files of at least SIZE' -x -a '(seq 1 16){K,M,G}'
+complete -c xcp -l max-size -d 'Only copy files of at most SIZE' -x -a '(seq 1 16){K,M,G}'
+complete -c xcp -l newer-than -d 'Only copy files modified after TIME or FILE' -r -F
+complete -c xcp -l older-than -d 'Only copy files modified before TIME or FILE' -r -F
+complete -c xcp -l type -d 'Only copy files of TYPE' -x
+complete -c xcp -s x -l one-file-system -d 'Stay on the source filesystems'
+complete -c xcp -l max-depth -d 'Descend at most N levels below the source directories' -x
+complete -c xcp -l min-depth -d 'Only copy files at least N levels below the source directories' -x
 complete -c xcp -l no-perms -d 'Do not copy file permissions'
 complete -c xcp -l no-timestamps -d 'Do not copy file timestamps'
+complete -c xcp -l hard-links -d 'Preserve hard links within the copied tree'
+complete -c xcp -l preserve -d 'Attributes to preserve' -x -a "$preserve"
+complete -c xcp -l no-preserve -d 'Attributes not to preserve' -x -a "$preserve"
 complete -c xcp -l no-progress -d 'Disable progress bar'
+complete -c xcp -l progress -d 'How to report progress' -x -a 'bar lines none json'
+complete -c xcp -l progress-fd -d 'File descriptor for lines and json progress' -x
 complete -c xcp -l block-size -d 'Block size for file operations' -x -a '(seq 1 16){B,K,M,G}'
 complete -c xcp -l driver -d 'Parallelise at the file or at the block level' -x -a "$drivers"
 complete -c xcp -l reflink -d 'Whether and how to use reflinks' -x -a "$reflinks"
+complete -c xcp -l sparse -d 'Whether and how to create sparse files' -x -a "$sparse"
+complete -c xcp -l verify -d 'Verify copied files against the source' -x -a "$verify"
+complete -c xcp -l manifest -d 'Write a checksum manifest of copied files' -r -F
+complete -c xcp -l hash -d 'Hash algorithm for the manifest' -x -a "$hash"
+complete -c xcp -l update -d 'Only replace files that are out of date' -x -a "$update"
+complete -c xcp -l modify-window -d 'Tolerance in seconds when comparing modification times' -x
+complete -c xcp -l checksum -d 'Compare file contents rather than modification times'
+complete -c xcp -l dry-run -d 'Show what would be copied, without copying' -x -a 'human json'
+complete -c xcp -l keep-going -d 'Continue after errors'
+complete -c xcp -l bwlimit -d 'Limit the copy bandwidth' -x -a '(seq 1 16){K,M,G}'
+complete -c xcp -l read-bwlimit -d 'Limit the bandwidth of reads' -x -a '(seq 1 16){K,M,G}'
+complete -c xcp -l write-bwlimit -d 'Limit the bandwidth of writes to the target' -x -a '(seq 1 16){K,M,G}'
+complete -c xcp -l background -d 'Run as a low-impact background copy'
+complete -c xcp -l ionice -d 'IO scheduling priority of the copy workers' -x -a 'realtime best-effort idle'
+complete -c xcp -l nice -d 'CPU niceness of the copy workers' -x
+complete -c xcp -l move -d 'Move files rather than copying them'
+complete -c xcp -l delete -d 'Delete extraneous files from the target'
+complete -c xcp -l delete-dry-run -d 'Show what --delete would remove'
+complete -c xcp -l backup-dir -d 'Move deleted files into this directory' -x -a '(__fish_complete_directories)'
+complete -c xcp -l atomic -d 'Write files atomically via a temporary file'
+complete -c xcp -l journal -d 'Journal completed operations to allow resuming' -r -F
+complete -c xcp -l resume -d 'Resume an interrupted copy from the journal'
 complete -c xcp -l backup -d 'Whether to create backups of overwritten files' -x -a "$backup"
 
 # docs: https://fishshell.com/docs/current/completions.html
@@ -15,24 +15,35 @@
  */
 
 
-use log::{debug, warn};
-use rustix::fs::{fsync, ftruncate};
+use log::debug;
+use rustix::fs::{fsync, ftruncate, seek, utimensat, AtFlags, SeekFrom, Timespec, Timestamps, CWD};
 use rustix::io::{pread, pwrite};
 use std::cmp;
-use std::fs::{File, FileTimes};
+use std::ffi::OsStr;
+use std::fs::{File, FileTimes, Metadata};
 use std::io::{ErrorKind, Read, Write};
-use std::os::unix::fs::{fchown, MetadataExt};
+use std::os::unix::fs::{chown, fchown, lchown, FileExt as _, MetadataExt};
 use std::path::Path;
 use xattr::FileExt;
 
 use crate::errors::{Result, Error};
-use crate::{Extent, XATTR_SUPPORTED, copy_sparse, probably_sparse, copy_file_bytes};
+use crate::{Extent, XATTR_SUPPORTED, copy_sparse, probably_sparse, copy_file_bytes, punch_hole};
 
-fn copy_xattr(infd: &File, outfd: &File) -> Result<()> {
-    // FIXME: Flag for xattr.
+// POSIX ACLs are stored as xattrs on Linux; they are handled
+// separately so they can be preserved independently.
+const ACL_XATTRS: [&str; 2] = ["system.posix_acl_access", "system.posix_acl_default"];
+
+fn is_acl(attr: &OsStr) -> bool {
+    ACL_XATTRS.iter().any(|a| attr == *a)
+}
+
+fn copy_xattr_filtered(infd: &File, outfd: &File, acl: bool) -> Result<()> {
     if XATTR_SUPPORTED {
         debug!("Starting xattr copy...");
         for attr in infd.list_xattr()? {
+            if is_acl(&attr) != acl {
+                continue;
+            }
             if let Some(val) = infd.get_xattr(&attr)? {
                 debug!("Copy xattr {attr:?}");
                 outfd.set_xattr(attr, val.as_slice())?;
@@ -42,20 +53,38 @@ fn copy_xattr(infd: &File, outfd: &File) -> Result<()> {
     Ok(())
 }
 
-/// Copy file permissions. Will also copy
-/// [xattr](https://man7.org/linux/man-pages/man7/xattr.7.html)'s if
-/// possible.
-pub fn copy_permissions(infd: &File, outfd: &File) -> Result<()> {
-    let xr = copy_xattr(infd, outfd);
-    if let Err(e) = xr {
-        // FIXME: We don't have a way of detecting if the
-        // target FS supports XAttr, so assume any error is
-        // "Unsupported" for now.
-        warn!("Failed to copy xattrs from {infd:?}: {e}");
+// NOTE: The xattr path functions do not follow symlinks.
+fn copy_xattr_path_filtered(from: &Path, to: &Path, acl: bool) -> Result<()> {
+    if XATTR_SUPPORTED {
+        debug!("Starting xattr copy...");
+        for attr in xattr::list(from)? {
+            if is_acl(&attr) != acl {
+                continue;
+            }
+            if let Some(val) = xattr::get(from, &attr)? {
+                debug!("Copy xattr {attr:?}");
+                xattr::set(to, attr, val.as_slice())?;
+            }
+        }
     }
+    Ok(())
+}
+
+/// Copy file [xattrs](https://man7.org/linux/man-pages/man7/xattr.7.html),
+/// excluding any ACLs.
+pub fn copy_xattrs(infd: &File, outfd: &File) -> Result<()> {
+    copy_xattr_filtered(infd, outfd, false)
+}
 
-    // FIXME: ACLs, selinux, etc.
+/// Copy file [ACLs](https://man7.org/linux/man-pages/man5/acl.5.html).
+/// This is currently only supported on Linux, where they are stored as
+/// xattrs.
+pub fn copy_acl(infd: &File, outfd: &File) -> Result<()> {
+    copy_xattr_filtered(infd, outfd, true)
+}
 
+/// Copy file permissions (mode).
+pub fn copy_permissions(infd: &File, outfd: &File) -> Result<()> {
     let inmeta = infd.metadata()?;
 
     debug!("Performing permissions copy");
@@ -84,6 +113,72 @@ pub fn copy_owner(infd: &File, outfd: &File) -> Result<()> {
     Ok(())
 }
 
+/// Copy xattrs between paths, excluding any ACLs. Symlinks are not
+/// followed.
+pub fn copy_xattrs_path(from: &Path, to: &Path) -> Result<()> {
+    copy_xattr_path_filtered(from, to, false)
+}
+
+/// Copy ACLs between paths. Symlinks are not followed.
+pub fn copy_acl_path(from: &Path, to: &Path) -> Result<()> {
+    copy_xattr_path_filtered(from, to, true)
+}
+
+/// Copy permissions (mode) between paths. This is intended for
+/// directories, which may not be readable.
+pub fn copy_permissions_path(from: &Path, to: &Path) -> Result<()> {
+    let inmeta = from.metadata()?;
+
+    debug!("Performing permissions copy to {to:?}");
+    std::fs::set_permissions(to, inmeta.permissions())?;
+
+    Ok(())
+}
+
+fn set_timestamps_at(inmeta: &Metadata, to: &Path, flags: AtFlags) -> Result<()> {
+    let times = Timestamps {
+        last_access: Timespec {
+            tv_sec: inmeta.atime(),
+            tv_nsec: inmeta.atime_nsec() as _,
+        },
+        last_modification: Timespec {
+            tv_sec: inmeta.mtime(),
+            tv_nsec: inmeta.mtime_nsec() as _,
+        },
+    };
+    utimensat(CWD, to, &times, flags)?;
+
+    Ok(())
+}
+
+/// Copy timestamps between paths.
+pub fn copy_timestamps_path(from: &Path, to: &Path) -> Result<()> {
+    debug!("Performing timestamp copy to {to:?}");
+    set_timestamps_at(&from.metadata()?, to, AtFlags::empty())
+}
+
+/// Copy ownership between paths.
+pub fn copy_owner_path(from: &Path, to: &Path) -> Result<()> {
+    let inmeta = from.metadata()?;
+    chown(to, Some(inmeta.uid()), Some(inmeta.gid()))?;
+
+    Ok(())
+}
+
+/// Copy timestamps between symlinks, without following them.
+pub fn copy_timestamps_link(from: &Path, to: &Path) -> Result<()> {
+    debug!("Performing symlink timestamp copy to {to:?}");
+    set_timestamps_at(&from.symlink_metadata()?, to, AtFlags::SYMLINK_NOFOLLOW)
+}
+
+/// Copy ownership between symlinks, without following them.
+pub fn copy_owner_link(from: &Path, to: &Path) -> Result<()> {
+    let inmeta = from.symlink_metadata()?;
+    lchown(to, Some(inmeta.uid()), Some(inmeta.gid()))?;
+
+    Ok(())
+}
+
 pub(crate) fn read_bytes(fd: &File, buf: &mut [u8], off: usize) -> Result<usize> {
     Ok(pread(fd, buf, off as u64)?)
 }
@@ -140,6 +235,97 @@ pub(crate) fn copy_bytes_uspace(mut reader: &File, mut writer: &File, nbytes: us
     Ok(written)
 }
 
+fn is_zeroed(buf: &[u8]) -> bool {
+    buf.iter().all(|b| *b == 0)
+}
+
+/// Write a buffer to a file at offset `off`, skipping any filesystem
+/// blocks that consist entirely of zeros. Skipped blocks are
+/// deallocated with [punch_hole](crate::punch_hole), leaving holes in
+/// the file; if the filesystem doesn't support this they are written
+/// out as normal.
+pub fn write_sparse(fd: &File, buf: &[u8], off: u64) -> Result<usize> {
+    let blksize = cmp::max(fd.metadata()?.blksize(), 512);
+    let len = buf.len() as u64;
+    // Runs are aligned to filesystem blocks relative to the start of
+    // the file, not the buffer.
+    let next_boundary = |pos: u64| cmp::min(len, ((off + pos) / blksize + 1) * blksize - off);
+
+    let mut pos = 0;
+    while pos < len {
+        let mut end = next_boundary(pos);
+        let zero = is_zeroed(&buf[pos as usize..end as usize]);
+        while end < len {
+            let next = next_boundary(end);
+            if is_zeroed(&buf[end as usize..next as usize]) != zero {
+                break;
+            }
+            end = next;
+        }
+
+        let run = &buf[pos as usize..end as usize];
+        if !zero || !punch_hole(fd, off + pos, end - pos)? {
+            fd.write_all_at(run, off + pos)?;
+        }
+        pos = end;
+    }
+    Ok(buf.len())
+}
+
+// The buffer size for sparse user-space copies; large ranges are
+// copied in chunks of this size.
+const SPARSE_BUF_SIZE: u64 = 1024 * 1024;
+
+/// Copy a block of bytes at offset `off` between files, leaving holes
+/// in the target where the source contains blocks of zeros. See
+/// [write_sparse]. Unlike [copy_file_offset](crate::copy_file_offset)
+/// this always copies via user-space, using a fixed-size buffer. As
+/// with `copy_file_range` the copy is truncated if the source ends
+/// before `off + bytes`.
+pub fn copy_file_offset_sparse(infd: &File, outfd: &File, bytes: u64, off: i64) -> Result<usize> {
+    let mut buf = vec![0; cmp::min(bytes, SPARSE_BUF_SIZE) as usize];
+    let off = off as u64;
+
+    let mut copied = 0;
+    while copied < bytes {
+        let chunk = cmp::min(bytes - copied, SPARSE_BUF_SIZE) as usize;
+        let mut read = 0;
+        while read < chunk {
+            match read_bytes(infd, &mut buf[read..chunk], (off + copied) as usize + read)? {
+                0 => break,
+                len => read += len,
+            }
+        }
+        if read == 0 {
+            break;
+        }
+        write_sparse(outfd, &buf[..read], off + copied)?;
+        copied += read as u64;
+        if read < chunk {
+            break;
+        }
+    }
+    Ok(copied as usize)
+}
+
+/// Copy bytes between files from the current descriptor cursors,
+/// leaving holes in the target where the source contains blocks of
+/// zeros. Both cursors are advanced past the copied data. See
+/// [copy_file_offset_sparse].
+pub fn copy_file_bytes_sparse(infd: &File, outfd: &File, bytes: u64) -> Result<usize> {
+    let pos = seek(infd, SeekFrom::Current(0))?;
+    let written = copy_file_offset_sparse(infd, outfd, bytes, pos as i64)?;
+    if written == 0 && bytes > 0 {
+        return Err(Error::InvalidSource("Source file ended prematurely."));
+    }
+
+    let next = pos + written as u64;
+    seek(infd, SeekFrom::Start(next))?;
+    seek(outfd, SeekFrom::Start(next))?;
+
+    Ok(written)
+}
+
 /// Allocate file space on disk. Uses Posix ftruncate().
 pub fn allocate_file(fd: &File, len: u64) -> Result<()> {
     Ok(ftruncate(fd, len)?)
@@ -228,6 +414,60 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_copy_dir_metadata() -> Result<()> {
+        use std::fs::{create_dir, set_permissions, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+        use std::time::{Duration, SystemTime};
+
+        let dir = tempdir()?;
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        create_dir(&from)?;
+        create_dir(&to)?;
+
+        let past = SystemTime::now() - Duration::from_secs(60 * 60 * 24);
+        File::open(&from)?.set_times(FileTimes::new().set_accessed(past).set_modified(past))?;
+        set_permissions(&from, Permissions::from_mode(0o500))?;
+
+        copy_permissions_path(&from, &to)?;
+        copy_timestamps_path(&from, &to)?;
+
+        let fmeta = from.metadata()?;
+        let tmeta = to.metadata()?;
+        assert_eq!(0o500, tmeta.permissions().mode() & 0o7777);
+        assert_eq!(fmeta.modified()?, tmeta.modified()?);
+        assert_eq!(fmeta.accessed()?, tmeta.accessed()?);
+
+        set_permissions(&from, Permissions::from_mode(0o700))?;
+        set_permissions(&to, Permissions::from_mode(0o700))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_link_timestamps() -> Result<()> {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempdir()?;
+        let target = dir.path().join("target");
+        let from = dir.path().join("from");
+        let to = dir.path().join("to");
+        File::create(&target)?;
+        symlink(&target, &from)?;
+        symlink(&target, &to)?;
+
+        let old = Timespec { tv_sec: 1_000_000_000, tv_nsec: 0 };
+        let times = Timestamps { last_access: old, last_modification: old };
+        utimensat(CWD, &from, &times, AtFlags::SYMLINK_NOFOLLOW)?;
+
+        copy_timestamps_link(&from, &to)?;
+
+        assert_eq!(1_000_000_000, to.symlink_metadata()?.mtime());
+        // Link target is untouched
+        assert_ne!(1_000_000_000, target.metadata()?.mtime());
+        Ok(())
+    }
+
     #[test]
     fn test_copy_bytes_uspace_large() {
         let dir = tempdir().unwrap();
@@ -258,6 +498,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_copy_offset_sparse_chunked() -> Result<()> {
+        let dir = tempdir()?;
+        let from = dir.path().join("from.bin");
+        let to = dir.path().join("to.bin");
+        // Several buffers, with zeros spanning the chunk boundaries
+        // and a partial final chunk.
+        let len = SPARSE_BUF_SIZE as usize * 3 + 1000;
+        let mut data = vec![0_u8; len];
+        data[..100].fill(b'x');
+        data[SPARSE_BUF_SIZE as usize * 2 + 10..SPARSE_BUF_SIZE as usize * 2 + 20].fill(b'y');
+        data[len - 10..].fill(b'z');
+        std::fs::write(&from, &data)?;
+
+        let infd = File::open(&from)?;
+        let outfd = File::create(&to)?;
+        allocate_file(&outfd, len as u64)?;
+        // The copy stops at the end of the source.
+        assert_eq!(len - 50, copy_file_offset_sparse(&infd, &outfd, len as u64, 50)?);
+        assert_eq!(50, copy_file_offset_sparse(&infd, &outfd, 50, 0)?);
+        assert_eq!(data, read(&to)?);
+
+        Ok(())
+    }
+
     #[test]
     fn test_copy_range_uspace_large() {
         let dir = tempdir().unwrap();
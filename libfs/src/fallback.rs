@@ -19,7 +19,7 @@ use std::path::Path;
 
 use log::warn;
 
-use crate::Extent;
+use crate::{Extent, IoClass};
 use crate::common::{copy_bytes_uspace, copy_range_uspace};
 use crate::errors::{Result, Error};
 
@@ -53,6 +53,38 @@ pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
         .map(|i| i as u64)
 }
 
+pub fn punch_hole(_fd: &File, _off: u64, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn allocate_blocks(_fd: &File, _len: u64) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn drop_cache(_fd: &File) -> Result<()> {
+    Ok(())
+}
+
+pub fn set_io_priority(_class: IoClass, _level: u8) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn set_thread_nice(_nice: i32) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn copy_flags(_infd: &File, _outfd: &File) -> Result<bool> {
+    Ok(false)
+}
+
+pub fn create_tmpfile(_dir: &Path) -> Result<Option<File>> {
+    Ok(None)
+}
+
+pub fn link_tmpfile(_fd: &File, _to: &Path) -> Result<()> {
+    Err(Error::UnsupportedOperation)
+}
+
 pub fn copy_node(src: &Path, _dest: &Path) -> Result<()> {
     // FreeBSD `cp` just warns about this, so do the same here.
     warn!("Socket copy not supported by this OS: {}", src.to_string_lossy());
@@ -32,24 +32,44 @@ cfg_if! {
     }
 }
 pub use backend::{
+    allocate_blocks,
     copy_file_bytes,
+    copy_flags,
     copy_file_offset,
     copy_node,
     copy_sparse,
+    create_tmpfile,
+    drop_cache,
+    link_tmpfile,
     probably_sparse,
     next_sparse_segments,
     map_extents,
+    punch_hole,
     reflink,
+    set_io_priority,
+    set_thread_nice,
 };
 pub use common::{
     allocate_file,
+    copy_acl,
+    copy_acl_path,
     copy_file,
+    copy_file_bytes_sparse,
+    copy_file_offset_sparse,
     copy_owner,
+    copy_owner_link,
+    copy_owner_path,
     copy_permissions,
+    copy_permissions_path,
     copy_timestamps,
+    copy_timestamps_link,
+    copy_timestamps_path,
+    copy_xattrs,
+    copy_xattrs_path,
     is_same_file,
     merge_extents,
     sync,
+    write_sparse,
 };
 pub use errors::Error;
 
@@ -104,6 +124,18 @@ impl From<fs::FileType> for FileType {
     }
 }
 
+/// IO scheduling classes; see
+/// [ioprio_set](https://man7.org/linux/man-pages/man2/ioprio_set.2.html).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IoClass {
+    /// Served before other classes; requires privileges.
+    RealTime,
+    /// The default class.
+    BestEffort,
+    /// Only served when no other process needs the disk.
+    Idle,
+}
+
 /// Struct representing a file extent metadata.
 #[derive(Debug, PartialEq)]
 pub struct Extent {
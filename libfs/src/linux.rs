@@ -21,9 +21,9 @@ use std::os::unix::prelude::PermissionsExt;
 
 use linux_raw_sys::ioctl::{FS_IOC_FIEMAP, FIEMAP_EXTENT_LAST, FICLONE, FIEMAP_EXTENT_SHARED};
 use rustix::fs::CWD;
-use rustix::{fs::{copy_file_range, seek, mknodat, FileType, Mode, RawMode, SeekFrom}, io::Errno};
+use rustix::{fs::{copy_file_range, fadvise, fallocate, ioctl_getflags, ioctl_setflags, linkat, open, seek, mknodat, Advice, AtFlags, FallocateFlags, FileType, IFlags, Mode, OFlags, RawMode, SeekFrom}, io::Errno};
 
-use crate::Extent;
+use crate::{Extent, IoClass};
 use crate::errors::Result;
 use crate::common::{copy_bytes_uspace, copy_range_uspace};
 
@@ -235,6 +235,133 @@ pub fn copy_sparse(infd: &File, outfd: &File) -> Result<u64> {
     Ok(len)
 }
 
+/// Deallocate a range of a file, leaving a hole in its place; the
+/// file size is unchanged. On Linux this uses
+/// [fallocate](https://man7.org/linux/man-pages/man2/fallocate.2.html)
+/// with `FALLOC_FL_PUNCH_HOLE`. Returns `false` if the filesystem
+/// doesn't support hole-punching.
+pub fn punch_hole(fd: &File, off: u64, len: u64) -> Result<bool> {
+    if len == 0 {
+        return Ok(true);
+    }
+    match fallocate(fd, FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE, off, len) {
+        Ok(()) => Ok(true),
+        Err(Errno::OPNOTSUPP) | Err(Errno::NOSYS) => Ok(false),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// Allocate disk blocks for the first `len` bytes of a file,
+/// materialising any holes. On Linux this uses
+/// [fallocate](https://man7.org/linux/man-pages/man2/fallocate.2.html). Returns
+/// `false` if the filesystem doesn't support allocation.
+pub fn allocate_blocks(fd: &File, len: u64) -> Result<bool> {
+    if len == 0 {
+        return Ok(true);
+    }
+    match fallocate(fd, FallocateFlags::empty(), 0, len) {
+        Ok(()) => Ok(true),
+        Err(Errno::OPNOTSUPP) | Err(Errno::NOSYS) => Ok(false),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// Advise the kernel to drop any cached pages for the file, so that
+/// subsequent reads come from the underlying storage. Dirty pages are
+/// not dropped, so the file should be synced first. On Linux this
+/// uses
+/// [posix_fadvise](https://man7.org/linux/man-pages/man2/posix_fadvise.2.html).
+pub fn drop_cache(fd: &File) -> Result<()> {
+    fadvise(fd, 0, None, Advice::DontNeed)?;
+    Ok(())
+}
+
+// From linux/ioprio.h
+const IOPRIO_CLASS_SHIFT: i32 = 13;
+const IOPRIO_WHO_PROCESS: i32 = 1;
+
+/// Set the IO scheduling class and level (0-7, with 0 the highest
+/// priority) of the calling thread. The level is ignored for
+/// `IoClass::Idle`. Returns `false` if the OS doesn't support IO
+/// priorities. On Linux this uses
+/// [ioprio_set](https://man7.org/linux/man-pages/man2/ioprio_set.2.html).
+pub fn set_io_priority(class: IoClass, level: u8) -> Result<bool> {
+    let (class, level) = match class {
+        IoClass::RealTime => (1, level.min(7)),
+        IoClass::BestEffort => (2, level.min(7)),
+        IoClass::Idle => (3, 0),
+    };
+    let prio = (class << IOPRIO_CLASS_SHIFT) | level as i32;
+    // Linux IO priorities are per-thread; 0 is the calling thread.
+    if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(true)
+}
+
+/// Set the CPU scheduling niceness of the calling thread. Returns
+/// `false` if the OS doesn't support per-thread niceness. On Linux
+/// this uses
+/// [setpriority](https://man7.org/linux/man-pages/man2/setpriority.2.html).
+pub fn set_thread_nice(nice: i32) -> Result<bool> {
+    // As with IO priorities, Linux applies this to the calling
+    // thread rather than the whole process.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(true)
+}
+
+/// Copy the inode flags (e.g. immutable, append-only, nodump; see
+/// [chattr](https://man7.org/linux/man-pages/man1/chattr.1.html))
+/// between files. Returns `false` if the source or target filesystem
+/// doesn't support flags. Note that setting some flags requires
+/// `CAP_LINUX_IMMUTABLE`.
+pub fn copy_flags(infd: &File, outfd: &File) -> Result<bool> {
+    let getflags = |fd| match ioctl_getflags(fd) {
+        Ok(flags) => Ok(Some(flags)),
+        Err(Errno::NOTTY) | Err(Errno::OPNOTSUPP) | Err(Errno::INVAL) => Ok(None),
+        Err(errno) => Err(errno),
+    };
+    let (Some(inflags), Some(outflags)) = (getflags(infd)?, getflags(outfd)?) else {
+        return Ok(false);
+    };
+
+    // Only copy the flags we know about; others are
+    // filesystem-internal (e.g. extents) and may not be settable.
+    let known = IFlags::all();
+    let flags = (outflags & !known) | (inflags & known);
+    if flags == outflags {
+        return Ok(true);
+    }
+    match ioctl_setflags(outfd, flags) {
+        Ok(()) => Ok(true),
+        Err(Errno::NOTTY) | Err(Errno::OPNOTSUPP) => Ok(false),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// Create an unnamed temporary file in the given directory, which
+/// can later be given a name with [link_tmpfile]. Returns `None` if
+/// the filesystem doesn't support this. On Linux this uses
+/// [O_TMPFILE](https://man7.org/linux/man-pages/man2/open.2.html).
+pub fn create_tmpfile(dir: &Path) -> Result<Option<File>> {
+    let mode = Mode::from_raw_mode(0o666);
+    match open(dir, OFlags::TMPFILE | OFlags::RDWR | OFlags::CLOEXEC, mode) {
+        Ok(fd) => Ok(Some(File::from(fd))),
+        Err(Errno::OPNOTSUPP) | Err(Errno::ISDIR) | Err(Errno::INVAL) => Ok(None),
+        Err(errno) => Err(errno.into()),
+    }
+}
+
+/// Link a file created with [create_tmpfile] into the filesystem. As
+/// with [std::fs::hard_link] this fails if the target exists.
+pub fn link_tmpfile(fd: &File, to: &Path) -> Result<()> {
+    let procfd = format!("/proc/self/fd/{}", fd.as_raw_fd());
+    linkat(CWD, procfd.as_str(), CWD, to, AtFlags::SYMLINK_FOLLOW)?;
+    Ok(())
+}
+
 /// Create a clone of a special file (unix socket, char-device, etc.)
 pub fn copy_node(src: &Path, dest: &Path) -> Result<()> {
     use std::os::unix::fs::MetadataExt;
@@ -272,7 +399,7 @@ pub fn reflink(infd: &File, outfd: &File) -> Result<bool> {
 #[allow(unused)]
 mod tests {
     use super::*;
-    use crate::{allocate_file, copy_permissions};
+    use crate::{allocate_file, copy_acl};
     use std::env::{current_dir, var};
     use std::fs::{read, OpenOptions};
     use std::io::{self, Seek, Write};
@@ -291,6 +418,18 @@ mod tests {
         Ok(tempdir_in(current_dir()?.join("../target"))?)
     }
 
+    #[test]
+    fn test_thread_priority() -> Result<()> {
+        // Lowering priority needs no privileges. Use a new thread so
+        // other tests aren't affected.
+        std::thread::spawn(|| -> Result<()> {
+            assert!(set_io_priority(IoClass::Idle, 0)?);
+            assert!(set_thread_nice(19)?);
+            assert_eq!(19, unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) });
+            Ok(())
+        }).join().unwrap()
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_reflink", ignore = "No FS support")]
     fn test_reflink() -> Result<()> {
@@ -613,6 +752,127 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_punch_hole() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("file.bin");
+        let len = 1024 * 1024;
+
+        {
+            let mut fd = File::create(&file)?;
+            fd.write_all(&vec![0xff_u8; len])?;
+        }
+        assert!(!probably_sparse(&File::open(&file)?)?);
+
+        {
+            let fd = OpenOptions::new().write(true).open(&file)?;
+            assert!(punch_hole(&fd, 0, len as u64 / 2)?);
+        }
+
+        assert_eq!(len as u64, file.metadata()?.len());
+        assert!(probably_sparse(&File::open(&file)?)?);
+        let bytes = read(&file)?;
+        assert!(bytes[..len / 2].iter().all(|b| *b == 0));
+        assert!(bytes[len / 2..].iter().all(|b| *b == 0xff));
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_allocate_blocks() -> Result<()> {
+        let dir = tempdir()?;
+        let file = dir.path().join("sparse.bin");
+        let len = 1024 * 1024;
+
+        {
+            let fd = File::create(&file)?;
+            allocate_file(&fd, len)?;
+        }
+        assert!(probably_sparse(&File::open(&file)?)?);
+
+        {
+            let fd = OpenOptions::new().write(true).open(&file)?;
+            assert!(allocate_blocks(&fd, len)?);
+        }
+
+        assert_eq!(len, file.metadata()?.len());
+        assert!(!probably_sparse(&File::open(&file)?)?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_copy_offset_sparse_zeros() -> Result<()> {
+        use crate::copy_file_offset_sparse;
+
+        let dir = tempdir()?;
+        let from = dir.path().join("zeros.bin");
+        let to = dir.path().join("sparse.bin");
+        let len = 4 * 1024 * 1024;
+
+        {
+            let mut fd = File::create(&from)?;
+            let mut data = vec![0_u8; len];
+            data[..4096].fill(b'x');
+            data[len - 4096..].fill(b'y');
+            fd.write_all(&data)?;
+        }
+        assert!(!probably_sparse(&File::open(&from)?)?);
+
+        {
+            let infd = File::open(&from)?;
+            let outfd = File::create(&to)?;
+            allocate_file(&outfd, len as u64)?;
+            // Copy out-of-order, as the parblock driver would.
+            let half = len as u64 / 2;
+            assert_eq!(half as usize, copy_file_offset_sparse(&infd, &outfd, half, half as i64)?);
+            assert_eq!(half as usize, copy_file_offset_sparse(&infd, &outfd, half, 0)?);
+        }
+
+        assert!(probably_sparse(&File::open(&to)?)?);
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_copy_bytes_sparse_cursor() -> Result<()> {
+        use crate::copy_file_bytes_sparse;
+
+        let dir = tempdir()?;
+        let from = dir.path().join("zeros.bin");
+        let to = dir.path().join("sparse.bin");
+        let len = 1024 * 1024;
+
+        {
+            let mut fd = File::create(&from)?;
+            let mut data = vec![0_u8; len];
+            data[len / 2..len / 2 + 10].fill(b'z');
+            fd.write_all(&data)?;
+        }
+
+        {
+            let mut infd = File::open(&from)?;
+            let mut outfd = File::create(&to)?;
+            allocate_file(&outfd, len as u64)?;
+            let chunk = len as u64 / 4;
+            for _ in 0..4 {
+                assert_eq!(chunk as usize, copy_file_bytes_sparse(&infd, &outfd, chunk)?);
+            }
+            assert_eq!(len as u64, infd.stream_position()?);
+            assert_eq!(len as u64, outfd.stream_position()?);
+        }
+
+        assert!(probably_sparse(&File::open(&to)?)?);
+        assert_eq!(read(&from)?, read(&to)?);
+
+        Ok(())
+    }
+
     #[test]
     #[cfg_attr(feature = "test_no_extents", ignore = "No FS support")]
     fn test_empty_extent() -> Result<()> {
@@ -816,12 +1076,77 @@ mod tests {
         {
             let from_fd: File = File::open(&from)?;
             let to_fd: File = File::open(&to)?;
-            copy_permissions(&from_fd, &to_fd)?;
+            copy_acl(&from_fd, &to_fd)?;
         }
 
-        let to_acl = getfacl(&from, None)?;
+        let to_acl = getfacl(&to, None)?;
         assert!(to_acl.contains(&acl));
 
         Ok(())
     }
+
+    #[test]
+    #[cfg_attr(feature = "test_no_acl", ignore = "No FS support")]
+    fn test_copy_xattrs_excludes_acl() -> Result<()> {
+        use exacl::{getfacl, AclEntry, Perm, setfacl};
+        use crate::copy_xattrs;
+
+        let dir = tempdir()?;
+        let from = dir.path().join("file.bin");
+        let to = dir.path().join("copy.bin");
+        File::create(&from)?;
+        File::create(&to)?;
+
+        let acl = AclEntry::allow_user("mail", Perm::READ, None);
+        let mut from_acl = getfacl(&from, None)?;
+        from_acl.push(acl.clone());
+        setfacl(&[&from], &from_acl, None)?;
+
+        copy_xattrs(&File::open(&from)?, &File::open(&to)?)?;
+
+        assert!(!getfacl(&to, None)?.contains(&acl));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_flags() -> Result<()> {
+        use crate::copy_flags;
+
+        let dir = tempdir()?;
+        let from = dir.path().join("file.bin");
+        let to = dir.path().join("copy.bin");
+        let from_fd = File::create(&from)?;
+        let to_fd = File::create(&to)?;
+
+        match ioctl_getflags(&from_fd) {
+            Ok(flags) => ioctl_setflags(&from_fd, flags | IFlags::NODUMP)?,
+            // No FS support
+            Err(_) => return Ok(()),
+        }
+
+        assert!(copy_flags(&from_fd, &to_fd)?);
+        assert!(ioctl_getflags(&to_fd)?.contains(IFlags::NODUMP));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_tmpfile_link() -> Result<()> {
+        let dir = tempdir()?;
+        let to = dir.path().join("file.txt");
+
+        let Some(mut fd) = create_tmpfile(dir.path())? else {
+            // No FS support
+            return Ok(());
+        };
+        fd.write_all(b"data")?;
+        assert_eq!(0, dir.path().read_dir()?.count());
+
+        link_tmpfile(&fd, &to)?;
+        assert_eq!(b"data".to_vec(), read(&to)?);
+        assert!(link_tmpfile(&fd, &to).is_err());
+
+        Ok(())
+    }
 }
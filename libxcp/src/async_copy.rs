@@ -0,0 +1,206 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! An async interface for Tokio applications. Requires the `async`
+//! feature.
+//!
+//! [copy_async()] runs a copy on Tokio's blocking thread-pool and
+//! returns a [StatusStream] of updates, along with a handle to await
+//! the result.
+//!
+//! # Example
+//!
+//!     # use libxcp::errors::Result;
+//!     # use std::future::poll_fn;
+//!     # use std::path::PathBuf;
+//!     # use std::pin::Pin;
+//!     # use std::sync::Arc;
+//!     # use futures_core::Stream;
+//!     # use tempfile::TempDir;
+//!     use libxcp::async_copy::copy_async;
+//!     use libxcp::config::Config;
+//!     use libxcp::drivers::Drivers;
+//!     use libxcp::feedback::StatusUpdate;
+//!     # fn main() -> Result<()> {
+//!     # let rt = tokio::runtime::Builder::new_current_thread().build()?;
+//!     # rt.block_on(async {
+//!
+//!     let dest = TempDir::new()?;
+//!     let config = Arc::new(Config::default());
+//!     let (handle, mut updates) = copy_async(Drivers::ParFile, vec![PathBuf::from("src")], dest.path(), config);
+//!
+//!     // With `futures::StreamExt` this is `updates.next().await`.
+//!     while let Some(update) = poll_fn(|cx| Pin::new(&mut updates).poll_next(cx)).await {
+//!         if let StatusUpdate::FileFinished { path, .. } = update {
+//!             println!("Copied {:?}", path);
+//!         }
+//!     }
+//!     let stats = handle.await??;
+//!     println!("Copied {} bytes", stats.copied);
+//!     # Ok(())
+//!     # })
+//!     # }
+
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures_core::Stream;
+use tokio::sync::mpsc;
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::config::Config;
+use crate::control::CopyController;
+use crate::drivers::{load_driver, Drivers};
+use crate::errors::{Result, XcpError};
+use crate::feedback::{ChannelUpdater, CopyStats, StatusUpdate};
+
+/// The updates from a copy started with [copy_async()]. Dropping the
+/// stream cancels the copy if it is still running; it should be
+/// drained if the updates aren't needed.
+pub struct StatusStream {
+    rx: mpsc::UnboundedReceiver<StatusUpdate>,
+    control: CopyController,
+}
+
+impl StatusStream {
+    /// A handle to pause, resume or cancel the copy.
+    pub fn controller(&self) -> CopyController {
+        self.control.clone()
+    }
+}
+
+impl Stream for StatusStream {
+    type Item = StatusUpdate;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<StatusUpdate>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+impl Drop for StatusStream {
+    fn drop(&mut self) {
+        self.control.cancel();
+    }
+}
+
+/// Recursively copy a set of directories or files to a destination
+/// with the given driver, as with
+/// [CopyDriver::copy()](crate::drivers::CopyDriver::copy). The
+/// handle resolves to the totals of the updates once the copy is
+/// complete. This must be called from within a Tokio runtime.
+pub fn copy_async(
+    driver: Drivers,
+    sources: Vec<PathBuf>,
+    dest: &Path,
+    config: Arc<Config>,
+) -> (JoinHandle<Result<CopyStats>>, StatusStream) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let driver = load_driver(driver, &config);
+    let control = match &driver {
+        Ok(d) => d.controller(),
+        Err(_) => CopyController::new(),
+    };
+    let dest = dest.to_path_buf();
+
+    let handle = spawn_blocking(move || {
+        let driver = driver?;
+        let updater = ChannelUpdater::new(&config);
+        let stat_rx = updater.rx_channel();
+        let copier = thread::spawn(move || driver.copy(sources, &dest, Arc::new(updater)));
+
+        // The channel closes once the driver is finished with it.
+        let mut stats = CopyStats::default();
+        for update in stat_rx {
+            stats.update(&update);
+            // The stream may have been dropped; the copy is then
+            // being cancelled.
+            let _ = tx.send(update);
+        }
+        copier.join()
+            .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
+        Ok(stats)
+    });
+
+    (handle, StatusStream { rx, control })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Reflink;
+    use std::fs::{create_dir_all, read_to_string, write};
+    use std::future::poll_fn;
+    use tempfile::TempDir;
+    use tokio::runtime::Builder;
+
+    async fn next(stream: &mut StatusStream) -> Option<StatusUpdate> {
+        poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[test]
+    fn test_copy_async() -> Result<()> {
+        let source = TempDir::new()?;
+        let dest = TempDir::new()?;
+        create_dir_all(source.path().join("sub"))?;
+        write(source.path().join("sub/file.txt"), "12345")?;
+
+        let rt = Builder::new_current_thread().build()?;
+        let (stats, finished) = rt.block_on(async {
+            let (handle, mut updates) = copy_async(Drivers::ParFile, vec![source.path().to_path_buf()], dest.path(), Arc::new(Config::default()));
+            let mut finished = 0;
+            while let Some(update) = next(&mut updates).await {
+                if let StatusUpdate::FileFinished { .. } = update {
+                    finished += 1;
+                }
+            }
+            Ok::<_, anyhow::Error>((handle.await??, finished))
+        })?;
+
+        assert_eq!(1, finished);
+        assert_eq!(1, stats.files);
+        assert_eq!(5, stats.copied);
+        let name = source.path().file_name().unwrap();
+        assert_eq!("12345", read_to_string(dest.path().join(name).join("sub/file.txt"))?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_on_drop() -> Result<()> {
+        let source = TempDir::new()?;
+        let dest = TempDir::new()?;
+        // Large enough, in small enough blocks, to still be copying
+        // when the stream is dropped.
+        write(source.path().join("file.bin"), vec![0xa5; 32 * 1024 * 1024])?;
+        let config = Config {
+            block_size: 4096,
+            reflink: Reflink::Never,
+            ..Config::default()
+        };
+
+        let rt = Builder::new_current_thread().build()?;
+        let result = rt.block_on(async {
+            let (handle, updates) = copy_async(Drivers::ParFile, vec![source.path().to_path_buf()], dest.path(), Arc::new(config));
+            drop(updates);
+            handle.await
+        })?;
+
+        assert!(matches!(result.unwrap_err().downcast_ref(), Some(XcpError::Cancelled)));
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Atomic file writes. Files are written to a temporary file in the
+//! target directory and moved into place once complete, so the
+//! target is never seen partially written.
+
+use std::ffi::OsString;
+use std::fs::{hard_link, remove_file, rename, File, OpenOptions};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libfs::{create_tmpfile, link_tmpfile};
+use log::{debug, info, warn};
+
+use crate::backup::{get_backup_path, needs_backup};
+use crate::config::Config;
+use crate::errors::{Result, XcpError};
+
+/// A temporary file that will replace the target.
+#[derive(Debug)]
+pub(crate) enum Staged {
+    /// Created with `O_TMPFILE`; this has no name until installed,
+    /// and so disappears if the copy fails.
+    Anonymous,
+    /// A hidden file in the target directory.
+    Named(PathBuf),
+}
+
+fn parent_dir(to: &Path) -> &Path {
+    match to.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    }
+}
+
+// Generates a hidden name in the same directory as the target.
+fn temp_name(to: &Path) -> Result<PathBuf> {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let fname = to.file_name()
+        .ok_or(XcpError::InvalidArguments(format!("Invalid path found: {to:?}")))?;
+    let mut name = OsString::from(".");
+    name.push(fname);
+    name.push(format!(".xcp-{}-{}", process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+    Ok(parent_dir(to).join(name))
+}
+
+// Anonymous files are linked into place via /proc/self/fd, which
+// may not be mounted (e.g. in minimal containers or chroots).
+fn can_link_tmpfile() -> bool {
+    static PROC_FD: OnceLock<bool> = OnceLock::new();
+    *PROC_FD.get_or_init(|| Path::new("/proc/self/fd").is_dir())
+}
+
+/// Create a temporary file to be installed at `to` later. The file is
+/// opened read-write so it can be verified before installation.
+pub(crate) fn create_staged(to: &Path) -> Result<(File, Staged)> {
+    if can_link_tmpfile()
+        && let Some(fd) = create_tmpfile(parent_dir(to))?
+    {
+        return Ok((fd, Staged::Anonymous));
+    }
+    loop {
+        let tmp = temp_name(to)?;
+        match OpenOptions::new().read(true).write(true).create_new(true).open(&tmp) {
+            Ok(fd) => {
+                debug!("Staging {to:?} at {tmp:?}");
+                return Ok((fd, Staged::Named(tmp)));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+/// Move a completed temporary file into place, replacing any existing
+/// target (after backing it up if configured). The temporary file is
+/// removed on failure. Returns the backup path, if one was made.
+pub(crate) fn install(fd: &File, staged: Staged, to: &Path, config: &Config) -> Result<Option<PathBuf>> {
+    let backup = needs_backup(to, config)
+        .and_then(|need| if need { get_backup_path(to).map(Some) } else { Ok(None) });
+    let backup = match backup {
+        Ok(b) => b,
+        Err(e) => {
+            discard(staged);
+            return Err(e);
+        }
+    };
+    if let Some(backup) = &backup {
+        // Link rather than rename so the target is never missing.
+        info!("Backup: Link {to:?} to {backup:?}");
+        if let Err(e) = hard_link(to, backup) {
+            discard(staged);
+            return Err(e.into());
+        }
+    }
+
+    let tmp = match staged {
+        Staged::Named(tmp) => tmp,
+        Staged::Anonymous => {
+            // linkat() won't replace an existing file, so link to a
+            // temporary name and rename over the target.
+            if to.symlink_metadata().is_err() && link_tmpfile(fd, to).is_ok() {
+                sync_dir(to, config)?;
+                return Ok(backup);
+            }
+            let tmp = temp_name(to)?;
+            link_tmpfile(fd, &tmp)?;
+            tmp
+        }
+    };
+    if let Err(e) = rename(&tmp, to) {
+        discard(Staged::Named(tmp));
+        return Err(e.into());
+    }
+    sync_dir(to, config)?;
+    Ok(backup)
+}
+
+/// Clean up a temporary file that will not be installed.
+pub(crate) fn discard(staged: Staged) {
+    if let Staged::Named(tmp) = staged
+        && let Err(e) = remove_file(&tmp)
+    {
+        warn!("Failed to remove temporary file {tmp:?}: {e}");
+    }
+}
+
+// The new directory entry must also be synced to be durable.
+fn sync_dir(to: &Path, config: &Config) -> Result<()> {
+    if config.fsync {
+        File::open(parent_dir(to))?.sync_all()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{read_to_string, write};
+    use std::io::Write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_install_replaces() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let to = tdir.path().join("file.txt");
+        write(&to, "old")?;
+
+        let (mut fd, staged) = create_staged(&to)?;
+        fd.write_all(b"new")?;
+        assert_eq!("old", read_to_string(&to)?);
+
+        install(&fd, staged, &to, &Config::default())?;
+        assert_eq!("new", read_to_string(&to)?);
+        assert_eq!(1, tdir.path().read_dir()?.count());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_discard() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let to = tdir.path().join("file.txt");
+
+        let tmp = temp_name(&to)?;
+        assert!(tmp.file_name().unwrap().to_str().unwrap().starts_with(".file.txt.xcp-"));
+        write(&tmp, "partial")?;
+        discard(Staged::Named(tmp));
+        assert_eq!(0, tdir.path().read_dir()?.count());
+
+        Ok(())
+    }
+}
@@ -28,15 +28,20 @@ pub(crate) fn get_backup_path(file: &Path) -> Result<PathBuf> {
 pub(crate) fn needs_backup(file: &Path, conf: &Config) -> Result<bool> {
     let need = match conf.backup {
         Backup::None => false,
-        Backup::Auto if file.exists() => {
+        Backup::Auto if exists(file) => {
             has_backup(file)?
         }
-        Backup::Numbered if file.exists() => true,
+        Backup::Numbered if exists(file) => true,
         _ => false,
     };
     Ok(need)
 }
 
+// Dangling symlinks are backed up too.
+fn exists(file: &Path) -> bool {
+    file.symlink_metadata().is_ok()
+}
+
 fn ls_file_dir(file: &Path) -> Result<ReadDir> {
     let cwd = current_dir()?;
     let ls_dir = file.parent()
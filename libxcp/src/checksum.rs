@@ -0,0 +1,136 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! File content checksums, used for verification of copies.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+
+use sha2::{Digest, Sha256};
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::config::HashType;
+use crate::errors::Result;
+use crate::throttle::Throttle;
+
+pub(crate) const BUF_SIZE: usize = 1024 * 1024;
+
+fn read_file(fd: &File, throttle: &Throttle, mut f: impl FnMut(&[u8])) -> Result<()> {
+    let mut buf = vec![0; throttle.chunk_size(BUF_SIZE as u64) as usize];
+    let mut off = 0;
+    loop {
+        let n = fd.read_at(&mut buf, off)?;
+        if n == 0 {
+            break;
+        }
+        throttle.acquire_read(n as u64);
+        f(&buf[..n]);
+        off += n as u64;
+    }
+    Ok(())
+}
+
+/// Calculate a (non-cryptographic) hash of the full contents of a
+/// file. Holes in sparse files are read as zeros.
+pub(crate) fn hash_file(fd: &File, throttle: &Throttle) -> Result<u128> {
+    let mut hasher = Xxh3::new();
+    read_file(fd, throttle, |data| hasher.update(data))?;
+    Ok(hasher.digest128())
+}
+
+/// An incremental hasher for the supported manifest hash types.
+pub(crate) enum FileHasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl FileHasher {
+    pub fn new(hash: HashType) -> FileHasher {
+        match hash {
+            HashType::Sha256 => FileHasher::Sha256(Sha256::new()),
+            HashType::Blake3 => FileHasher::Blake3(Box::new(blake3::Hasher::new())),
+            HashType::Xxh3 => FileHasher::Xxh3(Box::new(Xxh3::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            FileHasher::Sha256(h) => h.update(data),
+            FileHasher::Blake3(h) => { h.update(data); }
+            FileHasher::Xxh3(h) => h.update(data),
+        }
+    }
+
+    /// Returns the digest as a lowercase hex string.
+    pub fn finish(self) -> String {
+        match self {
+            FileHasher::Sha256(h) => h.finalize().iter().map(|b| format!("{b:02x}")).collect(),
+            FileHasher::Blake3(h) => h.finalize().to_hex().to_string(),
+            FileHasher::Xxh3(h) => format!("{:016x}", h.digest()),
+        }
+    }
+
+    /// Hash the full contents of a file.
+    pub fn digest_file(mut self, fd: &File, throttle: &Throttle) -> Result<String> {
+        read_file(fd, throttle, |data| self.update(data))?;
+        Ok(self.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_hash_file() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let a = tdir.path().join("a");
+        let b = tdir.path().join("b");
+        let c = tdir.path().join("c");
+        let data = vec![0x5a; BUF_SIZE + 100];
+        write(&a, &data)?;
+        write(&b, &data)?;
+        write(&c, &data[..BUF_SIZE])?;
+
+        let throttle = Throttle::default();
+        let ha = hash_file(&File::open(&a)?, &throttle)?;
+        assert_eq!(ha, hash_file(&File::open(&b)?, &throttle)?);
+        assert_ne!(ha, hash_file(&File::open(&c)?, &throttle)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_digests() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let a = tdir.path().join("a");
+        write(&a, "abc")?;
+        let fd = File::open(&a)?;
+        let throttle = Throttle::default();
+
+        assert_eq!("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+                   FileHasher::new(HashType::Sha256).digest_file(&fd, &throttle)?);
+        assert_eq!("6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+                   FileHasher::new(HashType::Blake3).digest_file(&fd, &throttle)?);
+        assert_eq!("78af5f94892f3950",
+                   FileHasher::new(HashType::Xxh3).digest_file(&fd, &throttle)?);
+
+        Ok(())
+    }
+}
@@ -16,10 +16,15 @@
 
 //! Driver configuration support.
 
+use std::path::PathBuf;
 use std::result;
 use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 use crate::errors::XcpError;
+use crate::paths::Filter;
+
+pub use libfs::IoClass;
 
 /// Enum defining configuration options for handling
 /// [reflinks](https://btrfs.readthedocs.io/en/latest/Reflink.html). [FromStr]
@@ -53,6 +58,155 @@ impl FromStr for Reflink {
     }
 }
 
+/// Enum defining configuration options for handling sparse files,
+/// analogous to `cp`'s `--sparse` flag. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Sparse {
+    /// Preserve any holes in the source file; the target will be
+    /// sparse if the source is.
+    #[default]
+    Auto,
+    /// As with `Auto`, but additionally detect blocks of zeros in the
+    /// source and leave holes in the target in their place. This
+    /// requires reading the data in user-space, so disables reflinks
+    /// when `Reflink::Auto` is set.
+    Always,
+    /// Never create sparse files; holes in the source are fully
+    /// allocated in the target. Disables reflinks when
+    /// `Reflink::Auto` is set.
+    Never,
+}
+
+impl FromStr for Sparse {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "always" => Ok(Sparse::Always),
+            "auto" => Ok(Sparse::Auto),
+            "never" => Ok(Sparse::Never),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'sparse': {s}"))),
+        }
+    }
+}
+
+/// Enum defining configuration options for verifying copied files
+/// against the source. [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Verify {
+    /// Do not verify copies.
+    #[default]
+    None,
+    /// Compare a checksum of the target file to the source once
+    /// copied. The target may be read back from the page cache.
+    Cached,
+    /// As with `Cached`, but sync the target and drop it from the
+    /// page cache before reading it back, so the data is read from
+    /// the underlying storage where possible.
+    Uncached,
+}
+
+impl FromStr for Verify {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" | "off" => Ok(Verify::None),
+            "cached" => Ok(Verify::Cached),
+            "uncached" => Ok(Verify::Uncached),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'verify': {s}"))),
+        }
+    }
+}
+
+/// Enum defining the hash algorithm used for checksum manifests.
+/// [FromStr] is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HashType {
+    /// SHA-256; compatible with `sha256sum`.
+    #[default]
+    Sha256,
+    /// BLAKE3; compatible with `b3sum`.
+    Blake3,
+    /// 64-bit XXH3; compatible with `xxhsum -H3`. Not
+    /// cryptographically secure.
+    Xxh3,
+}
+
+impl FromStr for HashType {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "sha256" => Ok(HashType::Sha256),
+            "blake3" => Ok(HashType::Blake3),
+            "xxh3" => Ok(HashType::Xxh3),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'hash': {s}"))),
+        }
+    }
+}
+
+/// Enum defining which existing target files are replaced. [FromStr]
+/// is supported.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Update {
+    /// Replace all existing files.
+    #[default]
+    All,
+    /// Never replace existing files. Unlike `no_clobber` this is not
+    /// an error.
+    None,
+    /// Only replace files that are out of date; i.e. that differ in
+    /// size from the source or have an older modification time (see
+    /// `Config::modify_window`). If `Config::checksum` is set the
+    /// file contents are compared instead of the modification time.
+    Older,
+}
+
+impl FromStr for Update {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "all" => Ok(Update::All),
+            "none" => Ok(Update::None),
+            "older" => Ok(Update::Older),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'update': {s}"))),
+        }
+    }
+}
+
+/// Enum defining which symlinks in the sources are followed, as
+/// with `cp`'s `-P`, `-H` and `-L` options.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Dereference {
+    /// Copy all symlinks as links.
+    #[default]
+    Never,
+    /// Follow symlinks given as sources, but copy those found within
+    /// them as links.
+    CommandLine,
+    /// Follow all symlinks. A link to one of its own parent
+    /// directories is reported as an error, and a directory reached
+    /// by more than one path is only copied once.
+    Always,
+}
+
+/// Enum defining how symlinks that point outside of their source
+/// tree are handled when they are not dereferenced; i.e. links to
+/// absolute paths, or with enough `..` components to leave the tree.
+/// These are `rsync`'s "unsafe" links.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum UnsafeLinks {
+    /// Copy them as links.
+    #[default]
+    Keep,
+    /// Copy the file or directory they point to instead.
+    Copy,
+    /// Skip them.
+    Skip,
+}
+
 /// Enum defining configuration options for handling backups of
 /// overwritten files. [FromStr] is supported.
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -79,6 +233,169 @@ impl FromStr for Backup {
     }
 }
 
+/// The set of file attributes to preserve in the target, analogous
+/// to `cp`'s `--preserve` flag. [FromStr] is supported, and accepts a
+/// comma-separated list of attribute names (`mode`, `ownership`,
+/// `timestamps`, `links`, `xattr`, `acl`, `flags` or `all`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Preserve {
+    /// Permission bits.
+    pub mode: bool,
+    /// User and group. This requires root permissions or
+    /// appropriate capabilities; if the attempt fails a warning is
+    /// issued but the operation continues.
+    pub ownership: bool,
+    /// Access and modification times.
+    pub timestamps: bool,
+    /// Hard links between files within the copied tree; files that
+    /// share an inode in the source are copied once and hard-linked
+    /// in the target.
+    pub links: bool,
+    /// Extended attributes, other than ACLs.
+    pub xattr: bool,
+    /// POSIX ACLs. Currently only supported on Linux.
+    pub acl: bool,
+    /// Inode flags (see `chattr(1)`). Currently only supported on
+    /// Linux.
+    pub flags: bool,
+}
+
+impl Preserve {
+    /// Preserve nothing.
+    pub const fn none() -> Self {
+        Preserve {
+            mode: false,
+            ownership: false,
+            timestamps: false,
+            links: false,
+            xattr: false,
+            acl: false,
+            flags: false,
+        }
+    }
+
+    /// Preserve all supported attributes.
+    pub const fn all() -> Self {
+        Preserve {
+            mode: true,
+            ownership: true,
+            timestamps: true,
+            links: true,
+            xattr: true,
+            acl: true,
+            flags: true,
+        }
+    }
+
+    /// The attributes preserved by `cp -p`; mode, ownership and
+    /// timestamps.
+    pub const fn basic() -> Self {
+        Preserve {
+            mode: true,
+            ownership: true,
+            timestamps: true,
+            ..Preserve::none()
+        }
+    }
+
+    /// The union of this set and `other`.
+    pub fn with(self, other: Preserve) -> Self {
+        Preserve {
+            mode: self.mode || other.mode,
+            ownership: self.ownership || other.ownership,
+            timestamps: self.timestamps || other.timestamps,
+            links: self.links || other.links,
+            xattr: self.xattr || other.xattr,
+            acl: self.acl || other.acl,
+            flags: self.flags || other.flags,
+        }
+    }
+
+    /// This set with the attributes in `other` removed.
+    pub fn without(self, other: Preserve) -> Self {
+        Preserve {
+            mode: self.mode && !other.mode,
+            ownership: self.ownership && !other.ownership,
+            timestamps: self.timestamps && !other.timestamps,
+            links: self.links && !other.links,
+            xattr: self.xattr && !other.xattr,
+            acl: self.acl && !other.acl,
+            flags: self.flags && !other.flags,
+        }
+    }
+}
+
+/// The default is to preserve mode, timestamps, xattrs and ACLs.
+impl Default for Preserve {
+    fn default() -> Self {
+        Preserve {
+            mode: true,
+            timestamps: true,
+            xattr: true,
+            acl: true,
+            ..Preserve::none()
+        }
+    }
+}
+
+impl FromStr for Preserve {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let mut preserve = Preserve::none();
+        for attr in s.split(',') {
+            match attr.trim().to_lowercase().as_str() {
+                "mode" => preserve.mode = true,
+                "ownership" => preserve.ownership = true,
+                "timestamps" => preserve.timestamps = true,
+                "links" => preserve.links = true,
+                "xattr" => preserve.xattr = true,
+                "acl" => preserve.acl = true,
+                "flags" => preserve.flags = true,
+                "all" => preserve = Preserve::all(),
+                _ => return Err(XcpError::InvalidArguments(format!("Unexpected value for 'preserve': {attr}"))),
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+/// The IO scheduling priority of the copy workers, analogous to
+/// `ionice`. [FromStr] is supported, and accepts `CLASS[:LEVEL]`,
+/// where the class is `realtime`, `best-effort` or `idle` (or `1`-`3`
+/// as with `ionice -c`), and the level is 0-7, with 0 the highest
+/// priority.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IoPriority {
+    pub class: IoClass,
+    /// Priority within the class; ignored for `IoClass::Idle`.
+    pub level: u8,
+}
+
+impl FromStr for IoPriority {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let err = || XcpError::InvalidArguments(format!("Unexpected value for 'ionice': {s}"));
+        let (class, level) = match s.split_once(':') {
+            Some((c, l)) => (c, Some(l)),
+            None => (s, None),
+        };
+        let class = match class.to_lowercase().as_str() {
+            "1" | "realtime" => IoClass::RealTime,
+            "2" | "best-effort" => IoClass::BestEffort,
+            "3" | "idle" => IoClass::Idle,
+            _ => return Err(err()),
+        };
+        // The kernel default level is 4.
+        let level = match level {
+            Some(l) => l.parse().ok().filter(|l| *l <= 7).ok_or_else(err)?,
+            None => 4,
+        };
+        Ok(IoPriority { class, level })
+    }
+}
+
 /// A structure defining the runtime options for copy-drivers. This
 /// would normally be passed to `load_driver()`.
 #[derive(Clone, Debug)]
@@ -91,33 +408,83 @@ pub struct Config {
     /// a smaller value for finer-grained feedback.
     pub block_size: u64,
 
-    /// Use .gitignore if present.
-    ///
-    /// NOTE: This is fairly basic at the moment, and only honours a
-    /// .gitignore in the directory root for each source directory;
-    /// global or sub-directory ignores are skipped. Default is
-    /// `false`.
+    /// Honour ignore files as git does; `.gitignore` files in the
+    /// source tree and its parents within the repository,
+    /// `.git/info/exclude` and the global excludes file. Files tracked
+    /// in the git index are copied even if ignored. `.ignore` and
+    /// `.xcpignore` files are also honoured, with `.xcpignore` taking
+    /// precedence, and `.git` directories are skipped. Outside of a
+    /// repository `.gitignore` files in all parent directories apply.
+    /// Default is `false`.
     pub gitignore: bool,
 
-    /// Do not overwrite existing files. Default is `false`.
-    pub no_clobber: bool,
+    /// Include and exclude rules, checked against paths relative to
+    /// each source. See [Filter]. The default includes everything.
+    pub filter: Filter,
+
+    /// Do not create directories that would be empty, either because
+    /// they are empty in the source or because all their contents are
+    /// filtered out. Default is `false`.
+    pub prune_empty_dirs: bool,
+
+    /// Only copy files of at least this many bytes. As with the other
+    /// size and age predicates only regular files are checked;
+    /// symlinks are checked by their target if dereferenced, and
+    /// otherwise always copied, as are directories and the sources
+    /// themselves. Default is `None`.
+    pub min_size: Option<u64>,
+
+    /// Only copy files of at most this many bytes. Default is `None`.
+    pub max_size: Option<u64>,
+
+    /// Only copy files modified after this time. Default is `None`.
+    pub newer_than: Option<SystemTime>,
 
-    /// Do not copy the file permissions. Default is `false`.
-    pub no_perms: bool,
+    /// Only copy files modified before this time. Default is `None`.
+    pub older_than: Option<SystemTime>,
 
-    /// Do not copy the file permissions. Default is `false`.
-    pub no_timestamps: bool,
+    /// Only copy files of these types, using the `ignore` crate's
+    /// definitions (e.g. `rust` or `json`); see
+    /// [file_types()](crate::paths::file_types). The default is empty,
+    /// which copies all types.
+    pub file_types: Vec<String>,
 
-    /// Copy ownership.
+    /// Do not descend into directories on other filesystems than
+    /// their source; mount points are created empty, as with `cp -x`.
+    /// Default is `false`.
+    pub one_file_system: bool,
+
+    /// Descend at most this many levels below each source directory;
+    /// 1 copies only its immediate entries. Default is `None`.
+    pub max_depth: Option<usize>,
+
+    /// Only copy files at least this many levels below each source
+    /// directory. Directories above this depth are still created;
+    /// see `prune_empty_dirs`. Default is `None`.
+    pub min_depth: Option<usize>,
+
+    /// Do not overwrite existing files. Default is `false`.
+    pub no_clobber: bool,
+
+    /// File attributes to preserve. See [Preserve] for the
+    /// defaults.
     ///
-    /// Whether to copy ownship (user/group).  This option requires
-    /// root permissions or appropriate capabilities; if the attempt
-    /// to copy ownership fails a warning is issued but the operation
-    /// continues.
-    pub ownership: bool,
+    /// This replaces the fields in versions before 0.25:
+    ///
+    /// * `no_perms: true` is `preserve.mode = false`.
+    /// * `no_timestamps: true` is `preserve.timestamps = false`.
+    /// * `ownership: true` is `preserve.ownership = true`.
+    /// * `hard_links: true` is `preserve.links = true`.
+    pub preserve: Preserve,
 
-    /// Dereference symlinks. Default is `false`.
-    pub dereference: bool,
+    /// Which symlinks to dereference. Default is
+    /// [Dereference::Never]. Before 0.25 this was a `bool`; `true` is
+    /// [Dereference::Always].
+    pub dereference: Dereference,
+
+    /// How to handle symlinks pointing outside of the source tree.
+    /// Default is [UnsafeLinks::Keep].
+    pub unsafe_links: UnsafeLinks,
 
     /// Target should not be a directory.
     ///
@@ -137,6 +504,14 @@ pub struct Config {
     /// and 'never' will always perform a full data copy.
     pub reflink: Reflink,
 
+    /// Sparse file options.
+    ///
+    /// How to handle sparse files. 'auto' (the default) will preserve
+    /// any holes in the source, 'always' will additionally convert
+    /// blocks of zeros into holes, and 'never' will fully allocate
+    /// the target file.
+    pub sparse: Sparse,
+
     /// Backup options
     ///
     /// Whether to create backups of overwritten files. Current
@@ -144,6 +519,121 @@ pub struct Config {
     /// semantics of `cp` numbered backups
     /// (e.g. `file.txt.~123~`). Default is `None`.
     pub backup: Backup,
+
+    /// Verification options.
+    ///
+    /// Whether to re-read each copied file and compare it to the
+    /// source. Default is `None`.
+    pub verify: Verify,
+
+    /// Checksum manifest.
+    ///
+    /// If set, write a checksum manifest of all copied regular
+    /// files to this path, in the format used by `sha256sum` and
+    /// similar tools. Paths are relative to the destination if it is a
+    /// directory, otherwise to its parent. Default is `None`.
+    pub manifest: Option<PathBuf>,
+
+    /// The hash algorithm to use for the manifest. Default is
+    /// `Sha256`.
+    pub hash: HashType,
+
+    /// Copy journal.
+    ///
+    /// If set, record completed operations and the byte-ranges of
+    /// partially copied files to this path, so that an interrupted
+    /// copy can be resumed. Written ranges are synced and recorded in
+    /// batches, every 64MiB or 5 seconds per file. Atomic copies are
+    /// only recorded once complete. Default is `None`.
+    pub journal: Option<PathBuf>,
+
+    /// Resume a previous copy from the journal. Files completed by
+    /// the previous run are skipped, and partially copied files are
+    /// continued, if their source size and modification time are
+    /// unchanged. Requires `journal`. Default is `false`.
+    pub resume: bool,
+
+    /// Write files atomically.
+    ///
+    /// If set, each file is written to a temporary file in the
+    /// target directory, which is renamed over the target once the
+    /// data and metadata are complete. Partially copied files are not
+    /// resumed in this mode. Default is `false`.
+    pub atomic: bool,
+
+    /// Update mode.
+    ///
+    /// Which existing target files should be replaced. Default is
+    /// `All`.
+    pub update: Update,
+
+    /// The tolerance when comparing modification times for
+    /// `Update::Older`; the source is only considered newer if it
+    /// is more than this much later than the target. Default is 0.
+    pub modify_window: Duration,
+
+    /// Compare file contents rather than modification times for
+    /// `Update::Older`. Files of equal size are hashed serially while
+    /// planning, before any are copied, so this can be slow for
+    /// large trees. Default is `false`.
+    pub checksum: bool,
+
+    /// Mirror mode.
+    ///
+    /// If set, once the copy has completed any files and directories
+    /// in the target directories without a counterpart in the source
+    /// are removed. Files excluded by the source filters are not
+    /// removed. Default is `false`.
+    pub delete: bool,
+
+    /// Report the files that would be removed by `delete`, without
+    /// removing them. Default is `false`.
+    pub delete_dry_run: bool,
+
+    /// If set, files removed by `delete` are moved into this
+    /// directory, preserving their relative path, rather than
+    /// unlinked. Default is `None`.
+    pub backup_dir: Option<PathBuf>,
+
+    /// Continue copying after an operation fails. Each failure is
+    /// reported as an [XcpError::OperationFailed] update, and the copy
+    /// carries on with the remaining files. Default is `false`.
+    pub keep_going: bool,
+
+    /// Bandwidth limit.
+    ///
+    /// The maximum rate, in bytes per second, at which file data is
+    /// copied, shared between all workers. `None` or 0 is unlimited.
+    /// Default is `None`.
+    pub bwlimit: Option<u64>,
+
+    /// As with `bwlimit`, but limiting all reads of file data. This
+    /// includes reading files back for `verify`, `checksum` and the
+    /// manifest, as well as the copy itself. Default is `None`.
+    pub read_bwlimit: Option<u64>,
+
+    /// As with `bwlimit`, but only limiting writes to the
+    /// target. Default is `None`.
+    pub write_bwlimit: Option<u64>,
+
+    /// IO priority.
+    ///
+    /// If set, the IO scheduling priority of each worker thread. This
+    /// is currently only supported on Linux. Default is `None`.
+    pub ionice: Option<IoPriority>,
+
+    /// If set, the CPU niceness of each worker thread. Raising the
+    /// priority (i.e. a lower value) requires privileges. This is
+    /// currently only supported on Linux. Default is `None`.
+    pub nice: Option<i32>,
+
+    /// Drop copied files from the page cache.
+    ///
+    /// If set, each file is removed from the page cache once copied,
+    /// so that a bulk copy doesn't evict other data. The target is
+    /// synced first, as dirty pages can't be dropped. Default is
+    /// `false`.
+    pub drop_cache: bool,
 }
 
 impl Config {
@@ -162,15 +652,81 @@ impl Default for Config {
             workers: num_cpus::get(),
             block_size: u64::MAX,
             gitignore: false,
+            filter: Filter::default(),
+            prune_empty_dirs: false,
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            older_than: None,
+            file_types: Vec::new(),
+            one_file_system: false,
+            max_depth: None,
+            min_depth: None,
             no_clobber: false,
-            no_perms: false,
-            no_timestamps: false,
-            ownership: false,
-            dereference: false,
+            preserve: Preserve::default(),
+            dereference: Dereference::Never,
+            unsafe_links: UnsafeLinks::Keep,
             no_target_directory: false,
             fsync: false,
             reflink: Reflink::Auto,
+            sparse: Sparse::Auto,
             backup: Backup::None,
+            verify: Verify::None,
+            manifest: None,
+            hash: HashType::Sha256,
+            journal: None,
+            resume: false,
+            atomic: false,
+            update: Update::All,
+            modify_window: Duration::ZERO,
+            checksum: false,
+            delete: false,
+            delete_dry_run: false,
+            backup_dir: None,
+            keep_going: false,
+            bwlimit: None,
+            read_bwlimit: None,
+            write_bwlimit: None,
+            ionice: None,
+            nice: None,
+            drop_cache: false,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ionice_parse() {
+        let p: IoPriority = "best-effort:7".parse().unwrap();
+        assert_eq!(IoPriority { class: IoClass::BestEffort, level: 7 }, p);
+        let p: IoPriority = "3".parse().unwrap();
+        assert_eq!(IoClass::Idle, p.class);
+        let p: IoPriority = "realtime".parse().unwrap();
+        assert_eq!(IoPriority { class: IoClass::RealTime, level: 4 }, p);
+
+        assert!("idle:8".parse::<IoPriority>().is_err());
+        assert!("low".parse::<IoPriority>().is_err());
+    }
+
+    #[test]
+    fn test_preserve_parse() {
+        let p: Preserve = "mode,links".parse().unwrap();
+        assert_eq!(Preserve { mode: true, links: true, ..Preserve::none() }, p);
+
+        assert_eq!(Preserve::all(), "all".parse().unwrap());
+        assert_eq!(Preserve::all(), "xattr, ALL".parse().unwrap());
+        assert!("mode,nope".parse::<Preserve>().is_err());
+    }
+
+    #[test]
+    fn test_preserve_sets() {
+        let p = Preserve::default()
+            .with(Preserve::basic())
+            .without("timestamps,acl".parse().unwrap());
+        assert!(p.mode && p.ownership && p.xattr);
+        assert!(!p.timestamps && !p.acl && !p.links && !p.flags);
+    }
+}
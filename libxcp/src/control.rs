@@ -0,0 +1,159 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Control of a running copy.
+//!
+//! Each driver has a [CopyController], retrieved with
+//! [CopyDriver::controller()](crate::drivers::CopyDriver::controller),
+//! which can be used from another thread to pause, resume or cancel
+//! the copy. Workers check the controller between operations and
+//! between blocks of a file, so control takes effect promptly.
+//!
+//! On cancellation no new operations are started, and the driver's
+//! `copy()` returns [XcpError::Cancelled]. Partially copied files are
+//! removed, unless a journal is being kept, in which case they are
+//! left in place and recorded so the copy can be resumed.
+//!
+//! # Example
+//!
+//!     # use libxcp::errors::Result;
+//!     # use std::path::PathBuf;
+//!     # use std::sync::Arc;
+//!     # use std::thread;
+//!     # use tempfile::TempDir;
+//!     use libxcp::config::Config;
+//!     use libxcp::drivers::{Drivers, load_driver};
+//!     use libxcp::errors::XcpError;
+//!     use libxcp::feedback::NoopUpdater;
+//!     # fn main() -> Result<()> {
+//!
+//!     let dest = TempDir::new()?;
+//!     let config = Arc::new(Config::default());
+//!     let driver = load_driver(Drivers::ParFile, &config)?;
+//!     let control = driver.controller();
+//!
+//!     // Cancel before starting; nothing will be copied.
+//!     control.cancel();
+//!     let result = driver.copy(vec![PathBuf::from("src")], dest.path(), Arc::new(NoopUpdater));
+//!     assert!(matches!(result.unwrap_err().downcast_ref(), Some(XcpError::Cancelled)));
+//!     # Ok(())
+//!     # }
+
+use std::sync::{Arc, Condvar, Mutex};
+
+use log::info;
+
+use crate::errors::{Result, XcpError};
+
+#[derive(Debug, Default)]
+struct State {
+    paused: bool,
+    cancelled: bool,
+}
+
+/// A handle to pause, resume or cancel a copy. This can be cloned
+/// and shared between threads; all clones control the same copy.
+#[derive(Clone, Debug, Default)]
+pub struct CopyController {
+    state: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl CopyController {
+    pub fn new() -> CopyController {
+        CopyController::default()
+    }
+
+    /// Stop the copy. Any paused workers are released.
+    pub fn cancel(&self) {
+        info!("Cancelling copy");
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().cancelled = true;
+        cvar.notify_all();
+    }
+
+    /// Suspend the copy; workers will wait once their current block
+    /// is complete.
+    pub fn pause(&self) {
+        info!("Pausing copy");
+        self.state.0.lock().unwrap().paused = true;
+    }
+
+    /// Continue a paused copy.
+    pub fn resume(&self) {
+        info!("Resuming copy");
+        let (lock, cvar) = &*self.state;
+        lock.lock().unwrap().paused = false;
+        cvar.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.0.lock().unwrap().cancelled
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.0.lock().unwrap().paused
+    }
+
+    /// Wait while the copy is paused, and return
+    /// [XcpError::Cancelled] if it has been cancelled.
+    pub(crate) fn checkpoint(&self) -> Result<()> {
+        let (lock, cvar) = &*self.state;
+        let state = cvar.wait_while(lock.lock().unwrap(), |s| s.paused && !s.cancelled)
+            .unwrap();
+        if state.cancelled {
+            return Err(XcpError::Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_pause_resume() -> Result<()> {
+        let control = CopyController::new();
+        control.checkpoint()?;
+
+        control.pause();
+        let worker = {
+            let c = control.clone();
+            thread::spawn(move || c.checkpoint())
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!worker.is_finished());
+
+        control.resume();
+        assert!(worker.join().unwrap().is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_paused() {
+        let control = CopyController::new();
+        control.pause();
+        let worker = {
+            let c = control.clone();
+            thread::spawn(move || c.checkpoint())
+        };
+        control.cancel();
+        assert!(worker.join().unwrap().is_err());
+        assert!(control.checkpoint().is_err());
+    }
+}
@@ -41,8 +41,10 @@ use std::str::FromStr;
 use std::sync::Arc;
 
 use crate::config::Config;
+use crate::control::CopyController;
 use crate::errors::{Result, XcpError};
 use crate::feedback::StatusUpdater;
+use crate::plan::CopyPlan;
 
 /// The trait specifying driver operations; drivers should implement
 /// this.
@@ -54,6 +56,18 @@ pub trait CopyDriver {
     /// `copy()` itself will block until all work is complete, so
     /// should be run in a thread if real-time updates are required.
     fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()>;
+
+    /// Perform the operations of a plan created by [CopyPlan::new()]
+    /// with the same `dest` and configuration, rather than walking
+    /// the sources again. The plan is otherwise executed as with
+    /// `copy()`, but any changes to the sources since it was created
+    /// are not seen.
+    fn copy_plan(&self, plan: &CopyPlan, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()>;
+
+    /// A handle to pause, resume or cancel copies by this driver. This
+    /// should be retrieved before calling `copy()`; see
+    /// [crate::control].
+    fn controller(&self) -> CopyController;
 }
 
 /// An enum specifing the driver to use. This is just a helper for
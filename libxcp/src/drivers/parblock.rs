@@ -19,25 +19,24 @@
 //! but has a higher overhead.
 
 use std::cmp;
-use std::fs::remove_file;
 use std::ops::Range;
-use std::os::unix::fs::symlink;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 
 use cfg_if::cfg_if;
 use crossbeam_channel as cbc;
-use libfs::copy_node;
 use log::{error, info};
 use blocking_threadpool::{Builder, ThreadPool};
 
-use crate::config::Config;
+use crate::config::{Config, Sparse};
+use crate::control::CopyController;
 use crate::drivers::CopyDriver;
 use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
-use libfs::{copy_file_offset, map_extents, merge_extents, probably_sparse};
+use crate::feedback::{CopyMethod, StatusUpdate, StatusUpdater};
+use crate::operations::{copy_special, copy_symlink, link_copied, Context, CopyHandle, Operation, Work, tree_walker};
+use crate::plan::CopyPlan;
+use libfs::{copy_file_offset, copy_file_offset_sparse, map_extents, merge_extents, probably_sparse};
 
 // ********************************************************************** //
 
@@ -62,6 +61,7 @@ const fn supported_platform() -> bool {
 
 pub struct Driver {
     config: Arc<Config>,
+    control: CopyController,
 }
 
 impl Driver {
@@ -74,40 +74,53 @@ impl Driver {
 
         Ok(Self {
             config,
+            control: CopyController::new(),
         })
     }
-}
 
-impl CopyDriver for Driver {
-    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+    fn run(&self, work: Work, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
         let (file_tx, file_rx) = cbc::unbounded::<Operation>();
+        let ctx = Arc::new(Context::new(self.config.clone(), dest, stats, self.control.clone())?);
 
         // Start (single) dispatch worker
         let dispatcher = {
-            let q_config = self.config.clone();
-            let st = stats.clone();
-            thread::spawn(move || dispatch_worker(file_rx, &st, q_config))
+            let c = ctx.clone();
+            thread::spawn(move || dispatch_worker(file_rx, &c))
         };
 
         // Thread which walks the file tree and sends jobs to the
         // workers. The worker tx channel is moved to the walker so it is
         // closed, which will cause the workers to shutdown on completion.
         let walk_worker = {
-            let sc = stats.clone();
             let d = dest.to_path_buf();
-            let c = self.config.clone();
-            thread::spawn(move || tree_walker(sources, &d, &c, file_tx, sc))
+            let c = ctx.clone();
+            thread::spawn(move || tree_walker(work, &d, &c, file_tx))
         };
 
         walk_worker.join()
             .map_err(|_| XcpError::CopyError("Error walking copy tree".to_string()))??;
         dispatcher.join()
             .map_err(|_| XcpError::CopyError("Error dispatching copy operation".to_string()))??;
+        ctx.finish()?;
 
         Ok(())
     }
 }
 
+impl CopyDriver for Driver {
+    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        self.run(Work::Sources(sources), dest, stats)
+    }
+
+    fn copy_plan(&self, plan: &CopyPlan, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        self.run(plan.into(), dest, stats)
+    }
+
+    fn controller(&self) -> CopyController {
+        self.control.clone()
+    }
+}
+
 // ********************************************************************** //
 
 fn queue_file_range(
@@ -117,8 +130,8 @@ fn queue_file_range(
     status_channel: &Arc<dyn StatusUpdater>,
 ) -> Result<u64> {
     let len = range.end - range.start;
-    let bsize = handle.config.block_size;
-    let blocks = (len / bsize) + (if len % bsize > 0 { 1 } else { 0 });
+    let bsize = handle.ctx.block_size();
+    let blocks = (len / bsize) + (if !len.is_multiple_of(bsize) { 1 } else { 0 });
 
     for blkn in 0..blocks {
         let harc = handle.clone();
@@ -127,14 +140,33 @@ fn queue_file_range(
         let off = range.start + (blkn * bsize);
 
         pool.execute(move || {
-            let copy_result = copy_file_offset(&harc.infd, &harc.outfd, bytes, off as i64);
+            harc.ctx.prioritise_thread();
+            harc.ctx.throttle.acquire(bytes);
+            if harc.ctx.control.checkpoint().is_err() {
+                // Cancelled; the partial file is cleaned up on drop.
+                harc.fail();
+                return;
+            }
+            let copy_result = if harc.ctx.config.sparse == Sparse::Always {
+                copy_file_offset_sparse(&harc.infd, &harc.outfd, bytes, off as i64)
+            } else {
+                copy_file_offset(&harc.infd, &harc.outfd, bytes, off as i64)
+            };
+            let copy_result = copy_result
+                .map_err(|e| e.into())
+                .and_then(|n| harc.commit(off, bytes).map(|_| n));
             let stat_result = match copy_result {
                 Ok(bytes) => {
                     stat_tx.send(StatusUpdate::Copied(bytes as u64))
                 }
                 Err(e) => {
-                    error!("Error copying: aborting.");
-                    stat_tx.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))
+                    // Only report the first failed block of a file.
+                    if harc.fail() {
+                        error!("Error copying {:?}: {e}", harc.source);
+                        stat_tx.send(StatusUpdate::Error(XcpError::failed(&harc.source, &e)))
+                    } else {
+                        Ok(())
+                    }
                 }
             };
             if let Err(e) = stat_result {
@@ -151,33 +183,60 @@ fn queue_file_blocks(
     source: &Path,
     dest: &Path,
     pool: &ThreadPool,
-    status_channel: &Arc<dyn StatusUpdater>,
-    config: &Arc<Config>,
+    ctx: &Arc<Context>,
 ) -> Result<u64> {
-    let handle = CopyHandle::new(source, dest, config)?;
+    let status_channel = &ctx.updates;
+    let handle = CopyHandle::new(source, dest, ctx)?;
     let len = handle.metadata.len();
 
-    if handle.try_reflink()? {
-        info!("Reflinked, skipping rest of copy");
-        return Ok(len);
-    }
-
     // Put the open files in an Arc, which we drop once work has been
     // queued. This will keep the files open until all work has been
     // consumed, then close them. (This may be overkill; opening the
     // files in the workers would also be valid.)
     let harc = Arc::new(handle);
+    let queued = match harc.try_reflink() {
+        Ok(true) => {
+            info!("Reflinked, skipping rest of copy");
+            Ok(len)
+        }
+        Ok(false) => queue_handle(&harc, pool, status_channel),
+        Err(e) => Err(e),
+    };
+    if queued.is_err() {
+        harc.fail();
+    }
+    // Finalising the copy may read the target back for verification
+    // or the manifest, so make sure the last reference is dropped on
+    // the pool rather than holding up the dispatcher.
+    pool.execute(move || drop(harc));
+    queued
+}
+
+fn queue_handle(
+    harc: &Arc<CopyHandle>,
+    pool: &ThreadPool,
+    status_channel: &Arc<dyn StatusUpdater>,
+) -> Result<u64> {
+    let config = &harc.ctx.config;
+    let len = harc.metadata.len();
 
     let queue_whole_file = || {
-        queue_file_range(&harc, 0..len, pool, status_channel)
+        queue_file_range(harc, 0..len, pool, status_channel)
     };
 
-    if probably_sparse(&harc.infd)? {
+    if let Some(ranges) = &harc.resume {
+        let mut queued = 0;
+        for range in harc.data_ranges(ranges)? {
+            queued += queue_file_range(harc, range, pool, status_channel)?;
+        }
+        Ok(queued)
+    } else if config.sparse != Sparse::Never && probably_sparse(&harc.infd)? {
         if let Some(extents) = map_extents(&harc.infd)? {
+            harc.set_method(CopyMethod::Sparse);
             let sparse_map = merge_extents(extents)?;
             let mut queued = 0;
             for ext in sparse_map {
-                queued += queue_file_range(&harc, ext.into(), pool, status_channel)?;
+                queued += queue_file_range(harc, ext.into(), pool, status_channel)?;
             }
             Ok(queued)
         } else {
@@ -190,7 +249,9 @@ fn queue_file_blocks(
 
 // Dispatch worker; receives queued files and hands them to
 // queue_file_blocks() which splits them onto the copy-pool.
-fn dispatch_worker(file_q: cbc::Receiver<Operation>, stats: &Arc<dyn StatusUpdater>, config: Arc<Config>) -> Result<()> {
+fn dispatch_worker(file_q: cbc::Receiver<Operation>, ctx: &Arc<Context>) -> Result<()> {
+    ctx.prioritise_thread();
+    let config = &ctx.config;
     let nworkers = config.num_workers();
     let copy_pool = Builder::new()
         .num_threads(nworkers)
@@ -201,44 +262,57 @@ fn dispatch_worker(file_q: cbc::Receiver<Operation>, stats: &Arc<dyn StatusUpdat
         .queue_len(128)
         .build();
     for op in file_q {
+        if let Err(e) = ctx.control.checkpoint() {
+            // Let queued blocks see the cancellation and finish.
+            copy_pool.join();
+            return Err(e);
+        }
         match op {
             Operation::Copy(from, to) => {
                 info!("Dispatch[{:?}]: Copy {:?} -> {:?}", thread::current().id(), from, to);
-                let r = queue_file_blocks(&from, &to, &copy_pool, stats, &config);
+                let r = queue_file_blocks(&from, &to, &copy_pool, ctx);
                 if let Err(e) = r {
-                    stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Dispatcher: Error copying {from:?} -> {to:?}.");
-                    return Err(e)
+                    ctx.links.complete(&to, false);
+                    ctx.report_failure(&from, e)?;
                 }
             }
 
             // Inline the following operations as the should be near-instant.
             Operation::Link(from, to) => {
                 info!("Dispatch[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
-                let r = symlink(&from, &to);
-                if let Err(e) = r {
-                    stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Error symlinking: {from:?} -> {to:?}; aborting.");
-                    return Err(e.into())
+                match copy_symlink(&from, &to, config) {
+                    Ok(()) => {
+                        ctx.record_done(&to);
+                        ctx.updates.send(StatusUpdate::SymlinkCreated { from, to })?;
+                    }
+                    Err(e) => ctx.report_failure(&from, e)?,
+                }
+            }
+
+            Operation::HardLink(from, to) => {
+                info!("Dispatch[{:?}]: Hard-link {:?} -> {:?}", thread::current().id(), from, to);
+                if let Err(e) = link_copied(&from, &to, ctx) {
+                    ctx.report_failure(&from, e)?;
                 }
             }
 
             Operation::Special(from, to) => {
                 info!("Dispatch[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
-                    }
-                    remove_file(&to)?;
+                match copy_special(&from, &to, config) {
+                    Ok(()) => { ctx.record_done(&to); }
+                    Err(e) => ctx.report_failure(&from, e)?,
                 }
-                copy_node(&from, &to)?;
             }
+
+            Operation::CreateDir(..) => unreachable!("Directories are created by the tree-walker"),
         }
     }
     info!("Queuing complete");
 
     copy_pool.join();
     info!("Pool complete");
+    // Blocks abandoned on cancellation don't report errors.
+    ctx.control.checkpoint()?;
 
     Ok(())
 }
@@ -18,46 +18,45 @@
 //! modern NVME devices, but can bottleneck on larger files.
 
 use crossbeam_channel as cbc;
-use log::{debug, error, info};
-use libfs::copy_node;
-use std::fs::remove_file;
-use std::os::unix::fs::symlink;
+use log::{debug, info};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::thread;
 
 use crate::config::Config;
+use crate::control::CopyController;
 use crate::drivers::CopyDriver;
 use crate::errors::{Result, XcpError};
 use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::operations::{CopyHandle, Operation, tree_walker};
+use crate::operations::{copy_special, copy_symlink, link_copied, Context, CopyHandle, Operation, Work, tree_walker};
+use crate::plan::CopyPlan;
 
 // ********************************************************************** //
 
 pub struct Driver {
     config: Arc<Config>,
+    control: CopyController,
 }
 
 impl Driver {
     pub fn new(config: Arc<Config>) -> Result<Self> {
         Ok(Self {
             config,
+            control: CopyController::new(),
         })
     }
-}
 
-impl CopyDriver for Driver {
-    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+    fn run(&self, work: Work, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
         let (work_tx, work_rx) = cbc::unbounded();
+        let ctx = Arc::new(Context::new(self.config.clone(), dest, stats, self.control.clone())?);
 
         // Thread which walks the file tree and sends jobs to the
         // workers. The worker tx channel is moved to the walker so it is
         // closed, which will cause the workers to shutdown on completion.
         let walk_worker = {
-            let sc = stats.clone();
             let d = dest.to_path_buf();
-            let o = self.config.clone();
-            thread::spawn(move || tree_walker(sources, &d, &o, work_tx, sc))
+            let c = ctx.clone();
+            thread::spawn(move || tree_walker(work, &d, &c, work_tx))
         };
 
         // Worker threads. Will consume work and then shutdown once the
@@ -67,9 +66,8 @@ impl CopyDriver for Driver {
         for _ in 0..nworkers {
             let copy_worker = {
                 let wrx = work_rx.clone();
-                let sc = stats.clone();
-                let conf = self.config.clone();
-                thread::spawn(move || copy_worker(wrx, &conf, sc))
+                let c = ctx.clone();
+                thread::spawn(move || copy_worker(wrx, &c))
             };
             joins.push(copy_worker);
         }
@@ -80,18 +78,35 @@ impl CopyDriver for Driver {
             handle.join()
                 .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
         }
+        ctx.finish()?;
 
         Ok(())
     }
+}
+
+impl CopyDriver for Driver {
+    fn copy(&self, sources: Vec<PathBuf>, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        self.run(Work::Sources(sources), dest, stats)
+    }
+
+    fn copy_plan(&self, plan: &CopyPlan, dest: &Path, stats: Arc<dyn StatusUpdater>) -> Result<()> {
+        self.run(plan.into(), dest, stats)
+    }
 
+    fn controller(&self) -> CopyController {
+        self.control.clone()
+    }
 }
 
 // ********************************************************************** //
 
-fn copy_worker(work: cbc::Receiver<Operation>, config: &Arc<Config>, updates: Arc<dyn StatusUpdater>) -> Result<()> {
+fn copy_worker(work: cbc::Receiver<Operation>, ctx: &Arc<Context>) -> Result<()> {
     debug!("Starting copy worker {:?}", thread::current().id());
+    ctx.prioritise_thread();
+    let config = &ctx.config;
     for op in work {
         debug!("Received operation {op:?}");
+        ctx.control.checkpoint()?;
 
         match op {
             Operation::Copy(from, to) => {
@@ -99,31 +114,43 @@ fn copy_worker(work: cbc::Receiver<Operation>, config: &Arc<Config>, updates: Ar
                 // copy_file() sends back its own updates, but we should
                 // send back any errors as they may have occurred
                 // before the copy started..
-                let r = CopyHandle::new(&from, &to, config)
-                    .and_then(|hdl| hdl.copy_file(&updates));
+                let r = CopyHandle::new(&from, &to, ctx)
+                    .and_then(|hdl| hdl.copy_file());
                 if let Err(e) = r {
-                    updates.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
-                    error!("Error copying: {from:?} -> {to:?}; aborting.");
-                    return Err(e)
+                    // Release anything waiting to hard-link to this file.
+                    ctx.links.complete(&to, false);
+                    ctx.report_failure(&from, e)?;
                 }
             }
 
             Operation::Link(from, to) => {
                 info!("Worker[{:?}]: Symlink {:?} -> {:?}", thread::current().id(), from, to);
-                let _r = symlink(&from, &to);
+                match copy_symlink(&from, &to, config) {
+                    Ok(()) => {
+                        ctx.record_done(&to);
+                        ctx.updates.send(StatusUpdate::SymlinkCreated { from, to })?;
+                    }
+                    Err(e) => ctx.report_failure(&from, e)?,
+                }
+            }
+
+            Operation::HardLink(from, to) => {
+                info!("Worker[{:?}]: Hard-link {:?} -> {:?}", thread::current().id(), from, to);
+                if let Err(e) = link_copied(&from, &to, ctx) {
+                    ctx.report_failure(&from, e)?;
+                }
             }
 
             Operation::Special(from, to) => {
                 info!("Worker[{:?}]: Special file {:?} -> {:?}", thread::current().id(), from, to);
-                if to.exists() {
-                    if config.no_clobber {
-                        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to).into());
-                    }
-                    remove_file(&to)?;
+                match copy_special(&from, &to, config) {
+                    Ok(()) => { ctx.record_done(&to); }
+                    Err(e) => ctx.report_failure(&from, e)?,
                 }
-                copy_node(&from, &to)?;
             }
 
+            Operation::CreateDir(..) => unreachable!("Directories are created by the tree-walker"),
+
         }
     }
     debug!("Copy worker {:?} shutting down", thread::current().id());
@@ -16,12 +16,16 @@
 
 //! Custom error types.
 
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
 
 pub use anyhow::Result;
 
 #[derive(Debug, thiserror::Error)]
 pub enum XcpError {
+    #[error("Copy cancelled")]
+    Cancelled,
+
     #[error("Error during copy: {0}")]
     CopyError(String),
 
@@ -40,6 +44,11 @@ pub enum XcpError {
     #[error("Invalid source: {0}")]
     InvalidSource(&'static str),
 
+    /// An operation on a file failed; the path, error message and OS
+    /// error number, if known.
+    #[error("Failed to copy {0:?}: {1}")]
+    OperationFailed(PathBuf, String, Option<i32>),
+
     #[error("Failed to reflink file and 'always' was specified: {0}")]
     ReflinkFailed(String),
 
@@ -49,6 +58,40 @@ pub enum XcpError {
     #[error("Unknown file-type: {0}")]
     UnknownFileType(PathBuf),
 
+    #[error("Verification failed; target does not match source: {0}")]
+    VerifyFailed(PathBuf),
+
     #[error("Unsupported OS")]
     UnsupportedOS(&'static str),
 }
+
+impl XcpError {
+    /// Convert an error from an operation on `path` to an
+    /// [XcpError::OperationFailed], retaining the underlying OS error
+    /// number if there is one.
+    pub fn failed(path: &Path, err: &anyhow::Error) -> XcpError {
+        let errno = err.chain()
+            .find_map(|e| e.downcast_ref::<io::Error>())
+            // Walker errors wrap their IO errors without exposing them as the source.
+            .or_else(|| err.chain()
+                .find_map(|e| e.downcast_ref::<ignore::Error>())
+                .and_then(ignore::Error::io_error))
+            .and_then(io::Error::raw_os_error);
+        XcpError::OperationFailed(path.to_path_buf(), err.to_string(), errno)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_errno() {
+        let err = anyhow::Error::new(io::Error::from_raw_os_error(13)).context("Failed to open");
+        let failed = XcpError::failed(Path::new("a/b"), &err);
+        assert!(matches!(failed, XcpError::OperationFailed(ref p, _, Some(13)) if p == Path::new("a/b")));
+
+        let err = anyhow::anyhow!("Not an OS error");
+        assert!(matches!(XcpError::failed(Path::new("a"), &err), XcpError::OperationFailed(_, _, None)));
+    }
+}
@@ -24,6 +24,7 @@
 //! * [NoopUpdater]
 //! * [ChannelUpdater]
 
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use crossbeam_channel as cbc;
@@ -33,16 +34,50 @@ use crate::errors::{Result, XcpError};
 
 /// A struct representing an updated status.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum StatusUpdate {
     /// An update representing a successful copy of bytes between
     /// files.
     Copied(u64),
     /// An update representing that this number of bytes will need to be copied.
     Size(u64),
+    /// A file or other entry that did not need to be copied (e.g. the
+    /// target was already up to date). Any bytes will have been
+    /// included in a previous `Size` update.
+    Skipped { path: PathBuf, size: u64 },
+    /// A target file or directory not present in the source was
+    /// removed (or would be, in a dry-run).
+    Deleted(PathBuf),
+    /// Copying of a file has started.
+    FileStarted { from: PathBuf, to: PathBuf, size: u64 },
+    /// A file has been completely copied, and any attributes
+    /// applied.
+    FileFinished { path: PathBuf, method: CopyMethod },
+    /// A symlink was recreated in the target.
+    SymlinkCreated { from: PathBuf, to: PathBuf },
+    /// A target directory was created.
+    DirCreated { path: PathBuf },
+    /// An existing target was backed up before being replaced.
+    BackedUp { from: PathBuf, to: PathBuf },
     /// An error during a copy operation.
     Error(XcpError)
 }
 
+/// How the data of a file was copied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum CopyMethod {
+    /// The target shares the source's blocks (copy-on-write).
+    Reflink,
+    /// The data was copied in the kernel with `copy_file_range()`.
+    CopyFileRange,
+    /// The data was read into userspace and written out.
+    Userspace,
+    /// Only the data segments of a sparse file were copied.
+    Sparse,
+}
+
 pub trait StatusUpdater: Sync + Send {
     fn send(&self, update: StatusUpdate) -> Result<()>;
 }
@@ -56,6 +91,8 @@ pub struct ChannelUpdater {
     chan_tx: cbc::Sender<StatusUpdate>,
     chan_rx: cbc::Receiver<StatusUpdate>,
     config: Arc<Config>,
+    // Total bytes copied, and the total included in sent updates.
+    copied: AtomicU64,
     sent: AtomicU64,
 }
 
@@ -67,6 +104,7 @@ impl ChannelUpdater {
             chan_tx,
             chan_rx,
             config: config.clone(),
+            copied: AtomicU64::new(0),
             sent: AtomicU64::new(0),
         }
     }
@@ -92,11 +130,16 @@ impl StatusUpdater for ChannelUpdater {
     // Wrapper around channel-send that groups updates together
     fn send(&self, update: StatusUpdate) -> Result<()> {
         if let StatusUpdate::Copied(bytes) = update {
-            // Avoid saturating the queue with small writes
+            // Avoid saturating the queue with small writes; batch them
+            // up until a block boundary is crossed.
             let bsize = self.config.block_size;
-            let prev_written = self.sent.fetch_add(bytes, Ordering::Relaxed);
-            if ((prev_written + bytes) / bsize) > (prev_written / bsize) {
-                self.chan_tx.send(update)?;
+            let prev_written = self.copied.fetch_add(bytes, Ordering::Relaxed);
+            let written = prev_written + bytes;
+            if (written / bsize) > (prev_written / bsize) {
+                let prev_sent = self.sent.fetch_max(written, Ordering::Relaxed);
+                if written > prev_sent {
+                    self.chan_tx.send(StatusUpdate::Copied(written - prev_sent))?;
+                }
             }
         } else {
             self.chan_tx.send(update)?;
@@ -105,6 +148,43 @@ impl StatusUpdater for ChannelUpdater {
     }
 }
 
+impl Drop for ChannelUpdater {
+    // Send any bytes held back by batching.
+    fn drop(&mut self) {
+        let unsent = self.copied.load(Ordering::Relaxed)
+            .saturating_sub(self.sent.load(Ordering::Relaxed));
+        if unsent > 0 {
+            let _ = self.chan_tx.send(StatusUpdate::Copied(unsent));
+        }
+    }
+}
+
+/// Running totals of the updates from a copy.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CopyStats {
+    /// Bytes to be copied, from `Size` updates.
+    pub size: u64,
+    pub copied: u64,
+    pub skipped: u64,
+    /// Files completely copied.
+    pub files: u64,
+    pub errors: u64,
+}
+
+impl CopyStats {
+    /// Add an update to the totals.
+    pub fn update(&mut self, update: &StatusUpdate) {
+        match update {
+            StatusUpdate::Size(n) => self.size += n,
+            StatusUpdate::Copied(n) => self.copied += n,
+            StatusUpdate::Skipped { size, .. } => self.skipped += size,
+            StatusUpdate::FileFinished { .. } => self.files += 1,
+            StatusUpdate::Error(_) => self.errors += 1,
+            _ => {}
+        }
+    }
+}
+
 /// A null updater for when no feedback is required.
 pub struct NoopUpdater;
 
@@ -113,3 +193,30 @@ impl StatusUpdater for NoopUpdater {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_batching() -> Result<()> {
+        let config = Arc::new(Config { block_size: 100, ..Config::default() });
+        let updater = ChannelUpdater::new(&config);
+        let rx = updater.rx_channel();
+
+        for _ in 0..25 {
+            updater.send(StatusUpdate::Copied(10))?;
+        }
+        drop(updater);
+
+        let copied = rx.iter()
+            .map(|u| match u {
+                StatusUpdate::Copied(n) => n,
+                _ => 0,
+            })
+            .collect::<Vec<u64>>();
+        // Two full blocks, then the remainder on close.
+        assert_eq!(vec![100, 100, 50], copied);
+        Ok(())
+    }
+}
@@ -0,0 +1,192 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Minimal reading of git index files, to find the tracked files in a
+//! repository. See
+//! [gitformat-index](https://git-scm.com/docs/gitformat-index).
+
+use std::collections::BTreeSet;
+use std::ffi::OsStr;
+use std::fs::{read, read_to_string};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+use crate::errors::{Result, XcpError};
+
+/// The files tracked in the index of the repository with its working
+/// tree at `root`, as paths under `root`. A repository with no index
+/// has no tracked files.
+pub(crate) fn tracked_files(root: &Path) -> Result<BTreeSet<PathBuf>> {
+    let git_dir = git_dir(root)?;
+    let index = git_dir.join("index");
+    if !index.exists() {
+        return Ok(BTreeSet::new());
+    }
+    let sha256 = read_to_string(git_dir.join("config"))
+        .is_ok_and(|c| c.to_lowercase().contains("objectformat = sha256"));
+    let hash_len = if sha256 { 32 } else { 20 };
+
+    let names = parse_index(&read(&index)?, hash_len)
+        .ok_or(XcpError::InvalidSource("Unsupported or corrupt git index"))?;
+    Ok(names.into_iter()
+       .map(|name| root.join(OsStr::from_bytes(&name)))
+       .collect())
+}
+
+// `.git` is a file containing the path of the git directory for
+// worktrees and submodules.
+fn git_dir(root: &Path) -> Result<PathBuf> {
+    let dotgit = root.join(".git");
+    if !dotgit.is_file() {
+        return Ok(dotgit);
+    }
+    let contents = read_to_string(&dotgit)?;
+    let dir = contents.trim_end()
+        .strip_prefix("gitdir: ")
+        .ok_or(XcpError::InvalidSource("Unexpected contents in .git file"))?;
+    Ok(root.join(dir))
+}
+
+fn be16(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn be32(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+// The variable-length offset encoding used by version 4 indexes.
+fn varint(data: &[u8]) -> Option<(usize, usize)> {
+    let mut used = 1;
+    let mut c = *data.first()?;
+    let mut val = (c & 0x7f) as usize;
+    while c & 0x80 != 0 {
+        c = *data.get(used)?;
+        used += 1;
+        val = ((val + 1) << 7) | (c & 0x7f) as usize;
+    }
+    Some((val, used))
+}
+
+// The NUL-terminated string at `pos`, and the position after it.
+fn cstr(data: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let len = data.get(pos..)?.iter().position(|&b| b == 0)?;
+    Some((&data[pos..pos + len], pos + len + 1))
+}
+
+// The entry names in an index of versions 2 to 4. Extensions are
+// ignored.
+fn parse_index(data: &[u8], hash_len: usize) -> Option<Vec<Vec<u8>>> {
+    if data.get(..4)? != b"DIRC" {
+        return None;
+    }
+    let version = be32(data, 4)?;
+    if !(2..=4).contains(&version) {
+        return None;
+    }
+    let count = be32(data, 8)? as usize;
+
+    let mut names: Vec<Vec<u8>> = Vec::with_capacity(count);
+    let mut pos = 12;
+    for _ in 0..count {
+        let start = pos;
+        // Timestamps, stat data and mode, then the object ID.
+        pos += 40 + hash_len;
+        let flags = be16(data, pos)?;
+        pos += 2;
+        if version >= 3 && flags & 0x4000 != 0 {
+            pos += 2;
+        }
+        let name = if version == 4 {
+            // Prefix-compressed against the previous name.
+            let (strip, used) = varint(data.get(pos..)?)?;
+            let (suffix, next) = cstr(data, pos + used)?;
+            pos = next;
+            let prev = names.last().map(Vec::as_slice).unwrap_or_default();
+            let mut name = prev.get(..prev.len().checked_sub(strip)?)?.to_vec();
+            name.extend_from_slice(suffix);
+            name
+        } else {
+            // NUL-padded to a multiple of 8 bytes.
+            let (name, next) = cstr(data, pos)?;
+            pos = start + (next - start).div_ceil(8) * 8;
+            name.to_vec()
+        };
+        names.push(name);
+    }
+    Some(names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+    use std::process::Command;
+    use tempfile::TempDir;
+
+    fn git(dir: &Path, args: &[&str]) -> bool {
+        Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .output()
+            .is_ok_and(|out| out.status.success())
+    }
+
+    #[test]
+    fn test_tracked_files() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let root = tdir.path();
+        if !git(root, &["init", "-q"]) {
+            // No git available to create the index.
+            return Ok(());
+        }
+        assert!(tracked_files(root)?.is_empty());
+
+        create_dir_all(root.join("src/nested/deeper"))?;
+        write(root.join("README.md"), "readme")?;
+        write(root.join("src/main.rs"), "main")?;
+        write(root.join("src/nested/deeper/a-long-file-name.txt"), "long")?;
+        write(root.join("src/nested/deeper/a-long-file-name.txt.orig"), "orig")?;
+        write(root.join("untracked.txt"), "untracked")?;
+        assert!(git(root, &["add", "README.md", "src"]));
+
+        let expected = BTreeSet::from([
+            root.join("README.md"),
+            root.join("src/main.rs"),
+            root.join("src/nested/deeper/a-long-file-name.txt"),
+            root.join("src/nested/deeper/a-long-file-name.txt.orig"),
+        ]);
+        assert_eq!(expected, tracked_files(root)?);
+
+        for version in ["3", "4"] {
+            assert!(git(root, &["update-index", "--index-version", version]));
+            assert_eq!(expected, tracked_files(root)?);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrupt_index() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let root = tdir.path();
+        create_dir_all(root.join(".git"))?;
+        write(root.join(".git/index"), b"DIRC\0\0\0\x02\0\0\0\x05short")?;
+        assert!(tracked_files(root).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,331 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! An on-disk journal of completed operations, allowing interrupted
+//! copies to be resumed.
+//!
+//! The journal is a line-based, append-only file keyed on the target
+//! path. Each line is one of:
+//!
+//! * `S <size> <mtime> <mtime_nsec> <target>`: A file copy was started
+//!   from a source with the given size and modification time.
+//! * `R <start> <end> <target>`: A byte-range of the file has been
+//!   written.
+//! * `D <target>`: The operation completed.
+//!
+//! Backslashes and newlines in paths are escaped.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::ops::Range;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{debug, info};
+
+use crate::errors::{Result, XcpError};
+
+const HEADER: &str = "xcp-journal 1";
+
+// Source size and mtime.
+type Ident = (u64, i64, i64);
+
+fn ident(meta: &Metadata) -> Ident {
+    (meta.len(), meta.mtime(), meta.mtime_nsec())
+}
+
+#[derive(Debug, Default)]
+struct Entry {
+    ident: Option<Ident>,
+    ranges: Vec<Range<u64>>,
+    done: bool,
+}
+
+#[derive(Debug)]
+pub(crate) struct Journal {
+    // None if opened read-only.
+    out: Option<Mutex<File>>,
+    // State from a previous run; read-only once loaded.
+    previous: HashMap<PathBuf, Entry>,
+}
+
+impl Journal {
+    /// Open a journal. If `resume` is set any existing journal is
+    /// loaded and appended to, otherwise it is replaced.
+    pub fn open(path: &Path, resume: bool) -> Result<Journal> {
+        let previous = if resume && path.exists() {
+            info!("Loading journal {path:?}");
+            load(path)?
+        } else {
+            HashMap::new()
+        };
+
+        let mut out = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .truncate(false)
+            .open(path)?;
+        if previous.is_empty() {
+            out.set_len(0)?;
+            writeln!(out, "{HEADER}")?;
+        }
+
+        Ok(Journal {
+            out: Some(Mutex::new(out)),
+            previous,
+        })
+    }
+
+    /// Load the state of a previous run without modifying the
+    /// journal, e.g. to plan a copy. Nothing can be recorded.
+    pub fn read_only(path: &Path) -> Result<Journal> {
+        info!("Loading journal {path:?}");
+        Ok(Journal {
+            out: None,
+            previous: load(path)?,
+        })
+    }
+
+    /// Whether the operation on `target` was completed by a previous
+    /// run. For files, `meta` should be the source metadata, which
+    /// must be unchanged since the copy.
+    pub fn is_done(&self, target: &Path, meta: Option<&Metadata>) -> bool {
+        match self.previous.get(target) {
+            Some(entry) if entry.done => match meta {
+                Some(m) => entry.ident == Some(ident(m)) && target_matches(target, m),
+                None => target.symlink_metadata().is_ok(),
+            },
+            _ => false,
+        }
+    }
+
+    /// If a previous run partially copied the source to `target`,
+    /// and the source is unchanged, returns the byte ranges that
+    /// still need to be copied.
+    pub fn resume(&self, target: &Path, meta: &Metadata) -> Option<Vec<Range<u64>>> {
+        let entry = self.previous.get(target)?;
+        if entry.ident != Some(ident(meta)) || !target_matches(target, meta) {
+            debug!("Source or target changed, not resuming {target:?}");
+            return None;
+        }
+        Some(subtract_ranges(meta.len(), &entry.ranges))
+    }
+
+    /// Record the start of a fresh file copy.
+    pub fn start(&self, target: &Path, meta: &Metadata) -> Result<()> {
+        let (size, mtime, nsec) = ident(meta);
+        self.write(&format!("S {size} {mtime} {nsec} "), target)
+    }
+
+    /// Record that a byte-range of the target has been written.
+    pub fn commit(&self, target: &Path, range: Range<u64>) -> Result<()> {
+        self.write(&format!("R {} {} ", range.start, range.end), target)
+    }
+
+    /// Record a completed operation.
+    pub fn done(&self, target: &Path) -> Result<()> {
+        self.write("D ", target)
+    }
+
+    fn write(&self, prefix: &str, target: &Path) -> Result<()> {
+        let mut line = prefix.as_bytes().to_vec();
+        line.extend(escape(target));
+        line.push(b'\n');
+        let Some(out) = &self.out else {
+            return Err(XcpError::CopyError("Journal is read-only".to_string()).into());
+        };
+        // Single write to an O_APPEND file; lines are not interleaved.
+        out.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+}
+
+fn target_matches(target: &Path, meta: &Metadata) -> bool {
+    target.metadata()
+        .map(|t| t.len() == meta.len())
+        .unwrap_or(false)
+}
+
+fn load(path: &Path) -> Result<HashMap<PathBuf, Entry>> {
+    let invalid = || XcpError::InvalidArguments(format!("Invalid journal file: {path:?}"));
+
+    let mut lines = BufReader::new(File::open(path)?).split(b'\n');
+    match lines.next() {
+        Some(Ok(l)) if l == HEADER.as_bytes() => {}
+        _ => return Err(invalid().into()),
+    }
+
+    let mut entries: HashMap<PathBuf, Entry> = HashMap::new();
+    for line in lines {
+        let line = line?;
+        // The numeric fields following the tag, then the target.
+        let fields = |n: usize| {
+            let mut f = line.splitn(n + 2, |b| *b == b' ').skip(1);
+            let nums = (0..n)
+                .map(|_| f.next().and_then(|s| std::str::from_utf8(s).ok()?.parse::<i64>().ok()))
+                .collect::<Option<Vec<i64>>>();
+            let target = f.next().map(unescape);
+            nums.zip(target)
+        };
+        match line.first() {
+            Some(b'S') => {
+                let (n, target) = fields(3).ok_or_else(invalid)?;
+                // A restart replaces any previous state.
+                entries.insert(target, Entry {
+                    ident: Some((n[0] as u64, n[1], n[2])),
+                    ..Entry::default()
+                });
+            }
+            Some(b'R') => {
+                let (n, target) = fields(2).ok_or_else(invalid)?;
+                entries.entry(target).or_default()
+                    .ranges.push(n[0] as u64..n[1] as u64);
+            }
+            Some(b'D') => {
+                let (_, target) = fields(0).ok_or_else(invalid)?;
+                entries.entry(target).or_default().done = true;
+            }
+            // A partially written final line is expected if we were
+            // interrupted.
+            _ => debug!("Skipping invalid journal line"),
+        }
+    }
+    Ok(entries)
+}
+
+fn escape(path: &Path) -> Vec<u8> {
+    let mut out = Vec::new();
+    for b in path.as_os_str().as_bytes() {
+        match b {
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            _ => out.push(*b),
+        }
+    }
+    out
+}
+
+fn unescape(bytes: &[u8]) -> PathBuf {
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut it = bytes.iter();
+    while let Some(b) = it.next() {
+        match (b, it.as_slice().first()) {
+            (b'\\', Some(b'n')) => { out.push(b'\n'); it.next(); }
+            (b'\\', Some(b'\\')) => { out.push(b'\\'); it.next(); }
+            _ => out.push(*b),
+        }
+    }
+    PathBuf::from(OsString::from_vec(out))
+}
+
+/// Returns the parts of `0..len` not covered by `done`.
+fn subtract_ranges(len: u64, done: &[Range<u64>]) -> Vec<Range<u64>> {
+    let mut done = done.to_vec();
+    done.sort_by_key(|r| r.start);
+
+    let mut missing = Vec::new();
+    let mut pos = 0;
+    for r in done {
+        if r.start > pos {
+            missing.push(pos..r.start.min(len));
+        }
+        pos = pos.max(r.end);
+        if pos >= len {
+            break;
+        }
+    }
+    if pos < len {
+        missing.push(pos..len);
+    }
+    missing.retain(|r| !r.is_empty());
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_subtract_ranges() {
+        assert_eq!(vec![0..100], subtract_ranges(100, &[]));
+        assert_eq!(Vec::<Range<u64>>::new(), subtract_ranges(100, &[0..100]));
+        assert_eq!(vec![10..20, 50..100], subtract_ranges(100, &[20..50, 0..10]));
+        assert_eq!(vec![40..50], subtract_ranges(100, &[0..30, 20..40, 50..120]));
+    }
+
+    #[test]
+    fn test_escaping() {
+        let path = Path::new("a b\nc\\d");
+        assert_eq!(b"a b\\nc\\\\d".to_vec(), escape(path));
+        assert_eq!(path, unescape(&escape(path)));
+    }
+
+    #[test]
+    fn test_journal_resume() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let jpath = tdir.path().join("journal");
+        let source = tdir.path().join("source");
+        let done = tdir.path().join("done");
+        let partial = tdir.path().join("part ial\nfile");
+        write(&source, vec![0; 100])?;
+        write(&done, vec![0; 100])?;
+        write(&partial, vec![0; 100])?;
+        let meta = source.metadata()?;
+
+        {
+            let journal = Journal::open(&jpath, false)?;
+            journal.start(&done, &meta)?;
+            journal.commit(&done, 0..100)?;
+            journal.done(&done)?;
+            journal.start(&partial, &meta)?;
+            journal.commit(&partial, 0..40)?;
+            journal.commit(&partial, 60..80)?;
+        }
+
+        let journal = Journal::open(&jpath, true)?;
+        assert!(journal.is_done(&done, Some(&meta)));
+        assert!(!journal.is_done(&partial, Some(&meta)));
+        assert_eq!(Some(vec![40..60, 80..100]), journal.resume(&partial, &meta));
+        assert_eq!(None, journal.resume(&source, &meta));
+
+        // Source changed
+        write(&source, vec![0; 50])?;
+        let meta = source.metadata()?;
+        assert!(!journal.is_done(&done, Some(&meta)));
+        assert_eq!(None, journal.resume(&partial, &meta));
+
+        // Loading read-only leaves the journal untouched.
+        let before = std::fs::read(&jpath)?;
+        let journal = Journal::read_only(&jpath)?;
+        assert_eq!(2, journal.previous.len());
+        assert!(journal.done(&done).is_err());
+        assert_eq!(before, std::fs::read(&jpath)?);
+
+        // Not resuming clears the journal
+        let journal = Journal::open(&jpath, false)?;
+        assert!(journal.previous.is_empty());
+        assert_eq!(format!("{HEADER}\n"), std::fs::read_to_string(&jpath)?);
+
+        Ok(())
+    }
+}
@@ -60,9 +60,32 @@
 //!             StatusUpdate::Size(v) => {
 //!                 println!("Size update: {}", v);
 //!             },
+//!             StatusUpdate::Skipped { path, size } => {
+//!                 println!("Skipped {:?} ({} bytes)", path, size);
+//!             },
+//!             StatusUpdate::Deleted(p) => {
+//!                 println!("Deleted {:?}", p);
+//!             },
+//!             StatusUpdate::FileStarted { from, to, .. } => {
+//!                 println!("Copying {:?} to {:?}", from, to);
+//!             },
+//!             StatusUpdate::FileFinished { path, method } => {
+//!                 println!("Finished {:?} ({:?})", path, method);
+//!             },
+//!             StatusUpdate::SymlinkCreated { to, .. } => {
+//!                 println!("Created symlink {:?}", to);
+//!             },
+//!             StatusUpdate::DirCreated { path } => {
+//!                 println!("Created directory {:?}", path);
+//!             },
+//!             StatusUpdate::BackedUp { from, to } => {
+//!                 println!("Backed up {:?} to {:?}", from, to);
+//!             },
 //!             StatusUpdate::Error(e) => {
 //!                 panic!("Error during copy: {}", e);
 //!             }
+//!             // StatusUpdate is non-exhaustive.
+//!             _ => {}
 //!         }
 //!     }
 //!
@@ -76,15 +99,28 @@
 //!
 //! [xcp]: https://crates.io/crates/xcp/
 
+#[cfg(feature = "async")]
+pub mod async_copy;
 pub mod config;
+pub mod control;
 pub mod drivers;
 pub mod errors;
 pub mod feedback;
+pub mod moves;
+pub mod paths;
+pub mod plan;
 
 // Internal
+mod atomic;
 mod backup;
+mod checksum;
+mod gitindex;
+mod journal;
+mod links;
+mod manifest;
+mod mirror;
 mod operations;
-mod paths;
+mod throttle;
 
 #[cfg(test)]
 #[allow(unused)]
@@ -126,6 +162,27 @@ mod tests {
                 StatusUpdate::Size(v) => {
                     println!("Size update: {v}");
                 },
+                StatusUpdate::Skipped { path, size } => {
+                    println!("Skipped {path:?} ({size} bytes)");
+                },
+                StatusUpdate::Deleted(p) => {
+                    println!("Deleted {p:?}");
+                },
+                StatusUpdate::FileStarted { from, to, .. } => {
+                    println!("Copying {from:?} to {to:?}");
+                },
+                StatusUpdate::FileFinished { path, method } => {
+                    println!("Finished {path:?} ({method:?})");
+                },
+                StatusUpdate::SymlinkCreated { to, .. } => {
+                    println!("Created symlink {to:?}");
+                },
+                StatusUpdate::DirCreated { path } => {
+                    println!("Created directory {path:?}");
+                },
+                StatusUpdate::BackedUp { from, to } => {
+                    println!("Backed up {from:?} to {to:?}");
+                },
                 StatusUpdate::Error(e) => {
                     println!("Error during copy: {e}");
                     return Err(e.into());
@@ -140,4 +197,87 @@ mod tests {
 
         Ok(())
     }
+
+    fn collect_events(driver: Drivers) -> Result<Vec<StatusUpdate>> {
+        let source = TempDir::new()?;
+        let dest = TempDir::new()?;
+        std::fs::create_dir(source.path().join("sub"))?;
+        std::fs::write(source.path().join("sub/file.txt"), "12345")?;
+        std::os::unix::fs::symlink("sub/file.txt", source.path().join("link"))?;
+
+        let config = Arc::new(Config::default());
+        let updater = ChannelUpdater::new(&config);
+        let stat_rx = updater.rx_channel();
+        let driver = load_driver(driver, &config)?;
+        driver.copy(vec![source.path().to_path_buf()], dest.path(), Arc::new(updater))?;
+
+        Ok(stat_rx.try_iter().collect())
+    }
+
+    fn check_events(driver: Drivers) -> Result<()> {
+        let events = collect_events(driver)?;
+
+        let started = events.iter().position(|e| matches!(e, StatusUpdate::FileStarted { size: 5, .. }));
+        let finished = events.iter().position(|e| matches!(e, StatusUpdate::FileFinished { .. }));
+        assert!(started.unwrap() < finished.unwrap());
+        assert_eq!(2, events.iter().filter(|e| matches!(e, StatusUpdate::DirCreated { .. })).count());
+        assert!(events.iter().any(|e| matches!(e, StatusUpdate::SymlinkCreated { .. })));
+
+        let copied: u64 = events.iter()
+            .map(|e| if let StatusUpdate::Copied(n) = e { *n } else { 0 })
+            .sum();
+        assert_eq!(5, copied);
+        Ok(())
+    }
+
+    #[test]
+    fn lifecycle_events_test() -> Result<()> {
+        check_events(Drivers::ParFile)?;
+        #[cfg(feature = "parblock")]
+        check_events(Drivers::ParBlock)?;
+        Ok(())
+    }
+
+    // Cancels the copy as soon as a file is started.
+    struct CancelOnStart(crate::control::CopyController);
+
+    impl StatusUpdater for CancelOnStart {
+        fn send(&self, update: StatusUpdate) -> Result<()> {
+            if let StatusUpdate::FileStarted { .. } = update {
+                self.0.cancel();
+            }
+            Ok(())
+        }
+    }
+
+    fn check_cancel(driver: Drivers) -> Result<()> {
+        let source = TempDir::new()?;
+        let dest = TempDir::new()?;
+        let from = source.path().join("file.bin");
+        std::fs::write(&from, vec![1u8; 64 * 1024])?;
+        let to = dest.path().join("file.bin");
+
+        // Reflinks complete immediately, so can't be interrupted.
+        let config = Arc::new(Config {
+            block_size: 4096,
+            reflink: crate::config::Reflink::Never,
+            ..Config::default()
+        });
+        let driver = load_driver(driver, &config)?;
+        let stats = Arc::new(CancelOnStart(driver.controller()));
+        let result = driver.copy(vec![from], &to, stats);
+
+        assert!(matches!(result.unwrap_err().downcast_ref(), Some(XcpError::Cancelled)));
+        // The partial copy is removed.
+        assert!(!to.exists());
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_test() -> Result<()> {
+        check_cancel(Drivers::ParFile)?;
+        #[cfg(feature = "parblock")]
+        check_cancel(Drivers::ParBlock)?;
+        Ok(())
+    }
 }
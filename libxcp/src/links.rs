@@ -0,0 +1,157 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Tracking of hard-linked files within a copied tree.
+
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Condvar, Mutex};
+
+use log::debug;
+
+use crate::errors::{Result, XcpError};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LinkState {
+    Pending,
+    Done,
+    Failed,
+}
+
+/// Tracks source files with multiple links so that subsequent
+/// occurrences can be recreated as hard-links to the first copy. As
+/// the first copy may still be in-flight in a worker when later links
+/// are processed, workers can wait on its completion.
+#[derive(Debug, Default)]
+pub(crate) struct LinkTracker {
+    // Source (dev, inode) to the target of the first copy.
+    seen: Mutex<HashMap<(u64, u64), PathBuf>>,
+    // Copy state of first-copy targets.
+    state: Mutex<HashMap<PathBuf, LinkState>>,
+    changed: Condvar,
+}
+
+impl LinkTracker {
+    /// Record a source file about to be copied to `target`. If the
+    /// source inode has been seen before the target of the first
+    /// copy is returned, otherwise the target is registered as
+    /// pending and `None` is returned.
+    pub fn check(&self, meta: &Metadata, target: &Path) -> Option<PathBuf> {
+        let key = (meta.dev(), meta.ino());
+        let mut seen = self.seen.lock().unwrap();
+        if let Some(first) = seen.get(&key) {
+            return Some(first.clone());
+        }
+        debug!("Tracking multiply-linked file {target:?}");
+        seen.insert(key, target.to_path_buf());
+        self.state.lock().unwrap().insert(target.to_path_buf(), LinkState::Pending);
+        None
+    }
+
+    /// Register `target` as the pending first copy of a planned
+    /// hard-link, when executing an existing plan.
+    pub fn expect(&self, target: &Path) {
+        self.state.lock().unwrap().entry(target.to_path_buf()).or_insert(LinkState::Pending);
+    }
+
+    /// Mark the copy to `target` as complete. Only the first result
+    /// is recorded, so a failure reported by a worker is not
+    /// overridden when the handle is later finalised. This is a no-op
+    /// if the target is not being tracked.
+    pub fn complete(&self, target: &Path, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(s) = state.get_mut(target)
+            && *s == LinkState::Pending
+        {
+            *s = if ok { LinkState::Done } else { LinkState::Failed };
+            self.changed.notify_all();
+        }
+    }
+
+    /// Block until the copy to `target` has completed.
+    pub fn wait(&self, target: &Path) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match state.get(target).copied() {
+                Some(LinkState::Pending) => state = self.changed.wait(state).unwrap(),
+                Some(LinkState::Done) | None => return Ok(()),
+                Some(LinkState::Failed) => {
+                    return Err(XcpError::CopyError(format!("Hard-link target was not copied: {target:?}")).into())
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{hard_link, File};
+    use std::sync::Arc;
+    use std::thread;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_link_tracking() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let a = tdir.path().join("a");
+        let b = tdir.path().join("b");
+        File::create(&a)?;
+        hard_link(&a, &b)?;
+
+        let links = LinkTracker::default();
+        assert_eq!(None, links.check(&a.metadata()?, Path::new("/dest/a")));
+        assert_eq!(Some(PathBuf::from("/dest/a")), links.check(&b.metadata()?, Path::new("/dest/b")));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_link_wait() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let a = tdir.path().join("a");
+        File::create(&a)?;
+
+        let links = Arc::new(LinkTracker::default());
+        let target = PathBuf::from("/dest/a");
+        links.check(&a.metadata()?, &target);
+
+        let waiter = {
+            let l = links.clone();
+            let t = target.clone();
+            thread::spawn(move || l.wait(&t))
+        };
+        links.complete(&target, true);
+        assert!(waiter.join().unwrap().is_ok());
+
+        // The first result wins.
+        links.complete(&target, false);
+        assert!(links.wait(&target).is_ok());
+
+        let failed = PathBuf::from("/dest/b");
+        links.check(&tdir.path().metadata()?, &failed);
+        links.complete(&failed, false);
+        links.complete(&failed, true);
+        assert!(links.wait(&failed).is_err());
+
+        // Untracked files never block.
+        assert!(links.wait(Path::new("/dest/other")).is_ok());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Checksum manifests of copied files.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use crate::checksum::FileHasher;
+use crate::config::HashType;
+use crate::errors::Result;
+use crate::throttle::Throttle;
+
+/// A manifest file in the format output by `sha256sum`, `b3sum` and
+/// `xxhsum`. Entries are written in the order files complete, which
+/// is not deterministic.
+///
+/// Paths are recorded relative to the copy destination if it is a
+/// directory, otherwise to its parent, so the manifest can be checked
+/// from there.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    hash: HashType,
+    dest: PathBuf,
+    // Resolved on first use, once the destination directory exists.
+    root: OnceLock<PathBuf>,
+    out: Mutex<BufWriter<File>>,
+}
+
+impl Manifest {
+    pub fn create(path: &Path, hash: HashType, dest: &Path) -> Result<Manifest> {
+        Ok(Manifest {
+            hash,
+            dest: dest.to_path_buf(),
+            root: OnceLock::new(),
+            out: Mutex::new(BufWriter::new(File::create(path)?)),
+        })
+    }
+
+    pub fn hasher(&self) -> FileHasher {
+        FileHasher::new(self.hash)
+    }
+
+    /// Read back a file and calculate its digest.
+    pub fn digest_file(&self, path: &Path, throttle: &Throttle) -> Result<String> {
+        self.hasher().digest_file(&File::open(path)?, throttle)
+    }
+
+    pub fn record(&self, path: &Path, digest: &str) -> Result<()> {
+        let root = self.root.get_or_init(|| {
+            if self.dest.is_dir() {
+                self.dest.clone()
+            } else {
+                self.dest.parent().map(Path::to_path_buf).unwrap_or_default()
+            }
+        });
+        let path = path.strip_prefix(root).unwrap_or(path);
+        let line = format_line(self.hash, path, digest);
+        self.out.lock().unwrap().write_all(&line)?;
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.out.lock().unwrap().flush()?;
+        Ok(())
+    }
+}
+
+// Filenames containing backslashes or newlines are escaped, and the
+// line prefixed with a backslash, as with coreutils.
+fn format_line(hash: HashType, path: &Path, digest: &str) -> Vec<u8> {
+    let name = path.as_os_str().as_bytes();
+    let escape = name.iter().any(|b| *b == b'\\' || *b == b'\n');
+
+    let mut line = Vec::with_capacity(digest.len() + name.len() + 8);
+    if escape {
+        line.push(b'\\');
+    }
+    if hash == HashType::Xxh3 {
+        line.extend_from_slice(b"XXH3_");
+    }
+    line.extend_from_slice(digest.as_bytes());
+    line.extend_from_slice(b"  ");
+    for b in name {
+        match b {
+            b'\\' if escape => line.extend_from_slice(b"\\\\"),
+            b'\n' if escape => line.extend_from_slice(b"\\n"),
+            _ => line.push(*b),
+        }
+    }
+    line.push(b'\n');
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_line() {
+        assert_eq!(b"abcd  dir/file.txt\n".to_vec(),
+                   format_line(HashType::Sha256, Path::new("dir/file.txt"), "abcd"));
+        assert_eq!(b"XXH3_abcd  file.txt\n".to_vec(),
+                   format_line(HashType::Xxh3, Path::new("file.txt"), "abcd"));
+        assert_eq!(b"\\abcd  a\\nb\\\\c\n".to_vec(),
+                   format_line(HashType::Blake3, Path::new("a\nb\\c"), "abcd"));
+    }
+}
@@ -0,0 +1,269 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Mirroring support; removal of target files that are not present
+//! in the source.
+
+use std::fs::{create_dir_all, remove_dir, remove_dir_all, remove_file, rename};
+use std::path::{absolute, Path, PathBuf};
+
+use log::{debug, info};
+use walkdir::WalkDir;
+
+use crate::backup::get_backup_path;
+use crate::errors::Result;
+use crate::feedback::StatusUpdate;
+use crate::operations::Context;
+use crate::paths::{SourceIgnores, SourceSelection};
+
+/// Remove entries under `target` that have no counterpart in
+/// `source`. Entries excluded by the source filters are left in
+/// place, as is `Config::backup_dir` if it is within the target. If
+/// an extraneous directory may hold such entries it is emptied rather
+/// than removed whole, and removed only if nothing in it was kept.
+pub(crate) fn delete_extraneous(source: &Path, target: &Path, ctx: &Context) -> Result<()> {
+    debug!("Checking for extraneous files in {target:?}");
+    if !target.exists() {
+        // Nothing was copied; e.g. everything was filtered out.
+        return Ok(());
+    }
+    let mut ignores = SourceIgnores::new(&ctx.config);
+    let selection = SourceSelection::new(&ctx.config)?;
+    let backup_dir = ctx.config.backup_dir.as_deref()
+        .map(absolute)
+        .transpose()?;
+    // Extraneous directories being emptied, and whether anything in
+    // them was kept.
+    let mut emptied: Vec<(PathBuf, bool)> = Vec::new();
+
+    let mut it = WalkDir::new(target).min_depth(1).into_iter();
+    while let Some(entry) = it.next() {
+        let entry = entry?;
+        let rel = entry.path().strip_prefix(target)?;
+        let spath = source.join(rel);
+        let is_dir = entry.file_type().is_dir();
+
+        let path = absolute(entry.path())?;
+        let holds_backup = backup_dir.as_ref().is_some_and(|b| b.starts_with(&path));
+
+        if backup_dir.as_ref() == Some(&path)
+            || ignores.is_ignored(&spath, is_dir)
+            || ctx.config.filter.is_excluded(rel, is_dir)
+            || !selection.selects(rel, entry.depth(), &entry.metadata()?)
+        {
+            // Nothing under an excluded directory is removed.
+            debug!("Excluded, not deleting {:?}", entry.path());
+            for (dir, kept) in emptied.iter_mut() {
+                *kept |= entry.path().starts_with(dir);
+            }
+            if is_dir {
+                it.skip_current_dir();
+            }
+            continue;
+        }
+        if spath.symlink_metadata().is_ok() {
+            continue;
+        }
+        if is_dir && (holds_backup || selection.is_restricted()) {
+            // The backup directory may not exist until entries are
+            // moved into it, so its parents are always kept.
+            emptied.push((entry.path().to_path_buf(), holds_backup));
+            continue;
+        }
+        remove_entry(entry.path(), rel, is_dir, ctx)?;
+        if is_dir {
+            it.skip_current_dir();
+        }
+    }
+
+    // Innermost first, after their contents.
+    for (dir, kept) in emptied.into_iter().rev() {
+        if kept {
+            continue;
+        }
+        ctx.updates.send(StatusUpdate::Deleted(dir.clone()))?;
+        if !ctx.config.delete_dry_run {
+            info!("Deleting emptied directory {dir:?}");
+            remove_dir(dir)?;
+        }
+    }
+    Ok(())
+}
+
+fn remove_entry(path: &Path, rel: &Path, is_dir: bool, ctx: &Context) -> Result<()> {
+    let config = &ctx.config;
+    ctx.updates.send(StatusUpdate::Deleted(path.to_path_buf()))?;
+    if config.delete_dry_run {
+        return Ok(());
+    }
+
+    if let Some(backup_dir) = &config.backup_dir {
+        let mut to = backup_dir.join(rel);
+        if let Some(parent) = to.parent() {
+            create_dir_all(parent)?;
+        }
+        if to.symlink_metadata().is_ok() {
+            to = get_backup_path(&to)?;
+        }
+        info!("Moving deleted {path:?} to {to:?}");
+        rename(path, to)?;
+    } else if is_dir {
+        info!("Deleting directory {path:?}");
+        remove_dir_all(path)?;
+    } else {
+        info!("Deleting {path:?}");
+        remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use std::sync::Arc;
+    use tempfile::TempDir;
+
+    use crate::config::Config;
+    use crate::control::CopyController;
+    use crate::feedback::ChannelUpdater;
+
+    fn run_delete(config: Config, source: &Path, target: &Path) -> Result<Vec<StatusUpdate>> {
+        let config = Arc::new(config);
+        let updater = ChannelUpdater::new(&config);
+        let stat_rx = updater.rx_channel();
+        let ctx = Context::new(config, target, Arc::new(updater), CopyController::default())?;
+        delete_extraneous(source, target, &ctx)?;
+        Ok(stat_rx.try_iter().collect())
+    }
+
+    fn create_trees(tdir: &TempDir) -> Result<()> {
+        let source = tdir.path().join("source");
+        let target = tdir.path().join("target");
+        create_dir_all(source.join("sub"))?;
+        create_dir_all(target.join("sub"))?;
+        create_dir_all(target.join("extra/deep"))?;
+        write(source.join("file.txt"), "source")?;
+        write(target.join("file.txt"), "target")?;
+        write(target.join("sub/extra.txt"), "extra")?;
+        write(target.join("extra/deep/file.txt"), "extra")?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_extraneous() -> Result<()> {
+        let tdir = TempDir::new()?;
+        create_trees(&tdir)?;
+        let target = tdir.path().join("target");
+
+        let updates = run_delete(Config { delete: true, ..Config::default() },
+                                 &tdir.path().join("source"), &target)?;
+        assert_eq!(2, updates.len());
+        assert!(target.join("file.txt").exists());
+        assert!(target.join("sub").exists());
+        assert!(!target.join("sub/extra.txt").exists());
+        assert!(!target.join("extra").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_dry_run() -> Result<()> {
+        let tdir = TempDir::new()?;
+        create_trees(&tdir)?;
+        let target = tdir.path().join("target");
+
+        let config = Config { delete: true, delete_dry_run: true, ..Config::default() };
+        let updates = run_delete(config, &tdir.path().join("source"), &target)?;
+        assert!(matches!(&updates[..], [StatusUpdate::Deleted(_), StatusUpdate::Deleted(_)]));
+        assert!(target.join("sub/extra.txt").exists());
+        assert!(target.join("extra/deep/file.txt").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_to_backup_dir() -> Result<()> {
+        let tdir = TempDir::new()?;
+        create_trees(&tdir)?;
+        let target = tdir.path().join("target");
+        let backup = tdir.path().join("backup");
+        create_dir_all(backup.join("sub"))?;
+        write(backup.join("sub/extra.txt"), "previous")?;
+
+        let config = Config { delete: true, backup_dir: Some(backup.clone()), ..Config::default() };
+        run_delete(config, &tdir.path().join("source"), &target)?;
+        assert!(!target.join("sub/extra.txt").exists());
+        assert!(backup.join("extra/deep/file.txt").exists());
+        assert!(backup.join("sub/extra.txt").exists());
+        assert!(backup.join("sub/extra.txt.~1~").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_keeps_unselected() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        let target = tdir.path().join("target");
+        create_dir_all(&source)?;
+        create_dir_all(target.join("a/b"))?;
+        write(target.join("notes.txt"), "text")?;
+        write(target.join("big.rs"), "fn main() {}\n".repeat(100))?;
+        write(target.join("small.rs"), "")?;
+        write(target.join("a/b/deep.rs"), "fn main() {}")?;
+
+        let config = Config {
+            delete: true,
+            file_types: vec!["rust".to_string()],
+            max_size: Some(100),
+            max_depth: Some(2),
+            ..Config::default()
+        };
+        run_delete(config, &source, &target)?;
+        assert!(target.join("notes.txt").exists());
+        assert!(target.join("big.rs").exists());
+        assert!(target.join("a/b/deep.rs").exists());
+        assert!(!target.join("small.rs").exists());
+
+        let config = Config { delete: true, min_depth: Some(2), ..Config::default() };
+        write(target.join("small.rs"), "")?;
+        write(target.join("a/shallow.rs"), "")?;
+        run_delete(config, &source, &target)?;
+        assert!(target.join("small.rs").exists());
+        assert!(!target.join("a/shallow.rs").exists());
+        assert!(!target.join("a/b").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_delete_keeps_backup_dir_in_target() -> Result<()> {
+        let tdir = TempDir::new()?;
+        create_trees(&tdir)?;
+        let target = tdir.path().join("target");
+        let backup = target.join("extra/backup");
+
+        let config = Config { delete: true, backup_dir: Some(backup.clone()), ..Config::default() };
+        run_delete(config, &tdir.path().join("source"), &target)?;
+        assert!(!target.join("sub/extra.txt").exists());
+        assert!(!target.join("extra/deep").exists());
+        assert!(backup.join("sub/extra.txt").exists());
+        assert!(backup.join("extra/deep/file.txt").exists());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,315 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Moving of files and directories.
+//!
+//! Sources on the same filesystem as the destination are renamed. Any
+//! others are copied with a driver, and each source file is then
+//! checked against its copy before it is removed. Directories are
+//! always copied if any filters are set, so that excluded entries are
+//! left in the source. If any errors are
+//! reported during the copy no sources are removed.
+
+use std::fs::{self, read_link, remove_dir, remove_file, File};
+use std::io::ErrorKind;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use log::{debug, error, info, warn};
+
+use crate::backup::{get_backup_path, needs_backup};
+use crate::checksum::hash_file;
+use crate::config::{Config, Verify};
+use crate::drivers::CopyDriver;
+use crate::errors::{Result, XcpError};
+use crate::feedback::{StatusUpdate, StatusUpdater};
+use crate::paths::is_filtered;
+use crate::plan::{CopyPlan, Operation, PlannedOperation};
+use crate::throttle::Throttle;
+
+// Passes updates through, noting any errors.
+struct ErrorTracker {
+    inner: Arc<dyn StatusUpdater>,
+    failed: AtomicBool,
+}
+
+impl StatusUpdater for ErrorTracker {
+    fn send(&self, update: StatusUpdate) -> Result<()> {
+        if let StatusUpdate::Error(_) = update {
+            self.failed.store(true, Ordering::Relaxed);
+        }
+        self.inner.send(update)
+    }
+}
+
+/// Move a set of files or directories to a destination. `dest` can be
+/// a file if a single file is provided as the source. Sources that
+/// can't be renamed into place are copied using `driver`, which
+/// should have been created with the same `config`; updates are sent
+/// to `stats` as with [CopyDriver::copy()].
+pub fn move_files(
+    driver: &dyn CopyDriver,
+    sources: Vec<PathBuf>,
+    dest: &Path,
+    config: &Config,
+    stats: Arc<dyn StatusUpdater>,
+) -> Result<()> {
+    let mut to_copy = Vec::new();
+    for source in sources {
+        let target = target_path(&source, dest, config)?;
+        // Renaming a directory would also move any excluded entries.
+        if (is_filtered(config) && source.is_dir()) || !try_rename(&source, &target, config, &stats)? {
+            to_copy.push(source);
+        }
+    }
+    if to_copy.is_empty() {
+        return Ok(());
+    }
+
+    // Plan first, as the targets will exist after the copy; the same
+    // plan is then used to remove the sources.
+    let plan = CopyPlan::new(to_copy, dest, config)?;
+    let tracker = Arc::new(ErrorTracker {
+        inner: stats.clone(),
+        failed: AtomicBool::new(false),
+    });
+    driver.copy_plan(&plan, dest, tracker.clone())?;
+    if tracker.failed.load(Ordering::Relaxed) {
+        return Err(XcpError::CopyError("Errors during copy, not removing sources".to_string()).into());
+    }
+
+    remove_sources(&plan, config, &stats)
+}
+
+fn target_path(source: &Path, dest: &Path, config: &Config) -> Result<PathBuf> {
+    let name = source.file_name()
+        .ok_or(XcpError::InvalidSource("Failed to find source file name."))?;
+    let target = if dest.is_dir() && !config.no_target_directory {
+        dest.join(name)
+    } else {
+        dest.to_path_buf()
+    };
+    Ok(target)
+}
+
+/// Attempt to rename the source into place. Returns `false` if the
+/// target is on another filesystem.
+fn try_rename(source: &Path, target: &Path, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<bool> {
+    let mut backup = None;
+    if target.symlink_metadata().is_ok() {
+        if config.no_clobber {
+            return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", target.to_path_buf()).into());
+        }
+        if needs_backup(target, config)? {
+            let path = get_backup_path(target)?;
+            info!("Backup: Rename {target:?} to {path:?}");
+            fs::rename(target, &path)?;
+            backup = Some(path);
+        }
+    }
+    let result = fs::rename(source, target);
+    // Put back the target; any copy will make its own backup.
+    if let (Err(_), Some(path)) = (&result, &backup) {
+        debug!("Rename failed, restoring {target:?} from {path:?}");
+        fs::rename(path, target)?;
+    }
+    match result {
+        Ok(()) => {
+            info!("Renamed {source:?} to {target:?}");
+            if let Some(to) = backup {
+                stats.send(StatusUpdate::BackedUp { from: target.to_path_buf(), to })?;
+            }
+            Ok(true)
+        }
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => {
+            debug!("{source:?} is on a different filesystem to {target:?}, copying");
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Check that the source has been copied intact.
+fn copied(planned: &PlannedOperation, config: &Config, throttle: &Throttle) -> Result<bool> {
+    let source = &planned.source;
+    let target = planned.operation.target();
+    let Ok(tmeta) = target.symlink_metadata() else {
+        return Ok(false);
+    };
+    let ok = match &planned.operation {
+        Operation::Copy(..) | Operation::HardLink(..) => {
+            let sfd = File::open(source)?;
+            tmeta.is_file()
+                && tmeta.len() == sfd.metadata()?.len()
+                // Skip the re-read if the copy was verified.
+                && (config.verify != Verify::None || hash_file(&sfd, throttle)? == hash_file(&File::open(target)?, throttle)?)
+        }
+        Operation::Link(..) => tmeta.is_symlink() && read_link(source)? == read_link(target)?,
+        Operation::Special(..) => {
+            let smeta = source.symlink_metadata()?;
+            tmeta.file_type() == smeta.file_type() && tmeta.rdev() == smeta.rdev()
+        }
+        Operation::CreateDir(..) => tmeta.is_dir(),
+    };
+    Ok(ok)
+}
+
+fn remove_sources(plan: &CopyPlan, config: &Config, stats: &Arc<dyn StatusUpdater>) -> Result<()> {
+    let throttle = Throttle::new(config);
+    let mut failed = false;
+    // Children before their directories.
+    for planned in plan.operations.iter().rev() {
+        if planned.skip.is_some() {
+            continue;
+        }
+        let source = &planned.source;
+        match copied(planned, config, &throttle) {
+            Ok(true) => {}
+            Ok(false) => {
+                error!("Copy of {source:?} does not match the source, not removing");
+                stats.send(StatusUpdate::Error(XcpError::VerifyFailed(planned.operation.target().to_path_buf())))?;
+                failed = true;
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to check copy of {source:?}: {e}");
+                stats.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+                failed = true;
+                continue;
+            }
+        }
+
+        debug!("Removing source {source:?}");
+        if let Operation::CreateDir(..) = planned.operation {
+            // Files excluded from the copy will remain.
+            if let Err(e) = remove_dir(source) {
+                warn!("Not removing source directory {source:?}: {e}");
+            }
+        } else {
+            remove_file(source)?;
+        }
+    }
+
+    if failed {
+        return Err(XcpError::CopyError("Some sources were not removed".to_string()).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, hard_link, read_to_string, write};
+    use std::os::unix::fs::symlink;
+    use std::os::unix::net::UnixListener;
+    use tempfile::TempDir;
+
+    use crate::config::Backup;
+    use crate::drivers::{load_driver, Drivers};
+    use crate::feedback::NoopUpdater;
+
+    #[test]
+    fn test_remove_sources() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        let dest = tdir.path().join("dest");
+        create_dir_all(source.join("sub"))?;
+        write(source.join("file.txt"), "data")?;
+        write(source.join("sub/other.txt"), "other")?;
+        symlink("file.txt", source.join("link.txt"))?;
+
+        let config = Arc::new(Config::default());
+        let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+        let driver = load_driver(Drivers::ParFile, &config)?;
+        driver.copy_plan(&plan, &dest, Arc::new(NoopUpdater))?;
+
+        // Corrupt one copy; it and its parents should be kept.
+        write(dest.join("sub/other.txt"), "0ther")?;
+
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        assert!(remove_sources(&plan, &config, &stats).is_err());
+        assert!(!source.join("file.txt").exists());
+        assert!(source.join("link.txt").symlink_metadata().is_err());
+        assert_eq!("other", read_to_string(source.join("sub/other.txt"))?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_copy_plan_links() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        let dest = tdir.path().join("dest");
+        create_dir_all(&source)?;
+        write(source.join("a.txt"), "data")?;
+        hard_link(source.join("a.txt"), source.join("b.txt"))?;
+
+        let mut config = Config::default();
+        config.preserve.links = true;
+        let config = Arc::new(config);
+        let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+        let driver = load_driver(Drivers::ParFile, &config)?;
+        driver.copy_plan(&plan, &dest, Arc::new(NoopUpdater))?;
+
+        assert_eq!(dest.join("a.txt").metadata()?.ino(), dest.join("b.txt").metadata()?.ino());
+        Ok(())
+    }
+
+    #[test]
+    fn test_rename_failure_keeps_target() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("missing.txt");
+        let target = tdir.path().join("target.txt");
+        write(&target, "target")?;
+
+        let config = Config { backup: Backup::Numbered, ..Config::default() };
+        let stats: Arc<dyn StatusUpdater> = Arc::new(NoopUpdater);
+        assert!(try_rename(&source, &target, &config, &stats).is_err());
+        assert_eq!("target", read_to_string(&target)?);
+        assert!(!tdir.path().join("target.txt.~1~").exists());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_checked() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source.sock");
+        let target = tdir.path().join("target.sock");
+        let _listener = UnixListener::bind(&source)?;
+        write(&target, "")?;
+
+        let planned = PlannedOperation {
+            operation: Operation::Special(source.clone(), target.clone()),
+            source,
+            size: 0,
+            conflict: None,
+            skip: None,
+            backup: None,
+        };
+        let config = Config::default();
+        let throttle = Throttle::new(&config);
+        assert!(!copied(&planned, &config, &throttle)?);
+
+        remove_file(&target)?;
+        let _listener = UnixListener::bind(&target)?;
+        assert!(copied(&planned, &config, &throttle)?);
+
+        Ok(())
+    }
+}
@@ -14,79 +14,365 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::os::unix::fs::{chown, MetadataExt};
+use std::os::unix::fs::{symlink, FileExt};
+use std::cell::Cell;
 use std::{cmp, thread};
-use std::fs::{self, canonicalize, create_dir_all, read_link, File, Metadata};
+use std::fs::{self, create_dir_all, hard_link, read_link, remove_file, File, Metadata, OpenOptions};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use crossbeam_channel as cbc;
 use libfs::{
-    allocate_file, copy_file_bytes, copy_owner, copy_permissions, copy_timestamps, next_sparse_segments, probably_sparse, reflink, sync, FileType
+    allocate_blocks, allocate_file, write_sparse, copy_acl, copy_acl_path, copy_file_bytes, copy_file_bytes_sparse,
+    copy_file_offset, copy_file_offset_sparse, copy_flags, copy_node, copy_owner,
+    copy_owner_link, copy_owner_path, copy_permissions, copy_permissions_path, copy_timestamps, copy_timestamps_link,
+    copy_timestamps_path, copy_xattrs, copy_xattrs_path, drop_cache, next_sparse_segments, probably_sparse, reflink, set_io_priority,
+    set_thread_nice, sync
 };
 use log::{debug, error, info, warn};
-use walkdir::WalkDir;
 
+use crate::atomic::{create_staged, discard, install, Staged};
 use crate::backup::{get_backup_path, needs_backup};
-use crate::config::{Config, Reflink};
+use crate::checksum::{hash_file, FileHasher, BUF_SIZE};
+use crate::config::{Config, Reflink, Sparse, Verify};
+use crate::control::CopyController;
 use crate::errors::{Result, XcpError};
-use crate::feedback::{StatusUpdate, StatusUpdater};
-use crate::paths::{parse_ignore, ignore_filter};
+use crate::feedback::{CopyMethod, StatusUpdate, StatusUpdater};
+use crate::journal::Journal;
+use crate::links::LinkTracker;
+use crate::manifest::Manifest;
+use crate::mirror::delete_extraneous;
+use crate::paths::walk_error_path;
+use crate::plan::{Conflict, CopyPlan, PlannedOperation, Planner};
+use crate::throttle::Throttle;
+
+thread_local! {
+    // Whether the configured priorities have been applied to this
+    // thread.
+    static PRIORITISED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Runtime state for a single copy run, shared between the
+/// tree-walker and the copy workers.
+pub struct Context {
+    pub config: Arc<Config>,
+    pub updates: Arc<dyn StatusUpdater>,
+    pub control: CopyController,
+    pub(crate) links: LinkTracker,
+    pub(crate) manifest: Option<Manifest>,
+    pub(crate) journal: Option<Journal>,
+    pub(crate) throttle: Throttle,
+    // Directories created by the walker, in pre-order.
+    dirs: Mutex<Vec<(PathBuf, PathBuf)>>,
+    // Source and target directories to be mirrored.
+    mirrors: Mutex<Vec<(PathBuf, PathBuf)>>,
+}
+
+impl Context {
+    pub fn new(config: Arc<Config>, dest: &Path, updates: Arc<dyn StatusUpdater>, control: CopyController) -> Result<Context> {
+        let manifest = match &config.manifest {
+            Some(path) => Some(Manifest::create(path, config.hash, dest)?),
+            None => None,
+        };
+        let journal = match &config.journal {
+            Some(path) => Some(Journal::open(path, config.resume)?),
+            None => None,
+        };
+        let throttle = Throttle::new(&config);
+        Ok(Context {
+            config,
+            updates,
+            control,
+            links: LinkTracker::default(),
+            manifest,
+            journal,
+            throttle,
+            dirs: Mutex::new(Vec::new()),
+            mirrors: Mutex::new(Vec::new()),
+        })
+    }
+
+    /// Complete any outstanding work once all copy operations have
+    /// finished.
+    pub fn finish(&self) -> Result<()> {
+        // Deletion modifies the directories, so must happen first.
+        let mirrors = std::mem::take(&mut *self.mirrors.lock().unwrap());
+        for (source, target) in mirrors {
+            if let Err(e) = delete_extraneous(&source, &target, self) {
+                self.report_error(XcpError::CopyError(format!("Failed to delete extraneous files in {target:?}: {e}")));
+                return Err(e);
+            }
+        }
+        finalise_dirs(self)?;
+        if let Some(manifest) = &self.manifest {
+            manifest.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Apply any configured IO and CPU priorities to the current
+    /// thread. This should be called by every worker thread; it only
+    /// takes effect once per thread.
+    pub(crate) fn prioritise_thread(&self) {
+        if PRIORITISED.replace(true) {
+            return;
+        }
+        if let Some(p) = self.config.ionice {
+            match set_io_priority(p.class, p.level) {
+                Ok(true) => debug!("Set IO priority of {:?} to {p:?}", thread::current().id()),
+                Ok(false) => warn!("IO priorities are not supported on this platform"),
+                Err(e) => warn!("Failed to set IO priority: {e}"),
+            }
+        }
+        if let Some(nice) = self.config.nice {
+            match set_thread_nice(nice) {
+                Ok(true) => debug!("Set niceness of {:?} to {nice}", thread::current().id()),
+                Ok(false) => warn!("Thread niceness is not supported on this platform"),
+                Err(e) => warn!("Failed to set niceness: {e}"),
+            }
+        }
+    }
+
+    /// The size of each chunk of file data copied; the block size,
+    /// reduced if needed to apply any bandwidth limit smoothly.
+    pub(crate) fn block_size(&self) -> u64 {
+        self.throttle.chunk_size(self.config.block_size)
+    }
+
+    /// Report a failed operation on `path`. Returns the error unless
+    /// `keep_going` is set, in which case the copy should continue.
+    /// Cancellation is always returned, and not reported.
+    pub(crate) fn report_failure(&self, path: &Path, err: anyhow::Error) -> Result<()> {
+        if let Some(XcpError::Cancelled) = err.downcast_ref() {
+            return Err(err);
+        }
+        error!("Error copying {path:?}: {err}");
+        self.updates.send(StatusUpdate::Error(XcpError::failed(path, &err)))?;
+        if self.config.keep_going {
+            Ok(())
+        } else {
+            Err(err)
+        }
+    }
+
+    fn report_error(&self, err: XcpError) {
+        error!("{err}");
+        if let Err(e) = self.updates.send(StatusUpdate::Error(err)) {
+            error!("Failed to send status update message: {e}");
+        }
+    }
+
+    /// Add a file to the manifest, if enabled, reading it back to
+    /// calculate the digest if not supplied.
+    fn record_manifest(&self, target: &Path, digest: Option<String>) -> bool {
+        let Some(manifest) = &self.manifest else {
+            return true;
+        };
+        let result = match digest {
+            Some(d) => Ok(d),
+            None => manifest.digest_file(target, &self.throttle),
+        }.and_then(|d| manifest.record(target, &d));
+        if let Err(e) = result {
+            self.report_error(XcpError::CopyError(format!("Failed to add {target:?} to manifest: {e}")));
+            return false;
+        }
+        true
+    }
+
+    /// Record a completed operation in the journal, if enabled.
+    pub(crate) fn record_done(&self, target: &Path) -> bool {
+        let Some(journal) = &self.journal else {
+            return true;
+        };
+        if let Err(e) = journal.done(target) {
+            self.report_error(XcpError::CopyError(format!("Failed to journal {target:?}: {e}")));
+            return false;
+        }
+        true
+    }
+}
+
+// Whether data copies are performed in the kernel (i.e. with
+// copy_file_range()); otherwise libfs reads the data into userspace.
+const KERNEL_COPY: bool = cfg!(all(target_os = "linux", feature = "use_linux"));
+
+// The method used for plain (non-sparse) data copies.
+const fn data_method(sparse: Sparse) -> CopyMethod {
+    if matches!(sparse, Sparse::Always) {
+        // Zeroed blocks are detected in userspace.
+        CopyMethod::Sparse
+    } else if KERNEL_COPY {
+        CopyMethod::CopyFileRange
+    } else {
+        CopyMethod::Userspace
+    }
+}
+
+// Journaled ranges are synced to disk and recorded once this much
+// data has been written, or this long has passed.
+const JOURNAL_SYNC_BYTES: u64 = 64 * 1024 * 1024;
+const JOURNAL_SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+// Ranges written but not yet recorded in the journal.
+struct Unsynced {
+    ranges: Vec<Range<u64>>,
+    bytes: u64,
+    since: Instant,
+}
 
-#[derive(Debug)]
 pub struct CopyHandle {
     pub infd: File,
     pub outfd: File,
     pub metadata: Metadata,
-    pub config: Arc<Config>,
+    pub source: PathBuf,
+    pub target: PathBuf,
+    pub ctx: Arc<Context>,
+    // Manifest digest, if calculated during the copy.
+    digest: OnceLock<String>,
+    // Ranges remaining to be copied if resuming a partial copy.
+    pub(crate) resume: Option<Vec<Range<u64>>>,
+    // Temporary file to be moved into place, for atomic copies.
+    staged: Option<Staged>,
+    unsynced: Mutex<Unsynced>,
+    // How the data was copied, once known.
+    method: OnceLock<CopyMethod>,
+    failed: AtomicBool,
 }
 
 impl CopyHandle {
-    pub fn new(from: &Path, to: &Path, config: &Arc<Config>) -> Result<CopyHandle> {
+    pub fn new(from: &Path, to: &Path, ctx: &Arc<Context>) -> Result<CopyHandle> {
+        let config = &ctx.config;
         let infd = File::open(from)?;
         let metadata = infd.metadata()?;
 
-        if needs_backup(to, config)? {
-            let backup = get_backup_path(to)?;
-            info!("Backup: Rename {to:?} to {backup:?}");
-            fs::rename(to, backup)?;
-        }
+        let resume = ctx.journal.as_ref()
+            .filter(|_| !config.atomic)
+            .and_then(|j| j.resume(to, &metadata));
+
+        let mut staged = None;
+        let outfd = if let Some(ranges) = &resume {
+            info!("Resuming partial copy of {to:?}");
+            let remaining: u64 = ranges.iter().map(|r| r.end - r.start).sum();
+            ctx.updates.send(StatusUpdate::Copied(metadata.len() - remaining))?;
+            OpenOptions::new().write(true).open(to)?
+        } else if config.atomic {
+            let (outfd, s) = create_staged(to)?;
+            if let Err(e) = init_target(&outfd, to, &metadata, ctx) {
+                discard(s);
+                return Err(e);
+            }
+            staged = Some(s);
+            outfd
+        } else {
+            if needs_backup(to, config)? {
+                let backup = get_backup_path(to)?;
+                info!("Backup: Rename {to:?} to {backup:?}");
+                fs::rename(to, &backup)?;
+                ctx.updates.send(StatusUpdate::BackedUp { from: to.to_path_buf(), to: backup })?;
+            }
 
-        let outfd = File::create(to)?;
-        allocate_file(&outfd, metadata.len())?;
+            let outfd = File::create(to)?;
+            init_target(&outfd, to, &metadata, ctx)?;
+            if let Some(journal) = &ctx.journal {
+                journal.start(to, &metadata)?;
+            }
+            outfd
+        };
 
         let handle = CopyHandle {
             infd,
             outfd,
             metadata,
-            config: config.clone(),
+            source: from.to_path_buf(),
+            target: to.to_path_buf(),
+            ctx: ctx.clone(),
+            digest: OnceLock::new(),
+            resume,
+            staged,
+            unsynced: Mutex::new(Unsynced {
+                ranges: Vec::new(),
+                bytes: 0,
+                since: Instant::now(),
+            }),
+            method: OnceLock::new(),
+            failed: AtomicBool::new(false),
         };
+        ctx.updates.send(StatusUpdate::FileStarted {
+            from: from.to_path_buf(),
+            to: to.to_path_buf(),
+            size: handle.metadata.len(),
+        })?;
 
         Ok(handle)
     }
 
-    /// Copy len bytes from wherever the descriptor cursors are set.
-    fn copy_bytes(&self, len: u64, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+    /// Record a range of the target as written in the journal, if
+    /// enabled. Ranges are batched to limit the number of syncs. Atomic
+    /// copies are not resumable, so their ranges are not recorded.
+    pub(crate) fn commit(&self, off: u64, len: u64) -> Result<()> {
+        if self.ctx.journal.is_none() || self.staged.is_some() {
+            return Ok(());
+        }
+        let mut unsynced = self.unsynced.lock().unwrap();
+        match unsynced.ranges.last_mut() {
+            Some(last) if last.end == off => last.end += len,
+            _ => unsynced.ranges.push(off..off + len),
+        }
+        unsynced.bytes += len;
+        if unsynced.bytes >= JOURNAL_SYNC_BYTES || unsynced.since.elapsed() >= JOURNAL_SYNC_INTERVAL {
+            self.sync_journal(&mut unsynced)?;
+        }
+        Ok(())
+    }
+
+    fn sync_journal(&self, unsynced: &mut Unsynced) -> Result<()> {
+        if let Some(journal) = &self.ctx.journal
+            && !unsynced.ranges.is_empty()
+        {
+            // The data must be on disk before the journal claims it is.
+            self.outfd.sync_data()?;
+            for range in unsynced.ranges.drain(..) {
+                journal.commit(&self.target, range)?;
+            }
+        }
+        unsynced.bytes = 0;
+        unsynced.since = Instant::now();
+        Ok(())
+    }
+
+    /// Copy len bytes from wherever the descriptor cursors are set;
+    /// this should be `start`.
+    fn copy_bytes(&self, start: u64, len: u64) -> Result<u64> {
         let mut written = 0;
         while written < len {
-            let bytes_to_copy = cmp::min(len - written, self.config.block_size);
-            let bytes = copy_file_bytes(&self.infd, &self.outfd, bytes_to_copy)? as u64;
+            let bytes_to_copy = cmp::min(len - written, self.ctx.block_size());
+            self.ctx.throttle.acquire(bytes_to_copy);
+            self.ctx.control.checkpoint()?;
+            let bytes = if self.ctx.config.sparse == Sparse::Always {
+                copy_file_bytes_sparse(&self.infd, &self.outfd, bytes_to_copy)?
+            } else {
+                copy_file_bytes(&self.infd, &self.outfd, bytes_to_copy)?
+            } as u64;
+            self.commit(start + written, bytes)?;
             written += bytes;
-            updates.send(StatusUpdate::Copied(bytes))?;
+            self.ctx.updates.send(StatusUpdate::Copied(bytes))?;
         }
 
         Ok(written)
     }
 
     /// Wrapper around copy_bytes that looks for sparse blocks and skips them.
-    fn copy_sparse(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+    fn copy_sparse(&self) -> Result<u64> {
         let len = self.metadata.len();
         let mut pos = 0;
 
         while pos < len {
             let (next_data, next_hole) = next_sparse_segments(&self.infd, &self.outfd, pos)?;
 
-            let _written = self.copy_bytes(next_hole - next_data, updates)?;
+            let _written = self.copy_bytes(next_data, next_hole - next_data)?;
             pos = next_hole;
         }
 
@@ -94,14 +380,26 @@ impl CopyHandle {
     }
 
     pub fn try_reflink(&self) -> Result<bool> {
-        match self.config.reflink {
+        let config = &self.ctx.config;
+        if self.resume.is_some() {
+            debug!("Resuming, skipping reflink of {:?}", self.infd);
+            return Ok(false);
+        }
+        match config.reflink {
+            Reflink::Auto if config.sparse != Sparse::Auto => {
+                debug!("Sparse conversion requested, skipping reflink of {:?}", self.infd);
+                Ok(false)
+            }
+
             Reflink::Always | Reflink::Auto => {
                 debug!("Attempting reflink from {:?}->{:?}", self.infd, self.outfd);
                 let worked = reflink(&self.infd, &self.outfd)?;
                 if worked {
                     debug!("Reflink {:?} succeeded", self.outfd);
+                    self.set_method(CopyMethod::Reflink);
+                    self.ctx.updates.send(StatusUpdate::Copied(self.metadata.len()))?;
                     Ok(true)
-                } else if self.config.reflink == Reflink::Always {
+                } else if config.reflink == Reflink::Always {
                     Err(XcpError::ReflinkFailed(format!("{:?}->{:?}", self.infd, self.outfd)).into())
                 } else {
                     debug!("Failed to reflink, falling back to copy");
@@ -115,153 +413,662 @@ impl CopyHandle {
         }
     }
 
-    pub fn copy_file(&self, updates: &Arc<dyn StatusUpdater>) -> Result<u64> {
+    /// Copy the whole file in userspace, calculating the manifest
+    /// digest as the data is read. If `holes` is set blocks of zeros
+    /// are left as holes in the target, as with
+    /// [copy_file_offset_sparse].
+    fn copy_hashed(&self, mut hasher: FileHasher, holes: bool) -> Result<u64> {
+        let len = self.metadata.len();
+        let bsize = cmp::min(self.ctx.block_size(), BUF_SIZE as u64) as usize;
+        let mut buf = vec![0; bsize];
+        let mut pos = 0;
+
+        while pos < len {
+            self.ctx.throttle.acquire(cmp::min(len - pos, bsize as u64));
+            self.ctx.control.checkpoint()?;
+            let n = self.infd.read_at(&mut buf, pos)?;
+            if n == 0 {
+                break;
+            }
+            hasher.update(&buf[..n]);
+            if holes {
+                write_sparse(&self.outfd, &buf[..n], pos)?;
+            } else {
+                self.outfd.write_all_at(&buf[..n], pos)?;
+            }
+            self.commit(pos, n as u64)?;
+            pos += n as u64;
+            self.ctx.updates.send(StatusUpdate::Copied(n as u64))?;
+        }
+        let _ = self.digest.set(hasher.finish());
+
+        Ok(pos)
+    }
+
+    /// The parts of the given ranges that hold data in the source. Holes
+    /// are skipped unless they are to be filled in.
+    pub(crate) fn data_ranges(&self, ranges: &[Range<u64>]) -> Result<Vec<Range<u64>>> {
+        if self.ctx.config.sparse == Sparse::Never || !probably_sparse(&self.infd)? {
+            return Ok(ranges.to_vec());
+        }
+        self.set_method(CopyMethod::Sparse);
+        let mut data = Vec::new();
+        for range in ranges {
+            let mut pos = range.start;
+            while pos < range.end {
+                let (next_data, next_hole) = next_sparse_segments(&self.infd, &self.outfd, pos)?;
+                if next_data >= range.end {
+                    break;
+                }
+                let end = cmp::min(next_hole, range.end);
+                data.push(next_data..end);
+                pos = end;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Copy the given ranges of the file, using explicit offsets.
+    fn copy_ranges(&self, ranges: &[Range<u64>]) -> Result<u64> {
+        let bsize = self.ctx.block_size();
+        let mut total = 0;
+        for range in &self.data_ranges(ranges)? {
+            let mut pos = range.start;
+            while pos < range.end {
+                let len = cmp::min(range.end - pos, bsize);
+                self.ctx.throttle.acquire(len);
+                self.ctx.control.checkpoint()?;
+                if self.ctx.config.sparse == Sparse::Always {
+                    copy_file_offset_sparse(&self.infd, &self.outfd, len, pos as i64)?;
+                } else {
+                    copy_file_offset(&self.infd, &self.outfd, len, pos as i64)?;
+                }
+                self.commit(pos, len)?;
+                pos += len;
+                total += len;
+                self.ctx.updates.send(StatusUpdate::Copied(len))?;
+            }
+        }
+        Ok(total)
+    }
+
+    /// Record how the data is being copied, for reporting on
+    /// completion.
+    pub(crate) fn set_method(&self, method: CopyMethod) {
+        let _ = self.method.set(method);
+    }
+
+    /// Mark the copy as failed. The target will not be finalised, and
+    /// for atomic copies will not be moved into place. Returns `true`
+    /// if the copy had not already failed.
+    pub(crate) fn fail(&self) -> bool {
+        !self.failed.swap(true, Ordering::Relaxed)
+    }
+
+    pub fn copy_file(&self) -> Result<u64> {
+        let result = self.copy_data();
+        if result.is_err() {
+            self.fail();
+        }
+        result
+    }
+
+    fn copy_data(&self) -> Result<u64> {
         if self.try_reflink()? {
             return Ok(self.metadata.len());
         }
-        let total = if probably_sparse(&self.infd)? {
-            self.copy_sparse(updates)?
+        if let Some(ranges) = &self.resume {
+            return self.copy_ranges(ranges);
+        }
+        let sparse = self.ctx.config.sparse != Sparse::Never && probably_sparse(&self.infd)?;
+        if let Some(manifest) = &self.ctx.manifest
+            && (self.ctx.config.sparse == Sparse::Always || !sparse || !KERNEL_COPY)
+        {
+            // Hashing inline saves reading the target back, which is
+            // worth giving up a kernel copy for, but not the holes of
+            // a sparse file.
+            let holes = sparse || self.ctx.config.sparse == Sparse::Always;
+            self.set_method(if holes {
+                CopyMethod::Sparse
+            } else {
+                CopyMethod::Userspace
+            });
+            return self.copy_hashed(manifest.hasher(), holes);
+        }
+        let total = if sparse {
+            self.set_method(CopyMethod::Sparse);
+            self.copy_sparse()?
         } else {
-            self.copy_bytes(self.metadata.len(), updates)?
+            self.copy_bytes(0, self.metadata.len())?
         };
 
         Ok(total)
     }
 
     fn finalise_copy(&self) -> Result<()> {
-        if !self.config.no_perms {
+        let config = &self.ctx.config;
+        let preserve = &config.preserve;
+        // Ownership first, as chown may clear setuid/setgid bits.
+        if preserve.ownership && copy_owner(&self.infd, &self.outfd).is_err() {
+            warn!("Failed to copy file ownership: {:?}", self.infd);
+        }
+        // FIXME: We don't have a way of detecting if the target FS
+        // supports xattrs/ACLs, so assume any error is "Unsupported"
+        // for now.
+        if preserve.xattr && let Err(e) = copy_xattrs(&self.infd, &self.outfd) {
+            warn!("Failed to copy xattrs from {:?}: {e}", self.infd);
+        }
+        if preserve.mode {
             copy_permissions(&self.infd, &self.outfd)?;
         }
-        if !self.config.no_timestamps {
+        if preserve.acl && let Err(e) = copy_acl(&self.infd, &self.outfd) {
+            warn!("Failed to copy ACLs from {:?}: {e}", self.infd);
+        }
+        if preserve.timestamps {
             copy_timestamps(&self.infd, &self.outfd)?;
         }
-        if self.config.ownership && copy_owner(&self.infd, &self.outfd).is_err() {
-            warn!("Failed to copy file ownership: {:?}", self.infd);
+        // Flags last, as they may make the file immutable. Immutable
+        // files can't be renamed, so atomic copies set them once
+        // installed.
+        if preserve.flags && self.staged.is_none() {
+            preserve_flags(&self.infd, &self.outfd, &self.target);
         }
-        if self.config.fsync {
+        if config.fsync {
             debug!("Syncing file {:?}", self.outfd);
             sync(&self.outfd)?;
         }
         Ok(())
     }
+
+    /// Move an atomic copy into place.
+    fn install(&mut self) -> Result<()> {
+        let Some(staged) = self.staged.take() else {
+            return Ok(());
+        };
+        debug!("Installing {:?}", self.target);
+        let backup = install(&self.outfd, staged, &self.target, &self.ctx.config)?;
+        if let Some(backup) = backup {
+            self.ctx.updates.send(StatusUpdate::BackedUp { from: self.target.clone(), to: backup })?;
+        }
+        if self.ctx.config.preserve.flags {
+            preserve_flags(&self.infd, &self.outfd, &self.target);
+        }
+        Ok(())
+    }
+
+    /// Re-read the target and compare it to the source. Returns
+    /// `false` if they differ.
+    fn verify_copy(&self) -> Result<bool> {
+        debug!("Verifying {:?}", self.target);
+        // Atomic copies are verified before they are installed, via
+        // the (read-write) staged file.
+        let reopened;
+        let outfd = if self.staged.is_some() {
+            &self.outfd
+        } else {
+            reopened = File::open(&self.target)?;
+            &reopened
+        };
+        if self.ctx.config.verify == Verify::Uncached {
+            sync(&self.outfd)?;
+            drop_cache(outfd)?;
+        }
+        let throttle = &self.ctx.throttle;
+        Ok(hash_file(&self.infd, throttle)? == hash_file(outfd, throttle)?)
+    }
+
+    /// Drop the source and target from the page cache.
+    fn release_cache(&self) {
+        // Dirty pages are not dropped. fsync has already synced.
+        let synced = self.ctx.config.fsync || self.ctx.config.verify == Verify::Uncached;
+        let result = if synced { Ok(()) } else { sync(&self.outfd) }
+            .and_then(|_| drop_cache(&self.outfd))
+            .and_then(|_| drop_cache(&self.infd));
+        if let Err(e) = result {
+            warn!("Failed to drop {:?} from the page cache: {e}", self.target);
+        }
+    }
+
+    fn verify_and_report(&self) -> bool {
+        let err = match self.verify_copy() {
+            Ok(true) => return true,
+            Ok(false) => XcpError::VerifyFailed(self.target.clone()),
+            Err(e) => XcpError::CopyError(format!("Failed to verify {:?}: {e}", self.target)),
+        };
+        self.ctx.report_error(err);
+        false
+    }
 }
 
 impl Drop for CopyHandle {
     fn drop(&mut self) {
+        // Completed ranges are kept for resuming even if the copy
+        // failed.
+        if let Err(e) = self.sync_journal(&mut self.unsynced.lock().unwrap()) {
+            warn!("Failed to journal {:?}: {e}", self.target);
+        }
+        if self.failed.load(Ordering::Relaxed) {
+            // The error has already been reported.
+            if let Some(staged) = self.staged.take() {
+                discard(staged);
+            } else if self.ctx.control.is_cancelled() && self.ctx.journal.is_none() {
+                // Partial copies are only useful if they can be resumed.
+                debug!("Removing partial copy {:?}", self.target);
+                if let Err(e) = remove_file(&self.target) {
+                    warn!("Failed to remove partial copy {:?}: {e}", self.target);
+                }
+            }
+            if self.ctx.config.preserve.links {
+                self.ctx.links.complete(&self.target, false);
+            }
+            return;
+        }
         // FIXME: Should we check for panicking() here?
-        if let Err(e) = self.finalise_copy() {
+        let atomic = self.staged.is_some();
+        let result = self.finalise_copy();
+        // A staged copy that fails verification never replaces the
+        // target.
+        let verified = result.is_ok()
+            && (self.ctx.config.verify == Verify::None || self.verify_and_report());
+        let result = result.and_then(|_| if verified { self.install() } else { Ok(()) });
+        if let Err(e) = &result {
             error!("Error during finalising copy operation {:?} -> {:?}: {}", self.infd, self.outfd, e);
+            if atomic {
+                self.ctx.report_error(XcpError::CopyError(format!("Failed to install {:?}: {e}", self.target)));
+            }
+        }
+        if let Some(staged) = self.staged.take() {
+            // The target was not replaced.
+            discard(staged);
+        }
+        let ok = verified
+            && result.is_ok()
+            && self.ctx.record_manifest(&self.target, self.digest.take())
+            && self.ctx.record_done(&self.target);
+        if ok && self.ctx.config.drop_cache {
+            self.release_cache();
+        }
+        if self.ctx.config.preserve.links {
+            self.ctx.links.complete(&self.target, ok);
+        }
+        if ok {
+            let method = self.method.get().copied()
+                .unwrap_or(data_method(self.ctx.config.sparse));
+            let finished = StatusUpdate::FileFinished { path: self.target.clone(), method };
+            if let Err(e) = self.ctx.updates.send(finished) {
+                error!("Failed to send status update message: {e}");
+            }
         }
     }
 }
 
-#[derive(Debug)]
+// Size and register a newly created target.
+fn init_target(outfd: &File, to: &Path, metadata: &Metadata, ctx: &Context) -> Result<()> {
+    allocate_file(outfd, metadata.len())?;
+    if ctx.config.sparse == Sparse::Never && !allocate_blocks(outfd, metadata.len())? {
+        debug!("Filesystem does not support allocation, target may be sparse: {to:?}");
+    }
+    Ok(())
+}
+
+/// A single copy operation. The first path is the source and the
+/// second the target unless otherwise noted.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
 pub enum Operation {
     Copy(PathBuf, PathBuf),
+    /// Recreate the source symlink (the first path) at the target.
     Link(PathBuf, PathBuf),
+    /// Hard-link the target of a previous copy (the first path) to a
+    /// new target.
+    HardLink(PathBuf, PathBuf),
     Special(PathBuf, PathBuf),
+    /// Create the target directory. Directories are created by the
+    /// tree-walker and never sent to the workers.
+    CreateDir(PathBuf, PathBuf),
+}
+
+impl Operation {
+    /// The target path of the operation.
+    pub fn target(&self) -> &Path {
+        match self {
+            Operation::Copy(_, to)
+            | Operation::Link(_, to)
+            | Operation::HardLink(_, to)
+            | Operation::Special(_, to)
+            | Operation::CreateDir(_, to) => to,
+        }
+    }
+}
+
+fn preserve_flags(infd: &File, outfd: &File, target: &Path) {
+    match copy_flags(infd, outfd) {
+        Ok(true) => {}
+        Ok(false) => debug!("Inode flags not supported, skipping: {target:?}"),
+        Err(e) => warn!("Failed to copy inode flags: {target:?}: {e}"),
+    }
 }
 
+/// Recreate a symlink and copy its metadata.
+pub fn copy_symlink(from: &Path, to: &Path, config: &Config) -> Result<()> {
+    let lfile = read_link(from)?;
+    replace_target(to, config)?;
+    symlink(&lfile, to)?;
+
+    let preserve = &config.preserve;
+    if preserve.ownership && let Err(e) = copy_owner_link(from, to) {
+        warn!("Failed to copy symlink ownership: {to:?}: {e}");
+    }
+    // Not all filesystems allow xattrs on symlinks.
+    if preserve.xattr && let Err(e) = copy_xattrs_path(from, to) {
+        warn!("Failed to copy symlink xattrs: {to:?}: {e}");
+    }
+    if preserve.timestamps {
+        copy_timestamps_link(from, to)?;
+    }
+    Ok(())
+}
+
+/// Recreate a special file (e.g. a FIFO or device node).
+pub fn copy_special(from: &Path, to: &Path, config: &Config) -> Result<()> {
+    replace_target(to, config)?;
+    copy_node(from, to)?;
+    Ok(())
+}
+
+// Clear the way for a recreated link or special file, backing up any
+// existing target as for copied files.
+fn replace_target(to: &Path, config: &Config) -> Result<()> {
+    if to.symlink_metadata().is_err() {
+        return Ok(());
+    }
+    if config.no_clobber {
+        return Err(XcpError::DestinationExists("Destination file exists and --no-clobber is set.", to.to_path_buf()).into());
+    }
+    if needs_backup(to, config)? {
+        let backup = get_backup_path(to)?;
+        info!("Backup: Rename {to:?} to {backup:?}");
+        fs::rename(to, backup)?;
+    } else {
+        remove_file(to)?;
+    }
+    Ok(())
+}
+
+/// Recreate a hard-link to a previously copied file. This will block
+/// until the copy of the original has completed.
+pub fn link_copied(from: &Path, to: &Path, ctx: &Context) -> Result<()> {
+    ctx.links.wait(from)?;
+    replace_target(to, &ctx.config)?;
+    hard_link(from, to)?;
+    ctx.record_manifest(to, None);
+    ctx.record_done(to);
+    Ok(())
+}
+
+fn finalise_dir(from: &Path, to: &Path, config: &Config) -> Result<()> {
+    debug!("Finalising directory {to:?}");
+    let preserve = &config.preserve;
+    // Ownership first, as chown may clear setuid/setgid bits.
+    if preserve.ownership && let Err(e) = copy_owner_path(from, to) {
+        warn!("Failed to copy directory ownership: {to:?}: {e}");
+    }
+    if preserve.xattr && let Err(e) = copy_xattrs_path(from, to) {
+        warn!("Failed to copy directory xattrs: {to:?}: {e}");
+    }
+    if preserve.mode {
+        copy_permissions_path(from, to)?;
+    }
+    if preserve.acl && let Err(e) = copy_acl_path(from, to) {
+        warn!("Failed to copy directory ACLs: {to:?}: {e}");
+    }
+    if preserve.timestamps {
+        copy_timestamps_path(from, to)?;
+    }
+    if preserve.flags {
+        match (File::open(from), File::open(to)) {
+            (Ok(infd), Ok(outfd)) => preserve_flags(&infd, &outfd, to),
+            (Err(e), _) | (_, Err(e)) => warn!("Failed to copy directory flags: {to:?}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Copy metadata to the directories created during the copy. This
+/// must be called after all other operations have completed, as
+/// creating children will update the directory timestamps, and the
+/// permissions may prevent writing to it. Directories are processed
+/// children-first for the same reasons.
+fn finalise_dirs(ctx: &Context) -> Result<()> {
+    let dirs = std::mem::take(&mut *ctx.dirs.lock().unwrap());
+    for (from, to) in dirs.iter().rev() {
+        if let Err(e) = finalise_dir(from, to, &ctx.config) {
+            error!("Error finalising directory {from:?} -> {to:?}: {e}");
+            ctx.updates.send(StatusUpdate::Error(XcpError::CopyError(e.to_string())))?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Perform the parts of a planned operation that must be done
+/// in-order, and pass the rest to the workers.
+fn execute(planned: PlannedOperation, ctx: &Context, work_tx: &cbc::Sender<Operation>) -> Result<()> {
+    let stats = &ctx.updates;
+
+    if let Some(skip) = planned.skip {
+        debug!("Skipping {:?} ({skip:?})", planned.operation);
+        if planned.size > 0 {
+            stats.send(StatusUpdate::Size(planned.size))?;
+        }
+        let path = planned.operation.target().to_path_buf();
+        // Later links may still refer to this file.
+        ctx.links.complete(&path, true);
+        stats.send(StatusUpdate::Skipped { path, size: planned.size })?;
+        return Ok(());
+    }
+
+    if planned.conflict == Some(Conflict::NoClobber) {
+        let msg = "Destination file exists and --no-clobber is set.";
+        let target = planned.operation.target().to_path_buf();
+        ctx.links.complete(&target, false);
+        stats.send(StatusUpdate::Error(
+            XcpError::DestinationExists(msg, target)))?;
+        if ctx.config.keep_going {
+            return Ok(());
+        }
+        return Err(XcpError::EarlyShutdown(msg).into());
+    }
+
+    match planned.operation {
+        Operation::CreateDir(from, target) => {
+            // Create dir tree immediately as we can't
+            // guarantee a worker will action the creation
+            // before a subsequent copy operation requires it.
+            debug!("Creating target directory {target:?}");
+            let exists = target.is_dir();
+            if let Err(err) = create_dir_all(&target) {
+                let msg = format!("Error creating target directory: {err}");
+                return ctx.report_failure(&from, anyhow::Error::new(err).context(msg));
+            }
+            if !exists {
+                stats.send(StatusUpdate::DirCreated { path: target.clone() })?;
+            }
+            ctx.dirs.lock().unwrap().push((from, target));
+        }
+        op => {
+            debug!("Send operation {op:?}");
+            if let Operation::Copy(..) = op {
+                stats.send(StatusUpdate::Size(planned.size))?;
+            }
+            if work_tx.send(op).is_err() {
+                // The workers have stopped; usually due to cancellation.
+                ctx.control.checkpoint()?;
+                return Err(XcpError::CopyError("Workers exited unexpectedly".to_string()).into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The work for a copy run.
+pub enum Work {
+    /// Walk and copy the sources.
+    Sources(Vec<PathBuf>),
+    /// Execute the operations of an existing plan.
+    Plan(Vec<PlannedOperation>, Vec<(PathBuf, PathBuf)>),
+}
+
+impl From<&CopyPlan> for Work {
+    fn from(plan: &CopyPlan) -> Work {
+        Work::Plan(plan.operations.clone(), plan.mirrors.clone())
+    }
+}
+
+fn execute_all(
+    planned: impl Iterator<Item = Result<PlannedOperation>>,
+    dest: &Path,
+    ctx: &Context,
+    work_tx: &cbc::Sender<Operation>,
+) -> Result<()> {
+    for planned in planned {
+        ctx.control.checkpoint()?;
+        match planned {
+            Ok(planned) => execute(planned, ctx, work_tx)?,
+            Err(e) => {
+                let path = walk_error_path(&e)
+                    .unwrap_or(dest)
+                    .to_path_buf();
+                ctx.report_failure(&path, e)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Walk the source trees, creating the target directories and sending
+/// file operations to the workers.
 pub fn tree_walker(
-    sources: Vec<PathBuf>,
+    work: Work,
     dest: &Path,
-    config: &Config,
+    ctx: &Arc<Context>,
     work_tx: cbc::Sender<Operation>,
-    stats: Arc<dyn StatusUpdater>,
 ) -> Result<()> {
     debug!("Starting walk worker {:?}", thread::current().id());
+    ctx.prioritise_thread();
+
+    match work {
+        Work::Sources(sources) => {
+            let mut planner = Planner::new(sources, dest, &ctx.config, ctx.journal.as_ref(), &ctx.links, &ctx.throttle);
+            execute_all(planner.by_ref(), dest, ctx, &work_tx)?;
+            ctx.mirrors.lock().unwrap().extend(planner.into_mirrors());
+        }
+        Work::Plan(operations, mirrors) => {
+            // Links must wait for the copies planned before them.
+            for planned in &operations {
+                if let Operation::HardLink(first, _) = &planned.operation {
+                    ctx.links.expect(first);
+                }
+            }
+            execute_all(operations.into_iter().map(Ok), dest, ctx, &work_tx)?;
+            ctx.mirrors.lock().unwrap().extend(mirrors);
+        }
+    }
+    debug!("Walk-worker finished: {:?}", thread::current().id());
 
-    for source in sources {
-        let sourcedir = source
-            .components()
-            .next_back()
-            .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+    Ok(())
+}
 
-        let target_base = if dest.exists() && dest.is_dir() && !config.no_target_directory {
-            dest.join(sourcedir)
-        } else {
-            dest.to_path_buf()
-        };
-        debug!("Target base is {target_base:?}");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::write;
+    use tempfile::TempDir;
+
+    use crate::feedback::{ChannelUpdater, NoopUpdater};
 
-        let gitignore = parse_ignore(&source, config)?;
+    fn verify_errors(verify: Verify, corrupt: bool) -> Result<Vec<XcpError>> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.txt");
+        let to = tdir.path().join("to.txt");
+        write(&from, "This is a test file.")?;
+
+        let config = Arc::new(Config { verify, ..Config::default() });
+        let updater = ChannelUpdater::new(&config);
+        let stat_rx = updater.rx_channel();
+        let ctx = Arc::new(Context::new(config, &to, Arc::new(updater), CopyController::default())?);
 
-        for entry in WalkDir::new(&source)
-            .into_iter()
-            .filter_entry(|e| ignore_filter(e, &gitignore))
         {
-            debug!("Got tree entry {entry:?}");
-            let epath = entry?.into_path();
-            let from = if config.dereference {
-                let cpath = canonicalize(&epath)?;
-                debug!("Dereferencing {epath:?} into {cpath:?}");
-                cpath
-            } else {
-                epath.clone()
-            };
-            let meta = from.symlink_metadata()?;
-            let path = epath.strip_prefix(&source)?;
-            let target = if !empty_path(path) {
-                target_base.join(path)
-            } else {
-                target_base.clone()
-            };
-
-            if config.no_clobber && target.exists() {
-                let msg = "Destination file exists and --no-clobber is set.";
-                stats.send(StatusUpdate::Error(
-                    XcpError::DestinationExists(msg, target)))?;
-                return Err(XcpError::EarlyShutdown(msg).into());
+            let handle = CopyHandle::new(&from, &to, &ctx)?;
+            handle.copy_file()?;
+            if corrupt {
+                write(&to, "This is a test fil3.")?;
             }
+        }
 
-            let ft = FileType::from(meta.file_type());
-            match ft {
-                FileType::File => {
-                    debug!("Send copy operation {from:?} to {target:?}");
-                    stats.send(StatusUpdate::Size(meta.len()))?;
-                    work_tx.send(Operation::Copy(from, target))?;
-                }
+        let errors = stat_rx.try_iter()
+            .filter_map(|s| match s {
+                StatusUpdate::Error(e) => Some(e),
+                _ => None,
+            })
+            .collect();
+        Ok(errors)
+    }
 
-                FileType::Symlink => {
-                    let lfile = read_link(from)?;
-                    debug!("Send symlink operation {lfile:?} to {target:?}");
-                    work_tx.send(Operation::Link(lfile, target))?;
-                }
+    #[test]
+    fn test_verify_copy() -> Result<()> {
+        assert!(verify_errors(Verify::Cached, false)?.is_empty());
+        assert!(verify_errors(Verify::Uncached, false)?.is_empty());
+        assert!(verify_errors(Verify::None, true)?.is_empty());
+        Ok(())
+    }
 
-                FileType::Dir => {
-                    // Create dir tree immediately as we can't
-                    // guarantee a worker will action the creation
-                    // before a subsequent copy operation requires it.
-                    debug!("Creating target directory {target:?}");
-                    if let Err(err) = create_dir_all(&target) {
-                        let msg = format!("Error creating target directory: {err}");
-                        error!("{msg}");
-                        return Err(XcpError::CopyError(msg).into())
-                    }
-                    if config.ownership &&
-                        let Err(e) = chown(&target, Some(meta.uid()), Some(meta.gid()))
-                    {
-                        warn!("Failed to copy directory ownership: {target:?}: {e}");
-                    }
-                }
+    fn journal_lines(atomic: bool) -> Result<Vec<String>> {
+        let tdir = TempDir::new()?;
+        let from = tdir.path().join("from.bin");
+        let to = tdir.path().join("to.bin");
+        let journal = tdir.path().join("journal");
+        write(&from, vec![0x5a; 10000])?;
 
-                FileType::Socket | FileType::Char | FileType::Fifo => {
-                    debug!("Special file found: {from:?} to {target:?}");
-                    work_tx.send(Operation::Special(from, target))?;
-                }
+        let config = Arc::new(Config {
+            journal: Some(journal.clone()),
+            block_size: 1000,
+            atomic,
+            ..Config::default()
+        });
+        let ctx = Arc::new(Context::new(config, &to, Arc::new(NoopUpdater), CopyController::default())?);
+        CopyHandle::new(&from, &to, &ctx)?.copy_file()?;
 
-                FileType::Block | FileType::Other => {
-                    error!("Unsupported filetype found: {target:?} -> {ft:?}");
-                    return Err(XcpError::UnknownFileType(target).into());
-                }
-            };
-        }
+        let lines = std::fs::read_to_string(&journal)?
+            .lines()
+            .skip(1)
+            .map(|l| l.split(' ').take(3).collect::<Vec<_>>().join(" "))
+            .collect();
+        Ok(lines)
     }
-    debug!("Walk-worker finished: {:?}", thread::current().id());
 
-    Ok(())
-}
+    #[test]
+    fn test_journal_batching() -> Result<()> {
+        // The blocks are recorded as a single range.
+        let lines = journal_lines(false)?;
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("S 10000"));
+        assert_eq!("R 0 10000", lines[1]);
+        assert!(lines[2].starts_with("D "));
 
-fn empty_path(path: &Path) -> bool {
-    *path == PathBuf::new()
+        // Staged files are not resumable.
+        let lines = journal_lines(true)?;
+        assert_eq!(1, lines.len());
+        assert!(lines[0].starts_with("D "));
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_mismatch() -> Result<()> {
+        let errors = verify_errors(Verify::Uncached, true)?;
+        assert_eq!(1, errors.len());
+        assert!(matches!(errors[0], XcpError::VerifyFailed(_)));
+        Ok(())
+    }
 }
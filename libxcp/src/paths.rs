@@ -14,38 +14,740 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::path::Path;
-use ignore::gitignore::{Gitignore, GitignoreBuilder};
-use log::info;
-use walkdir::DirEntry;
-
-use crate::config::Config;
-use crate::errors::Result;
-
-/// Parse a git ignore file.
-pub fn parse_ignore(source: &Path, config: &Config) -> Result<Option<Gitignore>> {
-    let gitignore = if config.gitignore {
-        let gifile = source.join(".gitignore");
-        info!("Using .gitignore file {gifile:?}");
-        let mut builder = GitignoreBuilder::new(source);
-        builder.add(&gifile);
-        let ignore = builder.build()?;
-        Some(ignore)
+//! Source path filtering.
+//!
+//! As well as `.gitignore` support (see `Config::gitignore`) and
+//! selection by file type, size and age (see `Config::file_types`
+//! and `Config::min_size` and friends), paths can be selected with a
+//! [Filter]; an ordered list of include and exclude rules with
+//! similar semantics to `rsync`'s filter rules:
+//!
+//! * Rules are checked in order against each path relative to the
+//!   source root, and the first match decides whether it is
+//!   included. Paths matching no rule are included.
+//! * A pattern starting with `/` is anchored to the source root;
+//!   otherwise it matches the end of the path, at any depth.
+//! * A pattern ending with `/` only matches directories.
+//! * `*` and `?` do not match `/`, while `**` matches any number of
+//!   directories.
+//! * Excluding a directory excludes everything under it, so to
+//!   include only some files the directories leading to them must
+//!   also be included; e.g. `+ */`, `+ *.rs`, `- *`.
+//!
+//! # Example
+//!
+//!     use std::path::Path;
+//!     use libxcp::paths::{Filter, FilterRule};
+//!     # fn main() -> libxcp::errors::Result<()> {
+//!
+//!     // Skip object files, and everything under `target` other than
+//!     // the release build.
+//!     let filter = Filter::new()
+//!         .with(FilterRule::exclude("*.o")?)
+//!         .with(FilterRule::include("/target/release/")?)
+//!         .with("- /target/*".parse()?);
+//!
+//!     assert!(filter.is_excluded(Path::new("src/main.o"), false));
+//!     assert!(!filter.is_excluded(Path::new("src/main.rs"), false));
+//!     assert!(!filter.is_excluded(Path::new("target"), true));
+//!     assert!(!filter.is_excluded(Path::new("target/release"), true));
+//!     assert!(filter.is_excluded(Path::new("target/debug"), true));
+//!     # Ok(())
+//!     # }
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{read_link, read_to_string, Metadata};
+use std::ops::Bound;
+use std::os::unix::fs::MetadataExt;
+use std::path::{absolute, Component, Path, PathBuf};
+use std::result;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use globset::{GlobBuilder, GlobMatcher};
+use ignore::gitignore::Gitignore;
+use ignore::types::{Types, TypesBuilder};
+use ignore::{Match, Walk, WalkBuilder};
+use log::{debug, info, warn};
+
+use crate::config::{Config, Dereference, UnsafeLinks};
+use crate::errors::{Result, XcpError};
+use crate::gitindex::tracked_files;
+
+/// Whether paths matching a [FilterRule] are copied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterAction {
+    Include,
+    Exclude,
+}
+
+/// A single include or exclude pattern; see the [module
+/// documentation](self) for the pattern syntax. [FromStr] is
+/// supported, accepting rsync-style rules; `+ PATTERN` or `include
+/// PATTERN`, and `- PATTERN` or `exclude PATTERN`.
+#[derive(Clone, Debug)]
+pub struct FilterRule {
+    pub action: FilterAction,
+    pattern: String,
+    dir_only: bool,
+    matcher: GlobMatcher,
+}
+
+impl FilterRule {
+    pub fn new(action: FilterAction, pattern: &str) -> Result<FilterRule> {
+        let (glob, dir_only) = match pattern.strip_suffix('/') {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+        let glob = match glob.strip_prefix('/') {
+            Some(p) => p.to_string(),
+            None => format!("**/{glob}"),
+        };
+        let matcher = GlobBuilder::new(&glob)
+            .literal_separator(true)
+            .build()
+            .map_err(|e| XcpError::InvalidArguments(format!("Invalid filter pattern '{pattern}': {e}")))?
+            .compile_matcher();
+        Ok(FilterRule {
+            action,
+            pattern: pattern.to_string(),
+            dir_only,
+            matcher,
+        })
+    }
+
+    pub fn include(pattern: &str) -> Result<FilterRule> {
+        FilterRule::new(FilterAction::Include, pattern)
+    }
+
+    pub fn exclude(pattern: &str) -> Result<FilterRule> {
+        FilterRule::new(FilterAction::Exclude, pattern)
+    }
+
+    /// The pattern as given.
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Whether the rule applies to `path`, relative to the source
+    /// root.
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        (is_dir || !self.dir_only) && self.matcher.is_match(path)
+    }
+}
+
+impl FromStr for FilterRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        let err = || XcpError::InvalidArguments(format!("Unexpected value for 'filter': {s}"));
+        let (action, pattern) = s.trim_start().split_once([' ', '_']).ok_or_else(err)?;
+        let action = match action {
+            "+" | "include" => FilterAction::Include,
+            "-" | "exclude" => FilterAction::Exclude,
+            _ => return Err(err().into()),
+        };
+        FilterRule::new(action, pattern)
+    }
+}
+
+/// An ordered list of [FilterRule]s. The default filter includes
+/// everything.
+#[derive(Clone, Debug, Default)]
+pub struct Filter {
+    rules: Vec<FilterRule>,
+}
+
+impl Filter {
+    pub fn new() -> Filter {
+        Filter::default()
+    }
+
+    /// Append a rule, returning the updated filter.
+    pub fn with(mut self, rule: FilterRule) -> Filter {
+        self.push(rule);
+        self
+    }
+
+    /// Append a rule. It is only checked if no earlier rule matches.
+    pub fn push(&mut self, rule: FilterRule) {
+        self.rules.push(rule);
+    }
+
+    /// Append all the rules of another filter.
+    pub fn extend(&mut self, other: Filter) {
+        self.rules.extend(other.rules);
+    }
+
+    /// Read a file of exclude patterns, one per line, as with
+    /// rsync's `--exclude-from`. Blank lines and lines starting with
+    /// `#` or `;` are ignored.
+    pub fn read_excludes(file: &Path) -> Result<Filter> {
+        let mut filter = Filter::new();
+        for line in read_to_string(file)?.lines() {
+            if line.trim().is_empty() || line.starts_with(['#', ';']) {
+                continue;
+            }
+            filter.push(FilterRule::exclude(line)?);
+        }
+        Ok(filter)
+    }
+
+    pub fn rules(&self) -> &[FilterRule] {
+        &self.rules
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether `path`, relative to the source root, is excluded. The
+    /// root itself is never excluded.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if path.as_os_str().is_empty() {
+            return false;
+        }
+        self.rules.iter()
+            .find(|r| r.matches(path, is_dir))
+            .is_some_and(|r| r.action == FilterAction::Exclude)
+    }
+}
+
+/// The name of per-directory ignore files specific to xcp. These take
+/// precedence over `.ignore` and `.gitignore` files.
+pub const XCPIGNORE: &str = ".xcpignore";
+
+/// The file types available for `Config::file_types`, as pairs of
+/// the type name and its globs.
+pub fn file_types() -> Vec<(String, Vec<String>)> {
+    let mut builder = TypesBuilder::new();
+    builder.add_defaults();
+    builder.definitions()
+        .into_iter()
+        .map(|def| (def.name().to_string(), def.globs().to_vec()))
+        .collect()
+}
+
+/// Whether any of the source filters in `config` are set.
+pub(crate) fn is_filtered(config: &Config) -> bool {
+    config.gitignore
+        || config.prune_empty_dirs
+        || !config.filter.is_empty()
+        || !config.file_types.is_empty()
+        || config.one_file_system
+        || config.max_depth.is_some()
+        || config.min_depth.is_some()
+        || !Selection::new(config).is_empty()
+}
+
+// The size and age predicates from `Config`.
+#[derive(Clone, Copy, Debug)]
+struct Selection {
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+    older_than: Option<SystemTime>,
+}
+
+impl Selection {
+    fn new(config: &Config) -> Selection {
+        Selection {
+            min_size: config.min_size,
+            max_size: config.max_size,
+            newer_than: config.newer_than,
+            older_than: config.older_than,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.min_size.is_none()
+            && self.max_size.is_none()
+            && self.newer_than.is_none()
+            && self.older_than.is_none()
+    }
+
+    // Only regular files are checked; links are checked by their
+    // target if followed.
+    fn selects(&self, meta: &Metadata) -> bool {
+        if !meta.is_file() {
+            return true;
+        }
+        let len = meta.len();
+        if self.min_size.is_some_and(|min| len < min) || self.max_size.is_some_and(|max| len > max) {
+            return false;
+        }
+        if self.newer_than.is_none() && self.older_than.is_none() {
+            return true;
+        }
+        let Ok(mtime) = meta.modified() else {
+            return true;
+        };
+        !(self.newer_than.is_some_and(|t| mtime <= t) || self.older_than.is_some_and(|t| mtime >= t))
+    }
+}
+
+/// The device and inode of the directories walked so far, shared
+/// between walkers.
+pub(crate) type Visited = Arc<Mutex<HashSet<(u64, u64)>>>;
+
+/// Build the walker for `path`, `depth` levels within the source
+/// tree at `tree`; these differ when following a link within the
+/// tree. This applies `Config::filter`, `Config::file_types`, the size
+/// and age predicates, the traversal limits and
+/// `Config::dereference`. Directories already in `visited`, e.g. when
+/// reached by more than one link, are skipped.
+///
+/// With `Config::gitignore` ignore files are honoured as git does,
+/// via [SourceIgnores]; files tracked in the index are walked even if
+/// they, or a directory above them, are ignored. `.git` directories
+/// are skipped.
+pub(crate) fn source_walker(path: &Path, tree: &Path, depth: usize, config: &Config, visited: &Visited) -> Result<Walk> {
+    let gitignore = config.gitignore;
+    let ignores = gitignore.then(|| Mutex::new(SourceIgnores::new(config)));
+    // Ignored directories walked for the tracked files within them.
+    let ignored_dirs = Mutex::new(HashSet::new());
+    let filter = config.filter.clone();
+    let selection = Selection::new(config);
+    let min_depth = config.min_depth;
+    let skip_unsafe = config.unsafe_links == UnsafeLinks::Skip && config.dereference != Dereference::Always;
+    let tree = tree.to_path_buf();
+    let visited = visited.clone();
+
+    // Links are always followed at the root of a walk, so don't
+    // descend into one unless it should be.
+    let max_depth = if depth == 0 && config.dereference == Dereference::Never && path.is_symlink() {
+        Some(0)
     } else {
-        None
+        config.max_depth.map(|max| max.saturating_sub(depth))
+    };
+
+    let mut builder = WalkBuilder::new(path);
+    builder
+        .standard_filters(false)
+        .same_file_system(config.one_file_system)
+        .follow_links(config.dereference == Dereference::Always)
+        .max_depth(max_depth)
+        .filter_entry(move |entry| {
+            let is_dir = entry.file_type().is_some_and(|ft| ft.is_dir());
+            if gitignore && is_dir && entry.depth() > 0 && entry.file_name() == ".git" {
+                return false;
+            }
+            if let Some(ignores) = &ignores
+                && entry.depth() > 0
+            {
+                let mut ignores = ignores.lock().unwrap();
+                let mut ignored_dirs = ignored_dirs.lock().unwrap();
+                let in_ignored = entry.path().parent().is_some_and(|p| ignored_dirs.contains(p));
+                if in_ignored || ignores.is_ignored(entry.path(), is_dir) {
+                    if !ignores.is_tracked(entry.path(), is_dir) {
+                        debug!("Ignored: {:?}", entry.path());
+                        return false;
+                    }
+                    if is_dir {
+                        ignored_dirs.insert(entry.path().to_path_buf());
+                    }
+                }
+            }
+            if skip_unsafe && entry.path_is_symlink() && is_unsafe_link(entry.path(), &tree) {
+                info!("Skipping unsafe symlink {:?}", entry.path());
+                return false;
+            }
+            if !is_dir && min_depth.is_some_and(|d| entry.depth() + depth < d) {
+                debug!("Above minimum depth: {:?}", entry.path());
+                return false;
+            }
+            if !selection.is_empty()
+                && let Ok(meta) = entry.metadata()
+                && !selection.selects(&meta)
+            {
+                debug!("Not selected by size or age: {:?}", entry.path());
+                return false;
+            }
+            if filter_excluded(entry.path(), is_dir, &tree, &filter) {
+                return false;
+            }
+            // Only directories that will be copied are recorded.
+            if is_dir
+                && let Ok(meta) = entry.metadata()
+                && !visited.lock().unwrap().insert((meta.dev(), meta.ino()))
+            {
+                warn!("Skipping directory already copied: {:?}", entry.path());
+                return false;
+            }
+            true
+        });
+    if gitignore {
+        info!("Using ignore files for {path:?}");
+    }
+    if let Some(types) = selected_types(config)? {
+        builder.types(types);
+    }
+    Ok(builder.build())
+}
+
+fn selected_types(config: &Config) -> Result<Option<Types>> {
+    if config.file_types.is_empty() {
+        return Ok(None);
+    }
+    let mut types = TypesBuilder::new();
+    types.add_defaults();
+    for name in &config.file_types {
+        types.select(name);
+    }
+    Ok(Some(types.build()?))
+}
+
+/// Checks entries against `Config::file_types`, the size and age
+/// predicates and the depth limits, as applied by [source_walker()].
+/// Unlike the walker the entries need not be in the source.
+pub(crate) struct SourceSelection {
+    types: Option<Types>,
+    selection: Selection,
+    min_depth: Option<usize>,
+    max_depth: Option<usize>,
+}
+
+impl SourceSelection {
+    pub(crate) fn new(config: &Config) -> Result<SourceSelection> {
+        Ok(SourceSelection {
+            types: selected_types(config)?,
+            selection: Selection::new(config),
+            min_depth: config.min_depth,
+            max_depth: config.max_depth,
+        })
+    }
+
+    /// Whether any entries may be left unselected.
+    pub(crate) fn is_restricted(&self) -> bool {
+        self.types.is_some()
+            || !self.selection.is_empty()
+            || self.min_depth.is_some()
+            || self.max_depth.is_some()
+    }
+
+    /// Whether the entry at `path`, `depth` levels below a source root,
+    /// would be selected.
+    pub(crate) fn selects(&self, path: &Path, depth: usize, meta: &Metadata) -> bool {
+        if self.max_depth.is_some_and(|max| depth > max) {
+            return false;
+        }
+        if meta.is_dir() {
+            return true;
+        }
+        !(self.min_depth.is_some_and(|min| depth < min)
+            || self.types.as_ref().is_some_and(|t| t.matched(path, false).is_ignore())
+            || !self.selection.selects(meta))
+    }
+}
+
+/// Whether the symlink at `link` points outside of the tree at `root`,
+/// either as an absolute path or with more `..` components than it
+/// is deep in the tree. As with `rsync` this only checks the link
+/// text, not any further links.
+pub(crate) fn is_unsafe_link(link: &Path, root: &Path) -> bool {
+    let Ok(target) = read_link(link) else {
+        return false;
+    };
+    let Ok(rel) = link.strip_prefix(root) else {
+        return true;
     };
-    Ok(gitignore)
+    // The depth of the link's directory within the tree.
+    let mut depth = rel.components().count() as isize - 1;
+    for component in target.components() {
+        match component {
+            Component::Prefix(_) | Component::RootDir => return true,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return true;
+                }
+            }
+            Component::Normal(_) => depth += 1,
+        }
+    }
+    false
 }
 
-/// Filter to return whether a given file should be ignored by a
-/// filter file.
-pub fn ignore_filter(entry: &DirEntry, ignore: &Option<Gitignore>) -> bool {
-    match ignore {
-        None => true,
-        Some(gi) => {
-            let path = entry.path();
-            let m = gi.matched(path, path.is_dir());
-            !m.is_ignore()
+fn filter_excluded(path: &Path, is_dir: bool, root: &Path, filter: &Filter) -> bool {
+    if filter.is_empty() {
+        return false;
+    }
+    let rel = path.strip_prefix(root).unwrap_or(path);
+    let excluded = filter.is_excluded(rel, is_dir);
+    if excluded {
+        debug!("Excluded by filter: {path:?}");
+    }
+    excluded
+}
+
+/// The path a walker error relates to, if known.
+pub(crate) fn walk_error_path(err: &anyhow::Error) -> Option<&Path> {
+    fn path_of(err: &ignore::Error) -> Option<&Path> {
+        match err {
+            ignore::Error::WithPath { path, .. } => Some(path),
+            ignore::Error::Loop { child, .. } => Some(child),
+            ignore::Error::WithDepth { err, .. }
+            | ignore::Error::WithLineNumber { err, .. } => path_of(err),
+            ignore::Error::Partial(errs) => errs.iter().find_map(path_of),
+            _ => None,
+        }
+    }
+    err.downcast_ref::<ignore::Error>().and_then(path_of)
+}
+
+// The ignore files found in a single directory.
+struct DirIgnores {
+    custom: Gitignore,
+    ignore: Gitignore,
+    git: Gitignore,
+    exclude: Gitignore,
+    has_git: bool,
+}
+
+impl DirIgnores {
+    fn load(dir: &Path) -> DirIgnores {
+        let load = |file: PathBuf| {
+            if !file.is_file() {
+                return Gitignore::empty();
+            }
+            let (gi, err) = Gitignore::new(&file);
+            if let Some(e) = err {
+                warn!("Error reading ignore file {file:?}: {e}");
+            }
+            gi
+        };
+        let git_dir = dir.join(".git");
+        let has_git = git_dir.exists();
+        DirIgnores {
+            custom: load(dir.join(XCPIGNORE)),
+            ignore: load(dir.join(".ignore")),
+            git: load(dir.join(".gitignore")),
+            exclude: if has_git { load(git_dir.join("info/exclude")) } else { Gitignore::empty() },
+            has_git,
+        }
+    }
+}
+
+/// Matches paths against the ignore files of source trees, as used by
+/// [source_walker()]; `.gitignore` files in the path's directory and
+/// its parents up to the repository root, `.git/info/exclude` and the
+/// global `core.excludesFile`. `.ignore` and [XCPIGNORE] files in all
+/// parent directories are also honoured, taking precedence in that
+/// order. Outside of a repository `.gitignore` files in all parent
+/// directories apply. The paths need not exist.
+pub(crate) struct SourceIgnores {
+    enabled: bool,
+    global: Gitignore,
+    dirs: HashMap<PathBuf, DirIgnores>,
+    // The tracked files of each repository, by its root.
+    tracked: HashMap<PathBuf, BTreeSet<PathBuf>>,
+}
+
+impl SourceIgnores {
+    pub(crate) fn new(config: &Config) -> SourceIgnores {
+        let global = if config.gitignore {
+            Gitignore::global().0
+        } else {
+            Gitignore::empty()
+        };
+        SourceIgnores {
+            enabled: config.gitignore,
+            global,
+            dirs: HashMap::new(),
+            tracked: HashMap::new(),
+        }
+    }
+
+    fn dir(&mut self, dir: &Path) -> &DirIgnores {
+        self.dirs.entry(dir.to_path_buf())
+            .or_insert_with(|| DirIgnores::load(dir))
+    }
+
+    /// Whether `path` is tracked by its git repository, or for a
+    /// directory whether any files under it are. As with git, tracked
+    /// files are not ignored.
+    pub(crate) fn is_tracked(&mut self, path: &Path, is_dir: bool) -> bool {
+        let Ok(path) = absolute(path) else {
+            return false;
+        };
+        let Some(root) = path.ancestors().skip(1).find(|dir| self.dir(dir).has_git) else {
+            return false;
+        };
+        let tracked = self.tracked.entry(root.to_path_buf())
+            .or_insert_with(|| {
+                tracked_files(root).unwrap_or_else(|e| {
+                    warn!("Failed to read the git index in {root:?}: {e}");
+                    BTreeSet::new()
+                })
+            });
+        if is_dir {
+            tracked.range::<Path, _>((Bound::Included(path.as_path()), Bound::Unbounded))
+                .next()
+                .is_some_and(|p| p.starts_with(&path))
+        } else {
+            tracked.contains(&path)
+        }
+    }
+
+    /// Whether `path` is ignored. Only the path itself is checked, not
+    /// its parent directories.
+    pub(crate) fn is_ignored(&mut self, path: &Path, is_dir: bool) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        if is_dir && path.file_name().is_some_and(|n| n == ".git") {
+            return true;
+        }
+        let Ok(path) = absolute(path) else {
+            return false;
+        };
+
+        // Within each type the closest ignore file wins. Git ignores
+        // stop at the repository root.
+        let matched = |gi: &Gitignore| gi.matched(&path, is_dir).map(|_| ());
+        let (mut custom, mut ignore, mut git, mut exclude) = (Match::None, Match::None, Match::None, Match::None);
+        let mut saw_git = false;
+        for dir in path.ancestors().skip(1) {
+            let ig = self.dir(dir);
+            if custom.is_none() {
+                custom = matched(&ig.custom);
+            }
+            if ignore.is_none() {
+                ignore = matched(&ig.ignore);
+            }
+            if !saw_git {
+                if git.is_none() {
+                    git = matched(&ig.git);
+                }
+                if exclude.is_none() {
+                    exclude = matched(&ig.exclude);
+                }
+            }
+            saw_git |= ig.has_git;
         }
+        custom
+            .or(ignore)
+            .or(git)
+            .or(exclude)
+            .or(matched(&self.global))
+            .is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write, File};
+    use std::os::unix::fs::symlink;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn excluded(filter: &Filter, path: &str, is_dir: bool) -> bool {
+        filter.is_excluded(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn test_filter_patterns() -> Result<()> {
+        let filter = Filter::new()
+            .with(FilterRule::exclude("*.o")?)
+            .with(FilterRule::exclude("/build")?)
+            .with(FilterRule::exclude("cache/")?)
+            .with(FilterRule::exclude("docs/*.tmp")?);
+
+        assert!(excluded(&filter, "main.o", false));
+        assert!(excluded(&filter, "src/deep/main.o", false));
+        assert!(!excluded(&filter, "main.c", false));
+
+        assert!(excluded(&filter, "build", true));
+        assert!(!excluded(&filter, "src/build", true));
+
+        assert!(excluded(&filter, "src/cache", true));
+        assert!(!excluded(&filter, "src/cache", false));
+
+        assert!(excluded(&filter, "docs/a.tmp", false));
+        assert!(excluded(&filter, "src/docs/a.tmp", false));
+        assert!(!excluded(&filter, "docs/sub/a.tmp", false));
+
+        assert!(!excluded(&filter, "", true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_filter_order() -> Result<()> {
+        let filter = Filter::new()
+            .with("+ */".parse()?)
+            .with("include *.rs".parse()?)
+            .with("- *".parse()?);
+
+        assert!(!excluded(&filter, "src", true));
+        assert!(!excluded(&filter, "src/lib.rs", false));
+        assert!(excluded(&filter, "src/lib.c", false));
+
+        assert!("? foo".parse::<FilterRule>().is_err());
+        assert!("foo".parse::<FilterRule>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_excludes() -> Result<()> {
+        let dir = TempDir::new()?;
+        let file = dir.path().join("excludes");
+        write(&file, "# Comment\n*.o\n\n; Other comment\n/target/\n")?;
+
+        let filter = Filter::read_excludes(&file)?;
+        assert_eq!(2, filter.rules().len());
+        assert!(excluded(&filter, "main.o", false));
+        assert!(excluded(&filter, "target", true));
+        Ok(())
+    }
+
+    #[test]
+    fn test_unsafe_links() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let root = tdir.path().join("root");
+        create_dir_all(root.join("a/b"))?;
+        let unsafe_link = |link: &str, target: &str| -> Result<bool> {
+            let path = root.join(link);
+            symlink(target, &path)?;
+            Ok(is_unsafe_link(&path, &root))
+        };
+
+        assert!(!unsafe_link("a/b/file", "file")?);
+        assert!(!unsafe_link("a/b/up", "../..")?);
+        assert!(!unsafe_link("a/down", "b/../../a")?);
+        assert!(unsafe_link("a/b/out", "../../..")?);
+        assert!(unsafe_link("a/sneaky", "b/../../../root")?);
+        assert!(unsafe_link("abs", "/etc/hosts")?);
+        assert!(!is_unsafe_link(&root.join("a"), &root));
+        Ok(())
+    }
+
+    #[test]
+    fn test_selection() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let file = tdir.path().join("file.txt");
+        write(&file, "12345")?;
+        let hour = Duration::from_secs(3600);
+        let mtime = SystemTime::now() - hour * 24;
+        File::options().write(true).open(&file)?.set_modified(mtime)?;
+        let meta = file.metadata()?;
+
+        let selects = |config: Config| Selection::new(&config).selects(&meta);
+        assert!(selects(Config::default()));
+        assert!(selects(Config { min_size: Some(5), max_size: Some(5), ..Config::default() }));
+        assert!(!selects(Config { min_size: Some(6), ..Config::default() }));
+        assert!(!selects(Config { max_size: Some(4), ..Config::default() }));
+        assert!(selects(Config { newer_than: Some(mtime - hour), older_than: Some(mtime + hour), ..Config::default() }));
+        assert!(!selects(Config { newer_than: Some(mtime + hour), ..Config::default() }));
+        assert!(!selects(Config { older_than: Some(mtime - hour), ..Config::default() }));
+
+        let link = tdir.path().join("link");
+        symlink("file.txt", &link)?;
+        let config = Config { min_size: Some(6), ..Config::default() };
+        assert!(Selection::new(&config).selects(&link.symlink_metadata()?));
+        assert!(!Selection::new(&config).selects(&link.metadata()?));
+        Ok(())
     }
 }
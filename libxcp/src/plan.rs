@@ -0,0 +1,677 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Planning of copy operations.
+//!
+//! Walking the source trees and deciding what to do with each entry
+//! is separate from performing the copy. The drivers consume the
+//! plan as it is generated, but it can also be collected into a
+//! [CopyPlan] for inspection before copying; e.g:
+//!
+//!     # use std::path::{Path, PathBuf};
+//!     use libxcp::config::Config;
+//!     use libxcp::plan::CopyPlan;
+//!
+//!     # fn main() -> libxcp::errors::Result<()> {
+//!     # let tdir = tempfile::TempDir::new()?;
+//!     # let source = tdir.path().join("source.txt");
+//!     # std::fs::write(&source, "data")?;
+//!     # let dest = tdir.path().join("dest.txt");
+//!     let plan = CopyPlan::new(vec![source], &dest, &Config::default())?;
+//!     for planned in &plan.operations {
+//!         println!("{:?}", planned.operation);
+//!     }
+//!     println!("{} bytes to copy", plan.total_bytes);
+//!     # assert_eq!(4, plan.total_bytes);
+//!     # Ok(())
+//!     # }
+
+use std::collections::VecDeque;
+use std::fs::{canonicalize, File, Metadata};
+use std::iter;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::vec;
+
+use libfs::FileType;
+use log::{debug, warn};
+use ignore::{DirEntry, Walk};
+
+use crate::backup::{get_backup_path, needs_backup};
+use crate::checksum::hash_file;
+use crate::config::{Config, Dereference, UnsafeLinks, Update};
+use crate::errors::{Result, XcpError};
+use crate::journal::Journal;
+use crate::links::LinkTracker;
+use crate::paths::{is_unsafe_link, source_walker, Visited};
+use crate::throttle::Throttle;
+
+pub use crate::operations::Operation;
+
+/// A conflict with an existing target.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Conflict {
+    /// The target exists and will be replaced.
+    Overwrite,
+    /// The target exists and `no_clobber` is set; the copy will fail.
+    NoClobber,
+}
+
+/// The reason an operation is not needed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+pub enum Skip {
+    /// The target was completed by a previous run (see
+    /// `Config::resume`).
+    Completed,
+    /// The target is up to date (see `Config::update`).
+    UpToDate,
+}
+
+/// A single operation within a plan.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct PlannedOperation {
+    pub operation: Operation,
+    /// The source entry. This differs from the operation source for
+    /// hard-links.
+    pub source: PathBuf,
+    /// The number of bytes to copy; only set for regular files.
+    pub size: u64,
+    /// Any conflict with an existing target.
+    pub conflict: Option<Conflict>,
+    /// If set the operation will not be performed.
+    pub skip: Option<Skip>,
+    /// The backup that will be made of the existing target, if any.
+    pub backup: Option<PathBuf>,
+}
+
+/// The full set of operations for a copy, and their totals.
+#[derive(Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct CopyPlan {
+    pub operations: Vec<PlannedOperation>,
+    /// Total bytes to be copied, excluding skipped files.
+    pub total_bytes: u64,
+    // Directories to mirror when the plan is executed.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub(crate) mirrors: Vec<(PathBuf, PathBuf)>,
+}
+
+impl CopyPlan {
+    /// Plan the copy of `sources` to `dest`. No changes are made to
+    /// the filesystem. Note that as no directories are created the
+    /// plan may differ from the final copy if multiple sources are
+    /// given and `dest` does not exist.
+    pub fn new(sources: Vec<PathBuf>, dest: &Path, config: &Config) -> Result<CopyPlan> {
+        let journal = match &config.journal {
+            Some(path) if config.resume && path.exists() => Some(Journal::read_only(path)?),
+            _ => None,
+        };
+        let links = LinkTracker::default();
+        let throttle = Throttle::new(config);
+
+        let mut planner = Planner::new(sources, dest, config, journal.as_ref(), &links, &throttle);
+        let operations = planner.by_ref()
+            .collect::<Result<Vec<PlannedOperation>>>()?;
+        let mirrors = planner.into_mirrors();
+        let total_bytes = operations.iter()
+            .filter(|p| p.skip.is_none())
+            .map(|p| p.size)
+            .sum();
+
+        Ok(CopyPlan {
+            operations,
+            total_bytes,
+            mirrors,
+        })
+    }
+
+    /// Operations that conflict with existing targets.
+    pub fn conflicts(&self) -> impl Iterator<Item = &PlannedOperation> {
+        self.operations.iter()
+            .filter(|p| p.conflict.is_some())
+    }
+}
+
+struct Root {
+    target_base: PathBuf,
+    source: PathBuf,
+    // The source tree this is in; differs from `source` when walking
+    // the target of a link.
+    tree: PathBuf,
+    depth: usize,
+    // The device and inode of the source directory.
+    id: Option<(u64, u64)>,
+    entries: Walk,
+}
+
+/// Generates the operations for a copy, walking the sources lazily.
+pub(crate) struct Planner<'a> {
+    config: &'a Config,
+    journal: Option<&'a Journal>,
+    links: &'a LinkTracker,
+    throttle: &'a Throttle,
+    dest: PathBuf,
+    sources: vec::IntoIter<PathBuf>,
+    current: Option<Root>,
+    // Roots suspended while walking the targets of links within them.
+    suspended: Vec<Root>,
+    mirrors: Vec<(PathBuf, PathBuf)>,
+    // Directories held back by `prune_empty_dirs`, outermost first.
+    pending: Vec<PlannedOperation>,
+    visited: Visited,
+    ready: VecDeque<PlannedOperation>,
+}
+
+impl<'a> Planner<'a> {
+    pub fn new(
+        sources: Vec<PathBuf>,
+        dest: &Path,
+        config: &'a Config,
+        journal: Option<&'a Journal>,
+        links: &'a LinkTracker,
+        throttle: &'a Throttle,
+    ) -> Planner<'a> {
+        Planner {
+            config,
+            journal,
+            links,
+            throttle,
+            dest: dest.to_path_buf(),
+            sources: sources.into_iter(),
+            current: None,
+            suspended: Vec::new(),
+            mirrors: Vec::new(),
+            pending: Vec::new(),
+            ready: VecDeque::new(),
+            visited: Visited::default(),
+        }
+    }
+
+    /// The source and target directory pairs to mirror, if
+    /// `Config::delete` is set. Only complete once the planner is
+    /// exhausted.
+    pub fn into_mirrors(self) -> Vec<(PathBuf, PathBuf)> {
+        self.mirrors
+    }
+
+    fn start_root(&mut self, source: PathBuf) -> Result<Root> {
+        let config = self.config;
+        let sourcedir = source
+            .components()
+            .next_back()
+            .ok_or(XcpError::InvalidSource("Failed to find source directory name."))?;
+
+        let target_base = if self.dest.exists() && self.dest.is_dir() && !config.no_target_directory {
+            self.dest.join(sourcedir)
+        } else {
+            self.dest.clone()
+        };
+        debug!("Target base is {target_base:?}");
+
+        if config.delete && source.is_dir() {
+            self.mirrors.push((source.clone(), target_base.clone()));
+        }
+
+        let id = dev_ino(&source);
+        if let Some(id) = id
+            && source.is_dir()
+        {
+            self.visited.lock().unwrap().insert(id);
+        }
+        let entries = source_walker(&source, &source, 0, config, &self.visited)?;
+
+        Ok(Root {
+            target_base,
+            id,
+            tree: source.clone(),
+            source,
+            depth: 0,
+            entries,
+        })
+    }
+
+    /// Walk the directory a link points to in place of the link, for
+    /// `UnsafeLinks::Copy`. Returns `None` if the directory has
+    /// already been walked.
+    fn start_link_root(&self, root: &Root, entry: &DirEntry) -> Result<Option<Root>> {
+        let link = entry.path();
+        let id = dev_ino(link);
+        // Links to a parent directory, or back to a directory being
+        // walked, would recurse forever.
+        let dir = canonicalize(link)?;
+        let parent = canonicalize(link.parent().unwrap_or(link))?;
+        if parent.starts_with(&dir)
+            || iter::once(root).chain(&self.suspended).any(|r| r.id.is_some() && r.id == id)
+        {
+            return Err(ignore::Error::Loop {
+                ancestor: dir,
+                child: link.to_path_buf(),
+            }.into());
+        }
+
+        if let Some(id) = id
+            && !self.visited.lock().unwrap().insert(id)
+        {
+            warn!("Skipping directory already copied: {link:?}");
+            return Ok(None);
+        }
+
+        let depth = root.depth + entry.depth();
+        debug!("Copying directory linked to by {link:?}");
+        Ok(Some(Root {
+            target_base: root.target_base.join(link.strip_prefix(&root.source)?),
+            source: link.to_path_buf(),
+            tree: root.tree.clone(),
+            depth,
+            id,
+            entries: source_walker(link, &root.tree, depth, self.config, &self.visited)?,
+        }))
+    }
+
+    /// Whether an entry is a symlink that should be dereferenced.
+    fn follows(&self, root: &Root, entry: &DirEntry) -> bool {
+        if !entry.path_is_symlink() {
+            return false;
+        }
+        match self.config.dereference {
+            Dereference::Always => true,
+            _ if entry.depth() == 0 => root.depth > 0 || self.config.dereference == Dereference::CommandLine,
+            _ => self.config.unsafe_links == UnsafeLinks::Copy && is_unsafe_link(entry.path(), &root.tree),
+        }
+    }
+
+    /// Hold back directories until something is created in them, for
+    /// `prune_empty_dirs`.
+    fn prune_empty(&mut self, planned: PlannedOperation) {
+        // Pending directories this isn't under were empty.
+        while let Some(dir) = self.pending.last()
+            && !planned.source.starts_with(&dir.source)
+        {
+            debug!("Pruning empty directory {:?}", dir.source);
+            self.pending.pop();
+        }
+        if let Operation::CreateDir(..) = planned.operation {
+            self.pending.push(planned);
+        } else {
+            self.ready.extend(self.pending.drain(..));
+            self.ready.push_back(planned);
+        }
+    }
+
+    fn plan_entry(&self, root: &Root, entry: DirEntry) -> Result<PlannedOperation> {
+        let config = self.config;
+        debug!("Got tree entry {entry:?}");
+        let follow = self.follows(root, &entry);
+        let from = entry.into_path();
+        let meta = if follow {
+            debug!("Dereferencing {from:?}");
+            from.metadata()?
+        } else {
+            from.symlink_metadata()?
+        };
+        let path = from.strip_prefix(&root.source)?;
+        let target = if !empty_path(path) {
+            root.target_base.join(path)
+        } else {
+            root.target_base.clone()
+        };
+
+        let ft = FileType::from(meta.file_type());
+        let (operation, size) = match ft {
+            FileType::File => {
+                let first = if config.preserve.links && meta.nlink() > 1 {
+                    self.links.check(&meta, &target)
+                } else {
+                    None
+                };
+                match first {
+                    Some(first) => (Operation::HardLink(first, target.clone()), 0),
+                    None => (Operation::Copy(from.clone(), target.clone()), meta.len()),
+                }
+            }
+            FileType::Symlink => (Operation::Link(from.clone(), target.clone()), 0),
+            FileType::Dir => (Operation::CreateDir(from.clone(), target.clone()), 0),
+            FileType::Socket | FileType::Char | FileType::Fifo => {
+                (Operation::Special(from.clone(), target.clone()), 0)
+            }
+            FileType::Block | FileType::Other => {
+                return Err(XcpError::UnknownFileType(target).into());
+            }
+        };
+
+        let mut planned = PlannedOperation {
+            operation,
+            source: from.clone(),
+            size,
+            conflict: None,
+            skip: None,
+            backup: None,
+        };
+        if target.symlink_metadata().is_err() {
+            return Ok(planned);
+        }
+        if meta.is_dir() {
+            // Existing directories are copied into, unless clobbering
+            // is disallowed.
+            if config.no_clobber {
+                planned.conflict = Some(Conflict::NoClobber);
+            }
+            return Ok(planned);
+        }
+
+        if let Some(journal) = self.journal
+            && journal.is_done(&target, meta.is_file().then_some(&meta))
+        {
+            planned.skip = Some(Skip::Completed);
+        } else if up_to_date(&meta, &from, &target, config, self.throttle)? {
+            planned.skip = Some(Skip::UpToDate);
+        }
+        if planned.skip.is_some() {
+            return Ok(planned);
+        }
+
+        if config.no_clobber {
+            planned.conflict = Some(Conflict::NoClobber);
+        } else {
+            planned.conflict = Some(Conflict::Overwrite);
+            if needs_backup(&target, config)? {
+                planned.backup = Some(get_backup_path(&target)?);
+            }
+        }
+        Ok(planned)
+    }
+}
+
+impl Iterator for Planner<'_> {
+    type Item = Result<PlannedOperation>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(planned) = self.ready.pop_front() {
+                return Some(Ok(planned));
+            }
+            let mut root = match self.current.take().or_else(|| self.suspended.pop()) {
+                Some(root) => root,
+                None => {
+                    self.pending.clear();
+                    let source = self.sources.next()?;
+                    match self.start_root(source) {
+                        Ok(root) => root,
+                        Err(e) => return Some(Err(e)),
+                    }
+                }
+            };
+            match root.entries.next() {
+                // A link to be copied as a directory; see `follows()`.
+                Some(Ok(entry)) if entry.depth() > 0
+                    && self.config.dereference != Dereference::Always
+                    && self.follows(&root, &entry)
+                    && entry.path().is_dir() =>
+                {
+                    match self.start_link_root(&root, &entry) {
+                        Ok(Some(linked)) => {
+                            self.suspended.push(root);
+                            self.current = Some(linked);
+                        }
+                        Ok(None) => self.current = Some(root),
+                        Err(e) => {
+                            self.current = Some(root);
+                            return Some(Err(e));
+                        }
+                    }
+                }
+                Some(entry) => {
+                    let planned = entry
+                        .map_err(|e| e.into())
+                        .and_then(|e| self.plan_entry(&root, e));
+                    self.current = Some(root);
+                    match planned {
+                        Ok(planned) if self.config.prune_empty_dirs => self.prune_empty(planned),
+                        _ => return Some(planned),
+                    }
+                }
+                None => continue,
+            }
+        }
+    }
+}
+
+/// Whether an existing target should be left in place under the
+/// configured update mode.
+fn up_to_date(meta: &Metadata, from: &Path, target: &Path, config: &Config, throttle: &Throttle) -> Result<bool> {
+    let Ok(tmeta) = target.symlink_metadata() else {
+        return Ok(false);
+    };
+    match config.update {
+        Update::All => Ok(false),
+        Update::None => Ok(!tmeta.is_dir()),
+        Update::Older => {
+            if !meta.is_file() || !tmeta.is_file() || meta.len() != tmeta.len() {
+                Ok(false)
+            } else if config.checksum {
+                Ok(hash_file(&File::open(from)?, throttle)? == hash_file(&File::open(target)?, throttle)?)
+            } else {
+                Ok(meta.modified()? <= tmeta.modified()? + config.modify_window)
+            }
+        }
+    }
+}
+
+fn dev_ino(path: &Path) -> Option<(u64, u64)> {
+    path.metadata().ok().map(|m| (m.dev(), m.ino()))
+}
+
+fn empty_path(path: &Path) -> bool {
+    *path == PathBuf::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, hard_link, write};
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    use crate::config::{Backup, Preserve};
+    use crate::paths::{Filter, FilterRule, XCPIGNORE};
+
+    #[test]
+    fn test_plan_tree() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        let dest = tdir.path().join("dest");
+        create_dir_all(source.join("sub"))?;
+        write(source.join("file.txt"), "12345")?;
+        write(source.join("sub/other.txt"), "123")?;
+        hard_link(source.join("file.txt"), source.join("link.txt"))?;
+        symlink("file.txt", source.join("symlink.txt"))?;
+
+        let config = Config {
+            preserve: Preserve::default().with(Preserve { links: true, ..Preserve::none() }),
+            ..Config::default()
+        };
+        let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+
+        assert_eq!(6, plan.operations.len());
+        assert_eq!(8, plan.total_bytes);
+        assert!(matches!(&plan.operations[0].operation, Operation::CreateDir(f, t) if *f == source && *t == dest));
+        let ops = plan.operations.iter()
+            .map(|p| &p.operation)
+            .collect::<Vec<_>>();
+        assert_eq!(1, ops.iter().filter(|o| matches!(o, Operation::HardLink(..))).count());
+        assert_eq!(2, ops.iter().filter(|o| matches!(o, Operation::Copy(..))).count());
+        assert!(ops.iter().any(|o| matches!(o, Operation::Link(f, _) if *f == source.join("symlink.txt"))));
+        assert_eq!(0, plan.conflicts().count());
+        // Nothing was created
+        assert!(!dest.exists());
+
+        // Links back up existing targets as copies do.
+        let target = dest.join("source");
+        create_dir_all(&target)?;
+        write(target.join("file.txt"), "old")?;
+        write(target.join("link.txt"), "old")?;
+        let config = Config { backup: Backup::Numbered, ..config };
+        let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+        let mut backups = plan.operations.iter()
+            .filter_map(|p| p.backup.clone())
+            .collect::<Vec<_>>();
+        backups.sort();
+        assert_eq!(vec![target.join("file.txt.~1~"), target.join("link.txt.~1~")], backups);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_filter() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        let dest = tdir.path().join("dest");
+        create_dir_all(source.join("src/empty"))?;
+        create_dir_all(source.join("objs"))?;
+        write(source.join("src/main.rs"), "fn main() {}")?;
+        write(source.join("src/main.o"), "1234")?;
+        write(source.join("objs/lib.o"), "1234")?;
+
+        let targets = |prune_empty_dirs| -> Result<Vec<PathBuf>> {
+            let config = Config {
+                filter: Filter::new().with(FilterRule::exclude("*.o")?),
+                prune_empty_dirs,
+                ..Config::default()
+            };
+            let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+            Ok(plan.operations.iter()
+                .map(|p| p.operation.target().strip_prefix(&dest).unwrap().to_path_buf())
+                .collect())
+        };
+
+        let all = targets(false)?;
+        assert_eq!(5, all.len());
+        assert!(all.contains(&PathBuf::from("objs")));
+        assert!(!all.iter().any(|p| p.extension().is_some_and(|e| e == "o")));
+
+        let pruned = targets(true)?;
+        let expected = ["", "src", "src/main.rs"].map(PathBuf::from);
+        assert_eq!(expected.as_slice(), pruned.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_gitignore() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let repo = tdir.path().join("repo");
+        let source = repo.join("proj");
+        let dest = tdir.path().join("dest");
+        create_dir_all(repo.join(".git/info"))?;
+        create_dir_all(source.join("sub/build"))?;
+        write(repo.join(".git/info/exclude"), "local.txt\n")?;
+        // Parent directories within the repository are honoured.
+        write(repo.join(".gitignore"), "*.log\n!keep.log\n")?;
+        write(source.join(XCPIGNORE), "!important.log\n")?;
+        write(source.join("sub/.gitignore"), "build/\n")?;
+        write(source.join("sub/.ignore"), "secret.txt\n")?;
+        for file in ["keep.log", "a.log", "important.log", "local.txt", "sub/build/out.bin", "sub/secret.txt", "sub/main.rs"] {
+            write(source.join(file), "1234")?;
+        }
+
+        let config = Config { gitignore: true, ..Config::default() };
+        let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+        let mut targets = plan.operations.iter()
+            .map(|p| p.operation.target().strip_prefix(&dest).unwrap().to_path_buf())
+            .collect::<Vec<_>>();
+        targets.sort();
+
+        let expected = ["", XCPIGNORE, "important.log", "keep.log", "sub", "sub/.gitignore", "sub/.ignore", "sub/main.rs"]
+            .map(PathBuf::from);
+        assert_eq!(expected.as_slice(), targets.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_depth() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source");
+        let dest = tdir.path().join("dest");
+        create_dir_all(source.join("a/b/c"))?;
+        for file in ["top.txt", "a/one.txt", "a/b/two.txt", "a/b/c/three.txt"] {
+            write(source.join(file), "1234")?;
+        }
+
+        let targets = |min_depth, max_depth| -> Result<Vec<PathBuf>> {
+            let config = Config {
+                min_depth,
+                max_depth,
+                one_file_system: true,
+                ..Config::default()
+            };
+            let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+            let mut targets = plan.operations.iter()
+                .map(|p| p.operation.target().strip_prefix(&dest).unwrap().to_path_buf())
+                .collect::<Vec<_>>();
+            targets.sort();
+            Ok(targets)
+        };
+
+        assert_eq!(8, targets(None, None)?.len());
+
+        let expected = ["", "a", "a/b", "a/one.txt", "top.txt"].map(PathBuf::from);
+        assert_eq!(expected.as_slice(), targets(None, Some(2))?.as_slice());
+
+        let expected = ["", "a", "a/b", "a/b/c", "a/b/two.txt", "a/one.txt"].map(PathBuf::from);
+        assert_eq!(expected.as_slice(), targets(Some(2), Some(3))?.as_slice());
+        Ok(())
+    }
+
+    #[test]
+    fn test_plan_conflicts() -> Result<()> {
+        let tdir = TempDir::new()?;
+        let source = tdir.path().join("source.txt");
+        let dest = tdir.path().join("dest.txt");
+        write(&source, "source")?;
+        write(&dest, "dest")?;
+
+        let config = Config { backup: Backup::Numbered, ..Config::default() };
+        let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+        assert_eq!(Some(Conflict::Overwrite), plan.operations[0].conflict);
+        assert_eq!(Some(tdir.path().join("dest.txt.~1~")), plan.operations[0].backup);
+
+        let config = Config { no_clobber: true, ..Config::default() };
+        let plan = CopyPlan::new(vec![source.clone()], &dest, &config)?;
+        assert_eq!(Some(Conflict::NoClobber), plan.operations[0].conflict);
+
+        let config = Config { update: Update::None, ..Config::default() };
+        let plan = CopyPlan::new(vec![source], &dest, &config)?;
+        assert_eq!(Some(Skip::UpToDate), plan.operations[0].skip);
+        assert_eq!(0, plan.total_bytes);
+
+        // Existing directories only conflict with no_clobber.
+        let source_dir = tdir.path().join("source");
+        let dest_dir = tdir.path().join("dest");
+        create_dir_all(&source_dir)?;
+        create_dir_all(dest_dir.join("source"))?;
+        let plan = CopyPlan::new(vec![source_dir.clone()], &dest_dir, &Config::default())?;
+        assert_eq!(None, plan.operations[0].conflict);
+        let config = Config { no_clobber: true, ..Config::default() };
+        let plan = CopyPlan::new(vec![source_dir], &dest_dir, &config)?;
+        assert_eq!(Some(Conflict::NoClobber), plan.operations[0].conflict);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,197 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+//! Bandwidth limiting, shared between all workers of a copy.
+
+use std::cmp;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::trace;
+
+use crate::config::Config;
+
+// The smallest chunk a throttled copy is split into.
+const MIN_CHUNK: u64 = 4096;
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket holding up to a second's worth of bytes at `rate`.
+/// Workers may take more than is available, in which case they sleep
+/// until the debt would have been refilled; later callers see the debt
+/// and wait their turn, so the aggregate rate is maintained.
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    state: Mutex<Bucket>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> TokenBucket {
+        TokenBucket {
+            rate: rate as f64,
+            state: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: Instant::now(),
+            }),
+        }
+    }
+
+    /// Take `bytes` from the bucket, returning how long the caller
+    /// should wait before using them.
+    fn take(&self, bytes: u64) -> Duration {
+        let mut bucket = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.last).as_secs_f64() * self.rate;
+        bucket.tokens = (bucket.tokens + refill).min(self.rate) - bytes as f64;
+        bucket.last = now;
+        if bucket.tokens < 0.0 {
+            Duration::from_secs_f64(-bucket.tokens / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// The bandwidth limits of a copy; see `Config::bwlimit` and
+/// friends. A limit of 0 is treated as unlimited.
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    copy: Option<TokenBucket>,
+    read: Option<TokenBucket>,
+    write: Option<TokenBucket>,
+    chunk: u64,
+}
+
+impl Default for Throttle {
+    /// No limits.
+    fn default() -> Throttle {
+        Throttle {
+            copy: None,
+            read: None,
+            write: None,
+            chunk: u64::MAX,
+        }
+    }
+}
+
+impl Throttle {
+    pub(crate) fn new(config: &Config) -> Throttle {
+        let rates = [config.bwlimit, config.read_bwlimit, config.write_bwlimit]
+            .map(|r| r.filter(|r| *r > 0));
+        // Split copies into chunks of a quarter-second or so at the
+        // lowest rate, so they are spread evenly over time.
+        let chunk = rates.iter().flatten().min()
+            .map(|r| cmp::max(r / 4, MIN_CHUNK))
+            .unwrap_or(u64::MAX);
+        let [copy, read, write] = rates.map(|r| r.map(TokenBucket::new));
+        Throttle {
+            copy,
+            read,
+            write,
+            chunk,
+        }
+    }
+
+    /// The size of chunk to copy between calls to `acquire()`.
+    pub(crate) fn chunk_size(&self, block_size: u64) -> u64 {
+        cmp::min(block_size, self.chunk)
+    }
+
+    /// Block until `bytes` may be copied. As each chunk is read from
+    /// the source and written to the target this draws from all the
+    /// limits.
+    pub(crate) fn acquire(&self, bytes: u64) {
+        wait(&[&self.copy, &self.read, &self.write], bytes);
+    }
+
+    /// Account for `bytes` read other than as part of a copy, e.g. to
+    /// verify or checksum a file, blocking if over the read limit.
+    pub(crate) fn acquire_read(&self, bytes: u64) {
+        wait(&[&self.read], bytes);
+    }
+}
+
+fn wait(limits: &[&Option<TokenBucket>], bytes: u64) {
+    let wait = limits.iter()
+        .filter_map(|b| b.as_ref())
+        .map(|b| b.take(bytes))
+        .max()
+        .unwrap_or(Duration::ZERO);
+    if !wait.is_zero() {
+        trace!("Throttling for {wait:?}");
+        thread::sleep(wait);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlimited() {
+        let throttle = Throttle::new(&Config::default());
+        assert_eq!(1024, throttle.chunk_size(1024));
+        let start = Instant::now();
+        throttle.acquire(u64::MAX);
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_rate() {
+        let config = Config {
+            bwlimit: Some(100_000),
+            write_bwlimit: Some(40_000),
+            ..Config::default()
+        };
+        let throttle = Throttle::new(&config);
+        assert_eq!(10_000, throttle.chunk_size(u64::MAX));
+
+        // 20KB at the lower limit of 40KB/s.
+        let start = Instant::now();
+        thread::scope(|s| {
+            s.spawn(|| throttle.acquire(10_000));
+            s.spawn(|| throttle.acquire(10_000));
+        });
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1000), "{elapsed:?}");
+    }
+
+    #[test]
+    fn test_read_only() {
+        let config = Config {
+            read_bwlimit: Some(40_000),
+            write_bwlimit: Some(40_000),
+            ..Config::default()
+        };
+        let throttle = Throttle::new(&config);
+
+        // Reads don't use the write limit, so 20KB takes ~500ms of
+        // read time however it is split.
+        let start = Instant::now();
+        throttle.acquire(10_000);
+        throttle.acquire_read(10_000);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(450), "{elapsed:?}");
+        assert!(elapsed < Duration::from_millis(1000), "{elapsed:?}");
+    }
+}
@@ -15,20 +15,34 @@
  */
 
 mod options;
+mod plan;
 mod progress;
 
 use std::path::PathBuf;
-use std::{result, thread};
+use std::{process, result, thread};
 use std::sync::Arc;
 
 use glob::{glob, Paths};
-use libxcp::config::{Config, Reflink};
+use libxcp::config::{Config, Reflink, Sparse};
+use libxcp::control::CopyController;
 use libxcp::drivers::load_driver;
 use libxcp::errors::{Result, XcpError};
 use libxcp::feedback::{ChannelUpdater, StatusUpdate, StatusUpdater};
+use libxcp::moves::move_files;
+use libxcp::plan::CopyPlan;
 use log::{error, info, warn};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
 use crate::options::Opts;
+use crate::progress::Progress;
+
+// Exit status when --keep-going is set and some operations failed.
+// This is rsync's partial-transfer status; clap uses 2 for usage
+// errors.
+const PARTIAL_FAILURE: i32 = 23;
+// Exit status when interrupted, as with shells.
+const INTERRUPTED: i32 = 130;
 
 fn init_logging(opts: &Opts) -> Result<()> {
     use simplelog::{ColorChoice, Config, SimpleLogger, TermLogger, TerminalMode};
@@ -75,6 +89,42 @@ fn expand_sources(source_list: &[String], opts: &Opts) -> Result<Vec<PathBuf>> {
     }
 }
 
+// Print a table of the errors collected with --keep-going.
+fn print_error_summary(errors: &[XcpError]) {
+    eprintln!("{} operations failed:", errors.len());
+    eprintln!("  {:<6} {:<40} ERROR", "ERRNO", "PATH");
+    for err in errors {
+        let (path, errno, msg) = progress::error_details(err);
+        let errno = errno.map_or("-".to_string(), |n| n.to_string());
+        let path = path.map_or("-".to_string(), |p| p.display().to_string());
+        eprintln!("  {errno:<6} {path:<40} {msg}");
+    }
+}
+
+// Interrupts cancel the copy cleanly; a second one exits
+// immediately. SIGTSTP and SIGCONT are deliberately left to job
+// control, which stops and continues every thread of the process and
+// so pauses the copy without the controller. Pausing the controller
+// as well would race with a SIGCONT that arrives before the stop.
+fn handle_signals(control: CopyController) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            match sig {
+                SIGINT | SIGTERM => {
+                    if control.is_cancelled() {
+                        process::exit(INTERRUPTED);
+                    }
+                    warn!("Interrupted, stopping copy; interrupt again to exit immediately");
+                    control.cancel();
+                }
+                _ => {}
+            }
+        }
+    });
+    Ok(())
+}
+
 fn opts_check(opts: &Opts) -> Result<()> {
     #[cfg(any(target_os = "linux", target_os = "android"))]
     if opts.reflink == Reflink::Never {
@@ -85,6 +135,10 @@ fn opts_check(opts: &Opts) -> Result<()> {
         warn!("--reflink is unsupported on Mac.");
     }
 
+    if opts.reflink == Reflink::Always && opts.sparse != Sparse::Auto {
+        return Err(XcpError::InvalidArguments("--reflink=always can only be used with --sparse=auto.".to_string()).into());
+    }
+
     if opts.no_clobber && opts.force {
         return Err(XcpError::InvalidArguments("--force and --noclobber cannot be set at the same time.".to_string()).into());
     }
@@ -146,43 +200,79 @@ fn main() -> Result<()> {
     }
 
 
+    let config = Arc::new(Config::from(&opts));
+
+    if let Some(format) = opts.dry_run {
+        let plan = CopyPlan::new(sources, &dest, &config)?;
+        return plan::print_plan(&plan, format);
+    }
+
     // ========== Start copy ============
 
-    let config = Arc::new(Config::from(&opts));
     let driver = load_driver(opts.driver, &config)?;
+    let control = driver.controller();
+    handle_signals(control.clone())?;
 
     let updater = ChannelUpdater::new(&config);
     let stat_rx = updater.rx_channel();
     let stats: Arc<dyn StatusUpdater> = Arc::new(updater);
 
+    let move_config = opts.move_files.then(|| config.clone());
     let handle = thread::spawn(move || -> Result<()> {
-        driver.copy(sources, &dest, stats)
+        match move_config {
+            Some(config) => move_files(driver.as_ref(), sources, &dest, &config, stats),
+            None => driver.copy(sources, &dest, stats),
+        }
     });
 
 
     // ========== Collect output and display ============
 
     let pb = progress::create_bar(&opts, 0)?;
+    let mut errors = Vec::new();
 
     // Gather the results as we go; our end of the channel has been
     // moved to the driver call and will end when drained.
     for stat in stat_rx {
+        pb.update(&stat);
         match stat {
-            StatusUpdate::Copied(v) => pb.inc(v),
-            StatusUpdate::Size(v) => pb.inc_size(v),
+            StatusUpdate::Deleted(p) => {
+                // JSON progress reports these itself.
+                if opts.delete_dry_run && opts.progress_mode() != Progress::Json {
+                    println!("Would delete {}", p.display());
+                } else {
+                    info!("Deleted {}", p.display());
+                }
+            }
             StatusUpdate::Error(e) => {
-                // FIXME: Optional continue?
                 error!("Received error: {e}");
-                return Err(e.into());
+                if !opts.keep_going {
+                    pb.end(false);
+                    return Err(e.into());
+                }
+                errors.push(e);
             }
+            _ => {}
         }
     }
 
-    handle.join()
-        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))??;
-
+    let result = handle.join()
+        .map_err(|_| XcpError::CopyError("Error during copy operation".to_string()))?;
+    pb.end(result.is_ok() && errors.is_empty());
+    if control.is_cancelled() {
+        error!("Copy cancelled");
+        process::exit(INTERRUPTED);
+    }
+    if !errors.is_empty() {
+        // Moves report a final error if any sources were kept.
+        if let Err(e) = result {
+            error!("{e}");
+        }
+        print_error_summary(&errors);
+        process::exit(PARTIAL_FAILURE);
+    }
+    result?;
     info!("Copy complete");
-    pb.end();
 
     Ok(())
 }
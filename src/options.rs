@@ -14,14 +14,25 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use clap::{ArgAction, Parser};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
 
-use libxcp::config::{Backup, Config, Reflink};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use libxcp::config::{Backup, Config, Dereference, HashType, IoClass, IoPriority, Preserve, Reflink, Sparse, UnsafeLinks, Update, Verify};
 use log::LevelFilter;
 use unbytify::unbytify;
 
 use libxcp::drivers::Drivers;
-use libxcp::errors::Result;
+use libxcp::errors::{Result, XcpError};
+use libxcp::paths::{file_types, Filter, FilterRule};
+
+use crate::plan::PlanFormat;
+use crate::progress::Progress;
+
+// Priorities for '--background'.
+const BACKGROUND_IONICE: IoPriority = IoPriority { class: IoClass::BestEffort, level: 7 };
+const BACKGROUND_NICE: i32 = 19;
 
 #[derive(Clone, Debug, Parser)]
 #[command(
@@ -40,13 +51,47 @@ pub struct Opts {
     #[arg(short, long)]
     pub recursive: bool,
 
-    /// Dereference symlinks in source
+    /// Archive mode.
+    ///
+    /// Copy directories recursively and preserve all attributes;
+    /// same as '-r --preserve=all'.
+    #[arg(short, long)]
+    pub archive: bool,
+
+    /// Dereference all symlinks in source
     ///
     /// Follow symlinks, possibly recursively, when copying source
-    /// files.
-    #[arg(short = 'L', long)]
+    /// files. Links to their own parent directories are reported as
+    /// errors, and directories reached by more than one link are only
+    /// copied once.
+    #[arg(short = 'L', long, overrides_with_all = ["no_dereference", "dereference_args"])]
     pub dereference: bool,
 
+    /// Never dereference symlinks in source (the default)
+    #[arg(short = 'P', long, overrides_with_all = ["dereference", "dereference_args"])]
+    pub no_dereference: bool,
+
+    /// Dereference symlinks given as sources
+    ///
+    /// Symlinks within source directories are copied as links.
+    #[arg(short = 'H', overrides_with_all = ["dereference", "no_dereference"])]
+    pub dereference_args: bool,
+
+    /// Copy the targets of unsafe symlinks
+    ///
+    /// Symlinks that point outside of their source directory, either
+    /// as an absolute path or through '..', are replaced by the file or
+    /// directory they point to.
+    #[arg(long, conflicts_with = "safe_links")]
+    pub copy_unsafe_links: bool,
+
+    /// Skip unsafe symlinks
+    ///
+    /// Symlinks that point outside of their source directory are not
+    /// copied; see '--copy-unsafe-links'.
+    #[arg(long)]
+    pub safe_links: bool,
+
     /// Number of parallel workers.
     ///
     /// Default is 4; if the value is negative or 0 it uses the number
@@ -65,6 +110,134 @@ pub struct Opts {
     #[arg(short, long)]
     pub no_clobber: bool,
 
+    /// Only replace files that are out of date.
+    ///
+    /// 'older' (the default if no value is given) only replaces target
+    /// files that are older than the source or differ in size; 'none'
+    /// never replaces existing files, without failing as
+    /// '--no-clobber' does; 'all' replaces all files. Default is
+    /// 'all'.
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true,
+          default_value = "all", default_missing_value = "older")]
+    pub update: Update,
+
+    /// Same as '--update=older'.
+    #[arg(short = 'u')]
+    pub update_older: bool,
+
+    /// Tolerance in seconds when comparing modification times.
+    ///
+    /// With '--update=older' the source is only considered newer if its
+    /// modification time is more than this many seconds later than the
+    /// target's. Useful for filesystems with coarse timestamps. Default
+    /// is 0.
+    #[arg(long, value_name = "SECS", default_value = "0")]
+    pub modify_window: u64,
+
+    /// Compare file contents rather than modification times.
+    ///
+    /// Decides whether a file is up to date by comparing checksums of
+    /// the source and target. Implies '--update=older' if no other
+    /// update mode is given.
+    ///
+    /// Files of equal size are read in full while the copy is being
+    /// planned, one at a time, before they are queued for copying;
+    /// this can be slow for large trees.
+    #[arg(long)]
+    pub checksum: bool,
+
+    /// Show what would be copied, without copying.
+    ///
+    /// Prints each planned operation, with any conflicts with existing
+    /// files and the backups that would be made. FORMAT is 'human'
+    /// (the default) or 'json'.
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, require_equals = true,
+          default_missing_value = "human")]
+    pub dry_run: Option<PlanFormat>,
+
+    /// Delete extraneous files from the target.
+    ///
+    /// Once the copy is complete, remove any files and directories
+    /// under the copied target directories that are not present in the
+    /// source. Files excluded by '--gitignore' or the filter rules are
+    /// not removed.
+    #[arg(long)]
+    pub delete: bool,
+
+    /// Move files rather than copying them.
+    ///
+    /// Sources on the same filesystem as the destination are renamed.
+    /// Others are copied recursively with all attributes, as with
+    /// '--archive', and each source file is removed once its copy has
+    /// been checked against it. Sources are not removed if the copy
+    /// fails. Attributes can be excluded with '--no-preserve'.
+    #[arg(long = "move")]
+    pub move_files: bool,
+
+    /// Continue after errors.
+    ///
+    /// Files that fail to copy are skipped and the copy continues. A
+    /// summary of the failures is printed at the end, and xcp exits
+    /// with status 23.
+    #[arg(long)]
+    pub keep_going: bool,
+
+    /// Limit the copy bandwidth.
+    ///
+    /// The maximum rate, per second, at which data is copied, shared
+    /// between all workers. Accepts standard size modifiers like "M"
+    /// and "GB". 0 is unlimited.
+    #[arg(long, value_name = "RATE", value_parser=unbytify)]
+    pub bwlimit: Option<u64>,
+
+    /// Limit the bandwidth of reads.
+    ///
+    /// As '--bwlimit', but also applies to files read back for
+    /// '--verify', '--checksum' and '--manifest'.
+    #[arg(long, value_name = "RATE", value_parser=unbytify)]
+    pub read_bwlimit: Option<u64>,
+
+    /// Limit the bandwidth of writes to the target.
+    #[arg(long, value_name = "RATE", value_parser=unbytify)]
+    pub write_bwlimit: Option<u64>,
+
+    /// Run as a low-impact background copy.
+    ///
+    /// Lowers the IO and CPU priority of the copy workers (as with
+    /// '--ionice=best-effort:7 --nice=19'), and drops each file from
+    /// the page cache once copied so other data isn't evicted.
+    #[arg(long)]
+    pub background: bool,
+
+    /// IO scheduling priority of the copy workers.
+    ///
+    /// CLASS is 'realtime', 'best-effort' or 'idle' (or 1-3, as with
+    /// 'ionice -c'), and LEVEL is 0-7, with 0 the highest
+    /// priority. Currently only supported on Linux.
+    #[arg(long, value_name = "CLASS[:LEVEL]")]
+    pub ionice: Option<IoPriority>,
+
+    /// CPU niceness of the copy workers.
+    ///
+    /// Currently only supported on Linux.
+    #[arg(long, value_name = "N", allow_negative_numbers = true)]
+    pub nice: Option<i32>,
+
+    /// Show what '--delete' would remove, without removing it.
+    ///
+    /// Implies '--delete'; files are still copied.
+    #[arg(long)]
+    pub delete_dry_run: bool,
+
+    /// Move files removed by '--delete' into this directory.
+    ///
+    /// Files keep their path relative to the target directory. Any
+    /// existing files in the backup directory are not overwritten;
+    /// numbered backups are used instead. The directory should be on
+    /// the same filesystem as the target.
+    #[arg(long, value_name = "DIR")]
+    pub backup_dir: Option<PathBuf>,
+
     /// Force (compatability only)
     ///
     /// Overwrite files; this is the default behaviour, this flag is
@@ -74,14 +247,112 @@ pub struct Opts {
     #[arg(short = 'f', long = "force")]
     pub force: bool,
 
-    /// Use .gitignore if present.
+    /// Honour .gitignore and other ignore files.
     ///
-    /// NOTE: This is fairly basic at the moment, and only honours a
-    /// .gitignore in the directory root for directory copies; global
-    /// or sub-directory ignores are skipped.
+    /// Ignore files are read as git does, including nested and parent
+    /// .gitignore files within the repository, .git/info/exclude and
+    /// the global excludes file; files tracked by git are always
+    /// copied, and .git directories are skipped. .ignore and
+    /// .xcpignore files are also read, taking precedence in that
+    /// order.
     #[arg(long)]
     pub gitignore: bool,
 
+    /// Exclude files matching PATTERN.
+    ///
+    /// Patterns are matched against paths relative to each source. A
+    /// leading '/' anchors the pattern to the source directory, and a
+    /// trailing '/' only matches directories. '*' does not match '/',
+    /// while '**' matches any number of directories. Filter rules are
+    /// checked in the order given, and the first match applies.
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRule::exclude)]
+    pub exclude: Vec<FilterRule>,
+
+    /// Include files matching PATTERN, overriding later excludes.
+    ///
+    /// Note that excluding a directory excludes everything under it;
+    /// e.g. to copy only Rust files use
+    /// "--include='*/' --include='*.rs' --exclude='*'".
+    #[arg(long, value_name = "PATTERN", value_parser = FilterRule::include)]
+    pub include: Vec<FilterRule>,
+
+    /// Read exclude patterns from FILE, one per line.
+    ///
+    /// Blank lines, and lines starting with '#' or ';', are ignored.
+    #[arg(long, value_name = "FILE")]
+    pub exclude_from: Vec<PathBuf>,
+
+    /// Add a filter rule.
+    ///
+    /// Accepts rsync-style '+ PATTERN' and '- PATTERN' rules (or
+    /// 'include PATTERN' and 'exclude PATTERN').
+    #[arg(long, value_name = "RULE", allow_hyphen_values = true)]
+    pub filter: Vec<FilterRule>,
+
+    /// The filter rules, in the order given.
+    #[arg(skip)]
+    pub filters: Filter,
+
+    /// Do not create empty directories.
+    ///
+    /// Directories that are empty in the source, or whose contents
+    /// are all excluded, are not created in the target.
+    #[arg(short = 'm', long)]
+    pub prune_empty_dirs: bool,
+
+    /// Only copy files of at least SIZE.
+    ///
+    /// Only regular files are checked; directories, symlinks that are
+    /// not dereferenced, and the sources themselves, are always
+    /// copied. Accepts suffixes such as 'KB', 'MiB' or 'G'.
+    #[arg(long, value_name = "SIZE", value_parser=unbytify)]
+    pub min_size: Option<u64>,
+
+    /// Only copy files of at most SIZE.
+    #[arg(long, value_name = "SIZE", value_parser=unbytify)]
+    pub max_size: Option<u64>,
+
+    /// Only copy files modified after TIME.
+    ///
+    /// TIME may be a UTC date such as '2024-06-01' or '2024-06-01
+    /// 12:00:00', a duration before now such as '7days' or '12h', or
+    /// a file, whose modification time is used.
+    #[arg(long, value_name = "TIME|FILE", value_parser = parse_time)]
+    pub newer_than: Option<SystemTime>,
+
+    /// Only copy files modified before TIME.
+    ///
+    /// TIME is as for '--newer-than'.
+    #[arg(long, value_name = "TIME|FILE", value_parser = parse_time)]
+    pub older_than: Option<SystemTime>,
+
+    /// Only copy files of TYPE.
+    ///
+    /// Types are named sets of file globs, as used by ripgrep; e.g.
+    /// 'rust', 'json' or 'cpp'. May be given multiple times.
+    #[arg(long = "type", value_name = "TYPE", value_parser = parse_file_type)]
+    pub file_types: Vec<String>,
+
+    /// Stay on the source filesystems.
+    ///
+    /// Directories on other filesystems, such as mount points, are
+    /// created but not copied into.
+    #[arg(short = 'x', long)]
+    pub one_file_system: bool,
+
+    /// Descend at most N levels below the source directories.
+    ///
+    /// A depth of 1 copies only the immediate contents of each source.
+    #[arg(long, value_name = "N")]
+    pub max_depth: Option<usize>,
+
+    /// Only copy files at least N levels below the source directories.
+    ///
+    /// Directories above this depth are still created; see
+    /// '--prune-empty-dirs'.
+    #[arg(long, value_name = "N")]
+    pub min_depth: Option<usize>,
+
     /// Expand file patterns.
     ///
     /// Glob (expand) filename patterns natively (note; the shell may still do its own expansion first)
@@ -89,14 +360,56 @@ pub struct Opts {
     pub glob: bool,
 
     /// Disable progress bar.
+    ///
+    /// Same as '--progress=none'.
     #[arg(long)]
     pub no_progress: bool,
 
+    /// How to report progress.
+    ///
+    /// 'bar' (the default) shows a progress bar. 'lines' prints a
+    /// plain-text progress line every second, and 'json' prints one
+    /// JSON object per line for each event, ending with a summary;
+    /// both write to stdout unless '--progress-fd' is given. 'none'
+    /// disables progress output.
+    #[arg(long, value_name = "MODE", default_value = "bar")]
+    pub progress: Progress,
+
+    /// File descriptor to write 'lines' and 'json' progress to.
+    #[arg(long, value_name = "FD")]
+    pub progress_fd: Option<u32>,
+
+    /// Attributes to preserve.
+    ///
+    /// A comma-separated list of 'mode', 'ownership', 'timestamps',
+    /// 'links', 'xattr', 'acl', 'flags' or 'all'. These are added to
+    /// the default of 'mode,timestamps,xattr,acl'. If no list is
+    /// given 'mode,ownership,timestamps' is used.
+    #[arg(long, value_name = "LIST", num_args = 0..=1, require_equals = true,
+          default_missing_value = "mode,ownership,timestamps")]
+    pub preserve: Option<Preserve>,
+
+    /// Attributes not to preserve.
+    ///
+    /// Takes the same list as '--preserve'.
+    #[arg(long, value_name = "LIST")]
+    pub no_preserve: Option<Preserve>,
+
+    /// Preserve mode, ownership and timestamps.
+    ///
+    /// Same as '--preserve=mode,ownership,timestamps'.
+    #[arg(short = 'p')]
+    pub preserve_basic: bool,
+
     /// Do not copy the file permissions.
+    ///
+    /// Same as '--no-preserve=mode,xattr,acl'.
     #[arg(long)]
     pub no_perms: bool,
 
     /// Do not copy the file timestamps.
+    ///
+    /// Same as '--no-preserve=timestamps'.
     #[arg(long)]
     pub no_timestamps: bool,
 
@@ -105,10 +418,18 @@ pub struct Opts {
     /// Whether to copy ownship (user/group).  This option requires
     /// root permissions or appropriate capabilities; if the attempt
     /// to copy ownership fails a warning is issued but the operation
-    /// continues.
+    /// continues. Same as '--preserve=ownership'.
     #[arg(short, long)]
     pub ownership: bool,
 
+    /// Preserve hard links.
+    ///
+    /// Files within the copied tree that are hard-linked to each
+    /// other are copied once, and the remaining links recreated in
+    /// the target. Same as '--preserve=links'.
+    #[arg(long)]
+    pub hard_links: bool,
+
     /// Driver to use, defaults to 'file-parallel'.
     ///
     /// Currently there are 2; the default "parfile", which
@@ -147,6 +468,18 @@ pub struct Opts {
     #[arg(long, default_value = "auto")]
     pub reflink: Reflink,
 
+    /// Sparse file options.
+    ///
+    /// How to handle sparse files. 'auto' (the default) will preserve
+    /// any holes in the source file, 'always' will additionally
+    /// detect blocks of zeros and leave holes in their place, and
+    /// 'never' will fully allocate the target file.
+    ///
+    /// Note: 'always' and 'never' disable reflinking when
+    /// '--reflink=auto' is set.
+    #[arg(long, default_value = "auto")]
+    pub sparse: Sparse,
+
     /// Backup options
     ///
     /// Whether to create backups of overwritten files. Current
@@ -157,6 +490,58 @@ pub struct Opts {
     #[arg(long, default_value = "none")]
     pub backup: Backup,
 
+    /// Verify copied files.
+    ///
+    /// Re-read each copied file and compare its checksum to the
+    /// source. 'uncached' (the default if no value is given) will
+    /// sync the target and drop it from the page cache before reading
+    /// it back, 'cached' will not. Default is 'none'.
+    #[arg(long, value_name = "MODE", num_args = 0..=1, require_equals = true,
+          default_value = "none", default_missing_value = "uncached")]
+    pub verify: Verify,
+
+    /// Write a checksum manifest.
+    ///
+    /// Writes a checksum of each copied file to the given file, in a
+    /// format compatible with 'sha256sum -c' (or 'b3sum'/'xxhsum'
+    /// depending on '--hash'). Paths are recorded relative to the
+    /// destination directory, or to the parent of a destination file,
+    /// so the manifest can be checked from there.
+    #[arg(long, value_name = "FILE")]
+    pub manifest: Option<PathBuf>,
+
+    /// Hash algorithm for the manifest.
+    ///
+    /// One of 'sha256' (the default), 'blake3' or 'xxh3'.
+    #[arg(long, default_value = "sha256")]
+    pub hash: HashType,
+
+    /// Write files atomically.
+    ///
+    /// Each file is written to a temporary file in the target
+    /// directory and renamed over the target once complete, so
+    /// partially written files are never visible.
+    #[arg(long)]
+    pub atomic: bool,
+
+    /// Journal completed operations to allow resuming.
+    ///
+    /// Records each completed file, and the blocks written of files
+    /// in-progress, to the given file. If the copy is interrupted it
+    /// can be restarted with '--resume'. Written blocks are synced to
+    /// disk and recorded every 64MiB or 5 seconds. With '--atomic'
+    /// only completed files are recorded.
+    #[arg(long, value_name = "FILE")]
+    pub journal: Option<PathBuf>,
+
+    /// Resume an interrupted copy from the journal.
+    ///
+    /// Files completed by the previous run are skipped, and partially
+    /// copied files are continued, provided the source size and
+    /// modification time are unchanged. Requires '--journal'.
+    #[arg(long, requires = "journal")]
+    pub resume: bool,
+
     /// Path list.
     ///
     /// Source and destination files, or multiple source(s) to a directory.
@@ -165,7 +550,116 @@ pub struct Opts {
 
 impl Opts {
     pub fn from_args() -> Result<Opts> {
-        Ok(Opts::parse())
+        let matches = Opts::command().get_matches();
+        let mut opts = Opts::from_arg_matches(&matches)
+            .unwrap_or_else(|e| e.exit());
+        opts.filters = opts.ordered_filters(&matches)?;
+        opts.update = opts.resolve_update(&matches);
+        // Moves keep all attributes by default; '--no-preserve' still
+        // applies.
+        if opts.move_files {
+            opts.archive = true;
+        }
+        if opts.archive {
+            opts.recursive = true;
+        }
+        Ok(opts)
+    }
+
+    /// Combine the filter options into a single filter, keeping the
+    /// order they were given on the command-line.
+    fn ordered_filters(&self, matches: &ArgMatches) -> Result<Filter> {
+        let indices = |id| matches.indices_of(id).into_iter().flatten();
+        let single = |r: &FilterRule| Filter::new().with(r.clone());
+
+        let mut filters = Vec::new();
+        filters.extend(indices("exclude").zip(self.exclude.iter().map(single)));
+        filters.extend(indices("include").zip(self.include.iter().map(single)));
+        filters.extend(indices("filter").zip(self.filter.iter().map(single)));
+        for (i, file) in indices("exclude_from").zip(&self.exclude_from) {
+            filters.push((i, Filter::read_excludes(file)?));
+        }
+        filters.sort_by_key(|(i, _)| *i);
+
+        let mut filter = Filter::new();
+        for (_, f) in filters {
+            filter.extend(f);
+        }
+        Ok(filter)
+    }
+
+    /// The progress mode, allowing for '--no-progress'.
+    pub fn progress_mode(&self) -> Progress {
+        if self.no_progress {
+            Progress::None
+        } else {
+            self.progress
+        }
+    }
+
+    /// Combine the various preservation flags into a single set.
+    pub fn preserve(&self) -> Preserve {
+        let mut preserve = if self.archive {
+            Preserve::all()
+        } else {
+            Preserve::default()
+        };
+        if self.preserve_basic {
+            preserve = preserve.with(Preserve::basic());
+        }
+        if let Some(p) = self.preserve {
+            preserve = preserve.with(p);
+        }
+        preserve.ownership |= self.ownership;
+        preserve.links |= self.hard_links;
+        if self.no_perms {
+            preserve.mode = false;
+            preserve.xattr = false;
+            preserve.acl = false;
+        }
+        if self.no_timestamps {
+            preserve.timestamps = false;
+        }
+        if let Some(p) = self.no_preserve {
+            preserve = preserve.without(p);
+        }
+        preserve
+    }
+
+    /// Resolve the dereference mode from '-L', '-H' and '-P'; the last
+    /// given wins.
+    pub fn dereference(&self) -> Dereference {
+        if self.dereference {
+            Dereference::Always
+        } else if self.dereference_args {
+            Dereference::CommandLine
+        } else {
+            Dereference::Never
+        }
+    }
+
+    /// Resolve the handling of unsafe symlinks.
+    pub fn unsafe_links(&self) -> UnsafeLinks {
+        if self.copy_unsafe_links {
+            UnsafeLinks::Copy
+        } else if self.safe_links {
+            UnsafeLinks::Skip
+        } else {
+            UnsafeLinks::Keep
+        }
+    }
+
+    /// Resolve the update mode from the various update flags. An
+    /// explicit '--update' always wins; otherwise '-u' and
+    /// '--checksum' imply 'older'.
+    fn resolve_update(&self, matches: &ArgMatches) -> Update {
+        if matches.value_source("update") == Some(ValueSource::CommandLine) {
+            self.update
+        } else if self.update_older || self.checksum {
+            Update::Older
+        } else {
+            self.update
+        }
     }
 
     pub fn log_level(&self) -> LevelFilter {
@@ -178,6 +672,39 @@ impl Opts {
     }
 }
 
+/// Parse a time for '--newer-than' and '--older-than'; a file, a
+/// duration before now, or a date.
+fn parse_time(s: &str) -> Result<SystemTime> {
+    let path = Path::new(s);
+    if path.exists() {
+        return Ok(path.metadata()?.modified()?);
+    }
+    if let Ok(ago) = humantime::parse_duration(s) {
+        return SystemTime::now()
+            .checked_sub(ago)
+            .ok_or_else(|| XcpError::InvalidArguments(format!("Duration out of range: {s}")).into());
+    }
+    // A bare date is taken as midnight.
+    let datetime = if s.contains([' ', 'T']) {
+        s.to_string()
+    } else {
+        format!("{s} 00:00:00")
+    };
+    humantime::parse_rfc3339_weak(&datetime)
+        .map_err(|_| XcpError::InvalidArguments(format!("Not a file, duration or date: {s}")).into())
+}
+
+/// Check a '--type' against the known file types.
+fn parse_file_type(s: &str) -> Result<String> {
+    let types = file_types();
+    if types.iter().any(|(name, _)| name == s) {
+        Ok(s.to_string())
+    } else {
+        let names = types.into_iter().map(|(name, _)| name).collect::<Vec<_>>();
+        Err(XcpError::InvalidArguments(format!("Unknown file type '{s}'; known types are: {}", names.join(", "))).into())
+    }
+}
+
 impl From<&Opts> for Config {
     fn from(opts: &Opts) -> Self {
         Config {
@@ -186,21 +713,50 @@ impl From<&Opts> for Config {
             } else {
                 opts.workers
             },
-            block_size: if opts.no_progress {
+            block_size: if opts.progress_mode() == Progress::None {
                 usize::MAX as u64
             } else {
                 opts.block_size
             },
             gitignore: opts.gitignore,
+            filter: opts.filters.clone(),
+            prune_empty_dirs: opts.prune_empty_dirs,
+            min_size: opts.min_size,
+            max_size: opts.max_size,
+            newer_than: opts.newer_than,
+            older_than: opts.older_than,
+            file_types: opts.file_types.clone(),
+            one_file_system: opts.one_file_system,
+            max_depth: opts.max_depth,
+            min_depth: opts.min_depth,
             no_clobber: opts.no_clobber,
-            no_perms: opts.no_perms,
-            no_timestamps: opts.no_timestamps,
-            ownership: opts.ownership,
-            dereference: opts.dereference,
+            preserve: opts.preserve(),
+            dereference: opts.dereference(),
+            unsafe_links: opts.unsafe_links(),
             no_target_directory: opts.no_target_directory,
             fsync: opts.fsync,
             reflink: opts.reflink,
+            sparse: opts.sparse,
             backup: opts.backup,
+            verify: opts.verify,
+            manifest: opts.manifest.clone(),
+            hash: opts.hash,
+            journal: opts.journal.clone(),
+            resume: opts.resume,
+            atomic: opts.atomic,
+            update: opts.update,
+            modify_window: Duration::from_secs(opts.modify_window),
+            checksum: opts.checksum,
+            delete: opts.delete || opts.delete_dry_run,
+            delete_dry_run: opts.delete_dry_run,
+            backup_dir: opts.backup_dir.clone(),
+            keep_going: opts.keep_going,
+            bwlimit: opts.bwlimit,
+            read_bwlimit: opts.read_bwlimit,
+            write_bwlimit: opts.write_bwlimit,
+            ionice: opts.ionice.or(opts.background.then_some(BACKGROUND_IONICE)),
+            nice: opts.nice.or(opts.background.then_some(BACKGROUND_NICE)),
+            drop_cache: opts.background,
         }
     }
 }
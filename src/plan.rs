@@ -0,0 +1,88 @@
+/*
+ * Copyright © 2024, Steve Smith <tarkasteve@gmail.com>
+ *
+ * This program is free software: you can redistribute it and/or
+ * modify it under the terms of the GNU General Public License version
+ * 3 as published by the Free Software Foundation.
+ *
+ * This program is distributed in the hope that it will be useful, but
+ * WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the GNU
+ * General Public License for more details.
+ *
+ * You should have received a copy of the GNU General Public License
+ * along with this program.  If not, see <https://www.gnu.org/licenses/>.
+ */
+
+use std::io::{stdout, Write};
+use std::result;
+use std::str::FromStr;
+
+use libxcp::errors::{Result, XcpError};
+use libxcp::plan::{Conflict, CopyPlan, Operation, PlannedOperation, Skip};
+
+/// Output format for `--dry-run`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlanFormat {
+    Human,
+    Json,
+}
+
+impl FromStr for PlanFormat {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "human" => Ok(PlanFormat::Human),
+            "json" => Ok(PlanFormat::Json),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'dry-run': {s}"))),
+        }
+    }
+}
+
+fn describe(planned: &PlannedOperation) -> String {
+    let (action, from, to) = match &planned.operation {
+        Operation::Copy(from, to) => ("copy", from, to),
+        Operation::Link(from, to) => ("symlink", from, to),
+        Operation::HardLink(from, to) => ("hardlink", from, to),
+        Operation::Special(from, to) => ("special", from, to),
+        Operation::CreateDir(from, to) => ("mkdir", from, to),
+    };
+    let mut line = format!("{action:<9}{} -> {}", from.display(), to.display());
+    if let Operation::Copy(..) = planned.operation {
+        line.push_str(&format!(" ({} bytes)", planned.size));
+    }
+    match planned.skip {
+        Some(Skip::Completed) => line.push_str(" [skip: completed]"),
+        Some(Skip::UpToDate) => line.push_str(" [skip: up to date]"),
+        None => {}
+    }
+    match planned.conflict {
+        Some(Conflict::Overwrite) => line.push_str(" [overwrite]"),
+        Some(Conflict::NoClobber) => line.push_str(" [exists: will fail]"),
+        None => {}
+    }
+    if let Some(backup) = &planned.backup {
+        line.push_str(&format!(" [backup: {}]", backup.display()));
+    }
+    line
+}
+
+/// Print a plan to stdout.
+pub fn print_plan(plan: &CopyPlan, format: PlanFormat) -> Result<()> {
+    let mut out = stdout().lock();
+    match format {
+        PlanFormat::Human => {
+            for planned in &plan.operations {
+                writeln!(out, "{}", describe(planned))?;
+            }
+            writeln!(out, "Total: {} operations, {} bytes to copy, {} conflicts",
+                     plan.operations.len(), plan.total_bytes, plan.conflicts().count())?;
+        }
+        PlanFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, plan)?;
+            writeln!(out)?;
+        }
+    }
+    Ok(())
+}
@@ -14,52 +14,79 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use crate::options::Opts;
+use std::cell::RefCell;
+use std::fs::OpenOptions;
+use std::io::{stdout, Write};
+use std::path::Path;
+use std::result;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use libxcp::errors::Result;
+use indicatif::HumanBytes;
+use libxcp::errors::{Result, XcpError};
+use libxcp::feedback::{CopyStats, StatusUpdate};
+use log::debug;
+use serde_json::{json, Value};
 use terminal_size::Width;
 
-struct NoopBar;
+use crate::options::Opts;
 
-struct VisualBar {
-    bar: indicatif::ProgressBar,
+/// Progress output mode for `--progress`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Progress {
+    Bar,
+    Lines,
+    None,
+    Json,
+}
+
+impl FromStr for Progress {
+    type Err = XcpError;
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bar" => Ok(Progress::Bar),
+            "lines" => Ok(Progress::Lines),
+            "none" => Ok(Progress::None),
+            "json" => Ok(Progress::Json),
+            _ => Err(XcpError::InvalidArguments(format!("Unexpected value for 'progress': {s}"))),
+        }
+    }
 }
 
 pub trait ProgressBar {
-    #[allow(unused)]
-    fn set_size(&self, size: u64);
-    fn inc_size(&self, size: u64);
-    fn inc(&self, size: u64);
-    fn end(&self);
+    fn update(&self, update: &StatusUpdate);
+    fn end(&self, success: bool);
 }
 
+struct NoopBar;
 
 impl ProgressBar for NoopBar {
-    fn set_size(&self, _size: u64) {
+    fn update(&self, _update: &StatusUpdate) {
     }
-    fn inc_size(&self, _size: u64) {
-    }
-    fn inc(&self, _size: u64) {
-    }
-    fn end(&self) {
+    fn end(&self, _success: bool) {
     }
 }
 
-impl ProgressBar for VisualBar {
-    fn set_size(&self, size: u64) {
-        self.bar.set_length(size);
-    }
-
-    fn inc_size(&self, size: u64) {
-        self.bar.inc_length(size);
-    }
+struct VisualBar {
+    bar: indicatif::ProgressBar,
+}
 
-    fn inc(&self, size: u64) {
-        self.bar.inc(size);
+impl ProgressBar for VisualBar {
+    fn update(&self, update: &StatusUpdate) {
+        match update {
+            StatusUpdate::Copied(n) | StatusUpdate::Skipped { size: n, .. } => self.bar.inc(*n),
+            StatusUpdate::Size(n) => self.bar.inc_length(*n),
+            _ => {}
+        }
     }
 
-    fn end(&self) {
-        self.bar.finish();
+    fn end(&self, success: bool) {
+        if success {
+            self.bar.finish();
+        } else {
+            self.bar.abandon();
+        }
     }
 }
 
@@ -79,10 +106,156 @@ impl VisualBar {
     }
 }
 
-pub fn create_bar(opts: &Opts, size: u64) -> Result<Box<dyn ProgressBar>> {
-    if opts.no_progress {
-        Ok(Box::new(NoopBar {}))
-    } else {
-        Ok(Box::new(VisualBar::new(size)?))
+// Minimum time between lines in `lines` mode.
+const LINE_INTERVAL: Duration = Duration::from_secs(1);
+
+// Periodic plain-text progress, for logs and other non-terminal
+// output.
+struct LinesBar {
+    out: RefCell<Box<dyn Write>>,
+    totals: RefCell<CopyStats>,
+    last: RefCell<Instant>,
+}
+
+impl LinesBar {
+    fn print(&self, status: &str) {
+        let totals = self.totals.borrow();
+        let done = totals.copied + totals.skipped;
+        let percent = (done * 100).checked_div(totals.size).unwrap_or(100);
+        let line = format!("{} / {} ({percent}%){status}", HumanBytes(done), HumanBytes(totals.size));
+        if let Err(e) = writeln!(self.out.borrow_mut(), "{line}") {
+            debug!("Failed to write progress: {e}");
+        }
     }
 }
+
+impl ProgressBar for LinesBar {
+    fn update(&self, update: &StatusUpdate) {
+        self.totals.borrow_mut().update(update);
+        if let StatusUpdate::Copied(_) | StatusUpdate::Skipped { .. } = update
+            && self.last.borrow().elapsed() >= LINE_INTERVAL
+        {
+            *self.last.borrow_mut() = Instant::now();
+            self.print("");
+        }
+    }
+
+    fn end(&self, success: bool) {
+        self.print(if success { ", complete" } else { ", failed" });
+    }
+}
+
+/// Break an error down into the path, OS error number and message, where
+/// available.
+pub fn error_details(err: &XcpError) -> (Option<&Path>, Option<i32>, String) {
+    match err {
+        XcpError::OperationFailed(path, msg, errno) => (Some(path), *errno, msg.clone()),
+        XcpError::DestinationExists(msg, path) => (Some(path), None, msg.to_string()),
+        XcpError::VerifyFailed(path) => (Some(path), None, "Target does not match source".to_string()),
+        e => (None, None, e.to_string()),
+    }
+}
+
+fn path_value(path: &Path) -> Value {
+    Value::String(path.to_string_lossy().into_owned())
+}
+
+// One JSON object per line for each update, followed by a summary.
+struct JsonEvents {
+    out: RefCell<Box<dyn Write>>,
+    totals: RefCell<CopyStats>,
+    start: Instant,
+    delete_dry_run: bool,
+}
+
+impl JsonEvents {
+    fn emit(&self, event: Value) {
+        let mut out = self.out.borrow_mut();
+        if let Err(e) = writeln!(out, "{event}").and_then(|_| out.flush()) {
+            debug!("Failed to write progress: {e}");
+        }
+    }
+}
+
+impl ProgressBar for JsonEvents {
+    fn update(&self, update: &StatusUpdate) {
+        self.totals.borrow_mut().update(update);
+        let event = match update {
+            StatusUpdate::Size(n) => json!({"event": "size", "bytes": n}),
+            StatusUpdate::Copied(n) => {
+                json!({"event": "copied", "bytes": n, "total": self.totals.borrow().copied})
+            }
+            StatusUpdate::Skipped { path, size } => {
+                json!({"event": "skipped", "path": path_value(path), "bytes": size})
+            }
+            StatusUpdate::Deleted(path) => {
+                json!({"event": "deleted", "path": path_value(path), "dry_run": self.delete_dry_run})
+            }
+            StatusUpdate::FileStarted { from, to, size } => {
+                json!({"event": "file_started", "from": path_value(from), "to": path_value(to), "size": size})
+            }
+            StatusUpdate::FileFinished { path, method } => {
+                json!({"event": "file_finished", "path": path_value(path), "method": method})
+            }
+            StatusUpdate::SymlinkCreated { from, to } => {
+                json!({"event": "symlink_created", "from": path_value(from), "to": path_value(to)})
+            }
+            StatusUpdate::DirCreated { path } => {
+                json!({"event": "dir_created", "path": path_value(path)})
+            }
+            StatusUpdate::BackedUp { from, to } => {
+                json!({"event": "backed_up", "from": path_value(from), "to": path_value(to)})
+            }
+            StatusUpdate::Error(e) => {
+                let (path, errno, message) = error_details(e);
+                json!({"event": "error", "path": path.map(path_value), "errno": errno, "message": message})
+            }
+            // Updates added to libxcp later have no event yet.
+            _ => return,
+        };
+        self.emit(event);
+    }
+
+    fn end(&self, success: bool) {
+        let totals = self.totals.borrow();
+        self.emit(json!({
+            "event": "summary",
+            "success": success,
+            "size": totals.size,
+            "copied": totals.copied,
+            "skipped": totals.skipped,
+            "files": totals.files,
+            "errors": totals.errors,
+            "elapsed": self.start.elapsed().as_secs_f64(),
+        }));
+    }
+}
+
+// Where to send text progress output; stdout unless a descriptor is
+// given.
+fn output(opts: &Opts) -> Result<Box<dyn Write>> {
+    let out: Box<dyn Write> = match opts.progress_fd {
+        None | Some(1) => Box::new(stdout()),
+        Some(fd) => Box::new(OpenOptions::new().write(true).open(format!("/dev/fd/{fd}"))?),
+    };
+    Ok(out)
+}
+
+pub fn create_bar(opts: &Opts, size: u64) -> Result<Box<dyn ProgressBar>> {
+    let bar: Box<dyn ProgressBar> = match opts.progress_mode() {
+        Progress::None => Box::new(NoopBar {}),
+        Progress::Bar => Box::new(VisualBar::new(size)?),
+        Progress::Lines => Box::new(LinesBar {
+            out: RefCell::new(output(opts)?),
+            totals: RefCell::new(CopyStats { size, ..CopyStats::default() }),
+            last: RefCell::new(Instant::now()),
+        }),
+        Progress::Json => Box::new(JsonEvents {
+            out: RefCell::new(output(opts)?),
+            totals: RefCell::new(CopyStats { size, ..CopyStats::default() }),
+            start: Instant::now(),
+            delete_dry_run: opts.delete_dry_run,
+        }),
+    };
+    Ok(bar)
+}
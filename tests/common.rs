@@ -14,9 +14,11 @@
  * along with this program.  If not, see <https://www.gnu.org/licenses/>.
  */
 
-use std::fs::{create_dir_all, set_permissions, write, File, Permissions};
-use std::os::unix::fs::{chown, symlink, PermissionsExt, MetadataExt};
+use std::fs::{create_dir_all, hard_link, read_link, set_permissions, write, File, Permissions};
+use std::os::unix::fs::{chown, lchown, symlink, PermissionsExt, MetadataExt};
 use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::process::Command;
 use cfg_if::cfg_if;
 use test_case::test_case;
 
@@ -417,6 +419,27 @@ fn file_copy_reflink_never(drv: &str) {
     assert!(files_match(&source_path, &dest_path));
 }
 
+#[test]
+fn reflink_always_sparse_conflict() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let out = run(&[
+        "--reflink=always",
+        "--sparse=never",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("--reflink=always can only be used with --sparse=auto"));
+    assert!(!dest_path.exists());
+}
+
 #[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 #[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
@@ -497,6 +520,99 @@ fn file_copy_no_perms(drv: &str) {
 }
 
 
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn file_copy_no_preserve(drv: &str) {
+    cfg_if! {
+        if #[cfg(feature = "test_no_xattr")] {
+            let fs_supports_xattr = false;
+        } else {
+            let fs_supports_xattr = true;
+        }
+    }
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+    set_time_past(&source_path).unwrap();
+    set_permissions(&source_path, Permissions::from_mode(0o600)).unwrap();
+    if fs_supports_xattr {
+        xattr::set(&source_path, "user.test", b"my test").unwrap();
+    }
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--no-preserve=xattr,timestamps",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+
+    let smeta = source_path.metadata().unwrap();
+    let dmeta = dest_path.metadata().unwrap();
+    assert_eq!(0o600, dmeta.permissions().mode() & 0o7777);
+    assert!(!timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    if fs_supports_xattr {
+        assert!(xattr::get(&dest_path, "user.test").unwrap().is_none());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_preserve_overrides_no_flags(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    create_file(&source_path, "This is a test file.").unwrap();
+    set_time_past(&source_path).unwrap();
+
+    // --no-preserve is applied last
+    let out = run(&[
+        "--driver",
+        drv,
+        "--preserve=timestamps",
+        "--no-preserve=all",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    let smeta = source_path.metadata().unwrap();
+    let dmeta = dest_path.metadata().unwrap();
+    assert!(!timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+}
+
+#[test]
+fn invalid_preserve_list() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "data").unwrap();
+
+    let out = run(&[
+        "--preserve=mode,colour",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Unexpected value for 'preserve': colour"));
+    assert!(!dest_path.exists());
+}
+
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
 fn file_copy_timestamps(drv: &str) {
@@ -982,16 +1098,70 @@ fn dir_copy_containing_symlinks(drv: &str) {
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn dir_copy_with_hidden_dir(drv: &str) {
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_recopy_containing_symlinks(drv: &str) {
     let dir = tempdir_rel().unwrap();
 
-    let source_path = dir.path().join("mydir/.hidden");
-    let source_file = source_path.join("file.txt");
+    let source_path = dir.path().join("mydir");
     create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    symlink("file.txt", source_path.join("link.txt")).unwrap();
+    symlink("/nonexistent", source_path.join("dangling")).unwrap();
 
-    let dest_base = dir.path().join("dest/.hidden");
-    let dest_file = dest_base.join("file.txt");
+    let dest_base = dir.path().join("dest");
+    let copy = |extra: &[&str]| {
+        let mut args = vec!["--driver", drv, "-r"];
+        args.extend_from_slice(extra);
+        args.push(source_path.to_str().unwrap());
+        args.push(dest_base.to_str().unwrap());
+        run(&args).unwrap()
+    };
+
+    // The second copy goes into dest/mydir, the third over it.
+    for _ in 0..3 {
+        let out = copy(&[]);
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    }
+    let dest_link = dest_base.join("mydir/link.txt");
+    assert_eq!(read_link(&dest_link).unwrap(), Path::new("file.txt"));
+    assert_eq!(read_link(dest_base.join("mydir/dangling")).unwrap(), Path::new("/nonexistent"));
+
+    let out = copy(&["--backup=numbered"]);
+    assert!(out.status.success());
+    assert_eq!(read_link(dest_base.join("mydir/link.txt.~1~")).unwrap(), Path::new("file.txt"));
+    assert_eq!(read_link(dest_base.join("mydir/dangling.~1~")).unwrap(), Path::new("/nonexistent"));
+
+    let out = copy(&["--no-clobber"]);
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn dir_copy_perms(drv: &str) {
+    cfg_if! {
+        if #[cfg(feature = "test_no_xattr")] {
+            let fs_supports_xattr = false;
+        } else {
+            let fs_supports_xattr = true;
+        }
+    }
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_sub = source_path.join("sub");
+    let source_ro = source_path.join("readonly");
+    create_dir_all(&source_sub).unwrap();
+    create_dir_all(&source_ro).unwrap();
+    create_file(&source_ro.join("file.txt"), "orig").unwrap();
+    set_permissions(&source_sub, Permissions::from_mode(0o750)).unwrap();
+    set_permissions(&source_ro, Permissions::from_mode(0o555)).unwrap();
+
+    if fs_supports_xattr {
+        xattr::set(&source_sub, "user.test", b"my test").unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
 
     let out = run(&[
         "--driver",
@@ -1003,34 +1173,34 @@ fn dir_copy_with_hidden_dir(drv: &str) {
     .unwrap();
 
     assert!(out.status.success());
-    assert!(dest_file.exists());
-    assert!(file_contains(&dest_file, "orig").unwrap());
-    assert!(files_match(&source_file, &dest_file));
+    assert!(file_contains(&dest_base.join("readonly/file.txt"), "orig").unwrap());
+    let mode = |p: &str| dest_base.join(p).metadata().unwrap().permissions().mode() & 0o7777;
+    assert_eq!(0o750, mode("sub"));
+    assert_eq!(0o555, mode("readonly"));
+
+    #[cfg(any(target_os = "linux", target_os = "freebsd"))]
+    if fs_supports_xattr {
+        assert_eq!(
+            xattr::get(dest_base.join("sub"), "user.test").unwrap().unwrap(),
+            b"my test"
+        );
+    }
+
+    // Allow cleanup
+    set_permissions(&source_ro, Permissions::from_mode(0o755)).unwrap();
+    set_permissions(dest_base.join("readonly"), Permissions::from_mode(0o755)).unwrap();
 }
 
-#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn dir_with_gitignore(drv: &str) {
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+fn dir_copy_no_perms(drv: &str) {
     let dir = tempdir_rel().unwrap();
 
     let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-
-    let source_file = source_path.join("file.txt");
-    create_file(&source_file, "file content").unwrap();
-
-    let ignore_file = source_path.join(".gitignore");
-    create_file(&ignore_file, "/.ignored\n").unwrap();
-
-    let ignored_path = dir.path().join("mydir/.ignored");
-    let ignored_file = ignored_path.join("file.txt");
-    create_dir_all(&ignored_path).unwrap();
-    create_file(&ignored_file, "ignored content").unwrap();
-
-    let hidden_path = dir.path().join("mydir/.hidden");
-    let hidden_file = hidden_path.join("file.txt");
-    create_dir_all(&hidden_path).unwrap();
-    create_file(&hidden_file, "hidden content").unwrap();
+    let source_sub = source_path.join("sub");
+    create_dir_all(&source_sub).unwrap();
+    set_permissions(&source_sub, Permissions::from_mode(0o700)).unwrap();
 
     let dest_base = dir.path().join("dest");
 
@@ -1038,304 +1208,376 @@ fn dir_with_gitignore(drv: &str) {
         "--driver",
         drv,
         "-r",
-        "--gitignore",
+        "--no-perms",
         source_path.to_str().unwrap(),
         dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-    assert!(dest_base.join("file.txt").exists());
-    assert!(dest_base.join(".gitignore").exists());
-
-    assert!(!dest_base.join(".ignored").exists());
-
-    assert!(dest_base.join(".hidden").exists());
-    assert!(dest_base.join(".hidden/file.txt").exists());
+    let mode = dest_base.join("sub").metadata().unwrap().permissions().mode() & 0o7777;
+    assert_ne!(0o700, mode);
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_with_glob(drv: &str) {
+fn dir_copy_timestamps(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
 
-    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
-    create_file(&f1, "test").unwrap();
-    create_file(&f2, "test").unwrap();
+    let source_path = dir.path().join("mydir");
+    let source_sub = source_path.join("sub");
+    create_dir_all(&source_sub).unwrap();
+    create_file(&source_sub.join("file.txt"), "orig").unwrap();
+    set_time_past(&source_sub).unwrap();
+    set_time_past(&source_path).unwrap();
+
+    let dest_base = dir.path().join("dest");
 
     let out = run(&[
         "--driver",
         drv,
-        "--glob",
-        dir.path().join("file*.txt").to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-    assert!(dest.join("file1.txt").exists());
-    assert!(dest.join("file2.txt").exists());
+    for (from, to) in [(&source_path, dest_base.clone()), (&source_sub, dest_base.join("sub"))] {
+        let smeta = from.metadata().unwrap();
+        let dmeta = to.metadata().unwrap();
+        assert!(timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+    }
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_pattern_no_glob(drv: &str) {
+fn dir_copy_no_timestamps(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
 
-    let f1 = dir.path().join("a [b] c.txt");
-    create_file(&f1, "test").unwrap();
+    let source_path = dir.path().join("mydir");
+    let source_sub = source_path.join("sub");
+    create_dir_all(&source_sub).unwrap();
+    set_time_past(&source_sub).unwrap();
+
+    let dest_base = dir.path().join("dest");
 
     let out = run(&[
         "--driver",
         drv,
-        dir.path().join("a [b] c.txt").to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "-r",
+        "--no-timestamps",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-    assert!(dest.join("a [b] c.txt").exists());
+    let smeta = source_sub.metadata().unwrap();
+    let dmeta = dest_base.join("sub").metadata().unwrap();
+    assert!(!timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn glob_pattern_error(drv: &str) {
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_symlink_timestamps(drv: &str) {
+    use rustix::fs::{utimensat, AtFlags, Timespec, Timestamps, CWD};
+
     let dir = tempdir_rel().unwrap();
-    let dest = dir.path().join("dest");
-    create_dir_all(&dest).unwrap();
 
-    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
-    create_file(&f1, "test").unwrap();
-    create_file(&f2, "test").unwrap();
+    let source_path = dir.path().join("mydir");
+    let source_link = source_path.join("link.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    symlink("file.txt", &source_link).unwrap();
+
+    let old = Timespec { tv_sec: 1_000_000_000, tv_nsec: 0 };
+    let times = Timestamps { last_access: old, last_modification: old };
+    utimensat(CWD, &source_link, &times, AtFlags::SYMLINK_NOFOLLOW).unwrap();
+
+    let dest_base = dir.path().join("dest");
 
     let out = run(&[
         "--driver",
         drv,
-        "--glob",
-        dir.path().join("file***.txt").to_str().unwrap(),
-        dest.to_str().unwrap(),
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
-    assert!(!out.status.success());
-    let stderr = String::from_utf8(out.stderr).unwrap();
-    assert!(stderr.contains("Pattern syntax error"));
+    assert!(out.status.success());
+    let lmeta = dest_base.join("link.txt").symlink_metadata().unwrap();
+    assert!(lmeta.file_type().is_symlink());
+    assert_eq!(1_000_000_000, lmeta.mtime());
+    assert_ne!(1_000_000_000, dest_base.join("file.txt").metadata().unwrap().mtime());
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
-fn test_socket_file(drv: &str) {
-
+fn dir_copy_verify(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let from = dir.path().join("from.sock");
-    let to = dir.path().join("to.sock");
 
-    let _sock = UnixListener::bind(&from).unwrap();
-    let ftype = from.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    write(source_path.join("big.bin"), rand_data(1024 * 1024)).unwrap();
+    File::create(source_path.join("empty.bin")).unwrap();
 
-    let out = run(&[
-        "--driver", drv,
-        from.to_str().unwrap(),
-        to.to_str().unwrap(),
-    ]).unwrap();
-    assert!(out.status.success());
+    for verify in ["--verify", "--verify=cached"] {
+        let dest_base = dir.path().join(verify);
+        let out = run(&[
+            "--driver",
+            drv,
+            "-r",
+            verify,
+            "--block-size", "4096",
+            source_path.to_str().unwrap(),
+            dest_base.to_str().unwrap(),
+        ])
+        .unwrap();
 
-    assert!(to.exists());
-    let ftype = to.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+        assert!(out.status.success());
+        assert!(files_match(&source_path.join("big.bin"), &dest_base.join("big.bin")));
+    }
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
-fn test_sockets_dir(drv: &str) {
+fn dir_copy_manifest(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let src_dir = dir.path().join("fromdir");
-    create_dir_all(&src_dir).unwrap();
 
-    let from = src_dir.join("from.sock");
-    let _sock = UnixListener::bind(&from).unwrap();
-    let ftype = from.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+    hard_link(source_path.join("file.txt"), source_path.join("link.txt")).unwrap();
+    symlink("file.txt", source_path.join("symlink.txt")).unwrap();
 
-    let to_dir = dir.path().join("todir");
-    let to = to_dir.join("from.sock");
+    let dest_base = dir.path().join("dest");
+    let manifest = dir.path().join("SHA256SUMS");
 
     let out = run(&[
-        "--driver", drv,
+        "--driver",
+        drv,
         "-r",
-        src_dir.to_str().unwrap(),
-        to_dir.to_str().unwrap(),
-    ]).unwrap();
+        "--hard-links",
+        "--manifest", manifest.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
     assert!(out.status.success());
 
-    assert!(to.exists());
-    let ftype = to.metadata().unwrap().file_type();
-    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+    let orig = "14e0ffdc8215c81da0cde40f581237ee35177ddac4f1fc7613cad3004798d25f";
+    let other = "d9298a10d1b0735837dc4bd85dac641b0f3cef27a47e5d53a54f2f3f5b2fcffa";
+    let mut lines = std::fs::read_to_string(&manifest).unwrap()
+        .lines()
+        .map(str::to_owned)
+        .collect::<Vec<String>>();
+    lines.sort();
+    // Paths are relative to the destination.
+    let mut expected = vec![
+        format!("{orig}  file.txt"),
+        format!("{orig}  link.txt"),
+        format!("{other}  sub/other.txt"),
+    ];
+    expected.sort();
+    assert_eq!(expected, lines);
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
-#[cfg_attr(feature = "test_no_root", ignore = "Not root compatible")]
-fn unreadable_file_error(drv: &str) {
+fn file_copy_manifest_hashes(drv: &str) {
     let dir = tempdir_rel().unwrap();
     let source_path = dir.path().join("source.txt");
     let dest_path = dir.path().join("dest.txt");
-    let text = "This is a test file.";
-
-    create_file(&source_path, text).unwrap();
-
-    let perms = Permissions::from_mode(0o0);
-    set_permissions(&source_path, perms).unwrap();
+    create_file(&source_path, "orig").unwrap();
 
-    let out = run(&[
-        "--driver",
-        drv,
-        source_path.to_str().unwrap(),
-        dest_path.to_str().unwrap(),
-    ])
-    .unwrap();
+    for (hash, digest) in [
+        ("blake3", "61f7f8de49e0c170d618834c48ba2c970d354f09940732591f07fca0fc7e379c"),
+        ("xxh3", "XXH3_8949c32e9581347d"),
+    ] {
+        let manifest = dir.path().join(hash);
+        let out = run(&[
+            "--driver",
+            drv,
+            "--manifest", manifest.to_str().unwrap(),
+            "--hash", hash,
+            source_path.to_str().unwrap(),
+            dest_path.to_str().unwrap(),
+        ])
+        .unwrap();
 
-    assert!(!out.status.success());
+        assert!(out.status.success());
+        let contents = std::fs::read_to_string(&manifest).unwrap();
+        assert!(contents.starts_with(digest));
+        assert!(contents.ends_with("  dest.txt\n"));
+    }
 }
 
-#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
-#[cfg_attr(feature = "test_no_root", ignore = "Not root compatible")]
-fn dest_file_exists_not_writable(drv: &str) {
+fn dir_copy_archive(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_path = dir.path().join("source.txt");
-    let dest_path = dir.path().join("dest.txt");
 
-    {
-        create_file(&source_path, "falskjdfa;lskdjfa").unwrap();
-        File::create(&dest_path).unwrap();
-    }
-    set_permissions(&dest_path, Permissions::from_mode(0o0)).unwrap();
+    let source_path = dir.path().join("mydir");
+    let source_sub = source_path.join("sub");
+    create_dir_all(&source_sub).unwrap();
+    create_file(&source_sub.join("file.txt"), "orig").unwrap();
+    hard_link(source_sub.join("file.txt"), source_path.join("link.txt")).unwrap();
+    set_permissions(&source_sub, Permissions::from_mode(0o750)).unwrap();
+    set_time_past(&source_sub).unwrap();
+
+    let dest_base = dir.path().join("dest");
 
+    // -a implies -r
     let out = run(&[
         "--driver",
         drv,
+        "-a",
         source_path.to_str().unwrap(),
-        dest_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
-    assert!(!out.status.success());
+    assert!(out.status.success());
+    let smeta = source_sub.metadata().unwrap();
+    let dmeta = dest_base.join("sub").metadata().unwrap();
+    assert_eq!(0o750, dmeta.permissions().mode() & 0o7777);
+    assert!(timestamps_same(&smeta.modified().unwrap(), &dmeta.modified().unwrap()));
+    assert_eq!(
+        dest_base.join("sub/file.txt").metadata().unwrap().ino(),
+        dest_base.join("link.txt").metadata().unwrap().ino()
+    );
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-fn copy_dirs_backup(drv: &str) {
+fn dir_copy_hard_links_backup(drv: &str) {
     let dir = tempdir_rel().unwrap();
 
     let source_path = dir.path().join("mydir");
-    let source_file = source_path.join("file.txt");
     create_dir_all(&source_path).unwrap();
-    create_file(&source_file, "orig").unwrap();
+    create_file(&source_path.join("a"), "new").unwrap();
+    hard_link(source_path.join("a"), source_path.join("b")).unwrap();
 
-    let dest_base = dir.path().join("dest");
+    let dest = dir.path().join("dest");
+    let dest_base = dest.join("mydir");
     create_dir_all(&dest_base).unwrap();
-    let dummy = dest_base.join("mydir/dummy_dir"); // Non-file member test
-    create_dir_all(&dummy).unwrap();
-    let dest_file = dest_base.join("mydir/file.txt");
+    create_file(&dest_base.join("a"), "old-a").unwrap();
+    create_file(&dest_base.join("b"), "old-b").unwrap();
 
-    let mut out = run(&[
+    let out = run(&[
         "--driver", drv,
         "-r",
+        "--hard-links",
+        "--backup=numbered",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
-    ])
-    .unwrap();
-
+        dest.to_str().unwrap(),
+    ]).unwrap();
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, "orig").unwrap());
 
-    // -- //
+    // Both the copied file and the link back up their targets.
+    assert!(file_contains(&dest_base.join("a.~1~"), "old-a").unwrap());
+    assert!(file_contains(&dest_base.join("b.~1~"), "old-b").unwrap());
+    assert_eq!(
+        dest_base.join("a").metadata().unwrap().ino(),
+        dest_base.join("b").metadata().unwrap().ino()
+    );
+}
 
-    out = run(&[
-        "--driver", drv,
-        "-r",
-        "--backup=auto",
-        source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
-    ])
-    .unwrap();
-    assert!(out.status.success());
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_preserves_hard_links(drv: &str) {
+    let dir = tempdir_rel().unwrap();
 
-    let backup1 = dest_base.join("mydir/file.txt.~1~");
-    assert!(!backup1.exists());
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    hard_link(source_path.join("file.txt"), source_path.join("sub/link.txt")).unwrap();
+    hard_link(source_path.join("file.txt"), source_path.join("link2.txt")).unwrap();
+    // Large enough to still be in-flight when the links are processed.
+    write(source_path.join("big.bin"), rand_data(1024 * 1024)).unwrap();
+    hard_link(source_path.join("big.bin"), source_path.join("sub/big.bin")).unwrap();
+    create_file(&source_path.join("other.txt"), "other").unwrap();
 
-    write(&source_file, "new content").unwrap();
-    assert!(file_contains(&source_file, "new content").unwrap());
+    let dest_base = dir.path().join("dest");
 
-    out = run(&[
-        "--driver", drv,
+    let out = run(&[
+        "--driver",
+        drv,
         "-r",
-        "--backup=numbered",
+        "--hard-links",
+        "--block-size", "4096",
         source_path.to_str().unwrap(),
         dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, "new content").unwrap());
-    assert!(backup1.exists());
-    assert!(files_match(&source_file, &dest_file));
 
-    // -- //
-    let backup2 = dest_base.join("mydir/file.txt.~2~");
-    assert!(!backup2.exists());
+    let file = dest_base.join("file.txt").metadata().unwrap();
+    assert_eq!(3, file.nlink());
+    assert_eq!(file.ino(), dest_base.join("sub/link.txt").metadata().unwrap().ino());
+    assert_eq!(file.ino(), dest_base.join("link2.txt").metadata().unwrap().ino());
+    assert!(file_contains(&dest_base.join("sub/link.txt"), "orig").unwrap());
 
-    write(&source_file, "new content 2").unwrap();
-    assert!(file_contains(&source_file, "new content 2").unwrap());
+    let big = dest_base.join("big.bin").metadata().unwrap();
+    assert_eq!(2, big.nlink());
+    assert_eq!(big.ino(), dest_base.join("sub/big.bin").metadata().unwrap().ino());
+    assert!(files_match(&source_path.join("big.bin"), &dest_base.join("sub/big.bin")));
 
-    out = run(&[
-        "--driver", drv,
+    assert_eq!(1, dest_base.join("other.txt").metadata().unwrap().nlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_without_hard_links(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    hard_link(source_path.join("file.txt"), source_path.join("link.txt")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver",
+        drv,
         "-r",
-        "--backup=auto",
         source_path.to_str().unwrap(),
         dest_base.to_str().unwrap(),
     ])
     .unwrap();
 
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, "new content 2").unwrap());
-    assert!(backup1.exists());
-    assert!(backup2.exists());
-    assert!(files_match(&source_file, &dest_file));
+    let file = dest_base.join("file.txt").metadata().unwrap();
+    let link = dest_base.join("link.txt").metadata().unwrap();
+    assert_eq!(1, file.nlink());
+    assert_ne!(file.ino(), link.ino());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
-fn dir_copy_deref_symlinks(drv: &str) {
+fn dir_copy_with_hidden_dir(drv: &str) {
     let dir = tempdir_rel().unwrap();
 
-    let source_path = dir.path().join("mydir");
+    let source_path = dir.path().join("mydir/.hidden");
     let source_file = source_path.join("file.txt");
-    let source_rlink = source_path.join("link.txt");
     create_dir_all(&source_path).unwrap();
     create_file(&source_file, "orig").unwrap();
-    symlink("file.txt", source_rlink).unwrap();
-    symlink("/etc/hosts", source_path.join("hosts")).unwrap();
 
-    let dest_base = dir.path().join("dest");
+    let dest_base = dir.path().join("dest/.hidden");
     let dest_file = dest_base.join("file.txt");
-    let dest_rlink = dest_base.join("link.txt");
 
     let out = run(&[
-        "--driver", drv,
-        "--recursive",
-        "--dereference",
+        "--driver",
+        drv,
+        "-r",
         source_path.to_str().unwrap(),
         dest_base.to_str().unwrap(),
     ])
@@ -1343,137 +1585,1670 @@ fn dir_copy_deref_symlinks(drv: &str) {
 
     assert!(out.status.success());
     assert!(dest_file.exists());
-    assert!(! dest_rlink
-        .symlink_metadata()
-        .unwrap()
-        .file_type()
-        .is_symlink());
-    assert!(! dest_base
-        .join("hosts")
-        .symlink_metadata()
-        .unwrap()
-        .file_type()
-        .is_symlink());
+    assert!(file_contains(&dest_file, "orig").unwrap());
+    assert!(files_match(&source_file, &dest_file));
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
-fn test_nested_symlinks(drv: &str) {
+fn dir_with_gitignore(drv: &str) {
     let dir = tempdir_rel().unwrap();
 
     let source_path = dir.path().join("mydir");
     create_dir_all(&source_path).unwrap();
 
-    let file = source_path.join("link-0.txt");
-    create_file(&file, "data").unwrap();
+    let source_file = source_path.join("file.txt");
+    create_file(&source_file, "file content").unwrap();
 
-    for i in 1..10 {
-        let from = source_path.join(format!("link-{}.txt", i-1));
-        let to = source_path.join(format!("link-{i}.txt"));
-        symlink(from, to).unwrap();
-    }
+    let ignore_file = source_path.join(".gitignore");
+    create_file(&ignore_file, "/.ignored\n").unwrap();
+
+    let ignored_path = dir.path().join("mydir/.ignored");
+    let ignored_file = ignored_path.join("file.txt");
+    create_dir_all(&ignored_path).unwrap();
+    create_file(&ignored_file, "ignored content").unwrap();
+
+    let hidden_path = dir.path().join("mydir/.hidden");
+    let hidden_file = hidden_path.join("file.txt");
+    create_dir_all(&hidden_path).unwrap();
+    create_file(&hidden_file, "hidden content").unwrap();
 
     let dest_base = dir.path().join("dest");
 
     let out = run(&[
-        "--driver", drv,
-        "--recursive",
-        "--dereference",
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
         source_path.to_str().unwrap(),
         dest_base.to_str().unwrap(),
-    ]).unwrap();
+    ])
+    .unwrap();
+
     assert!(out.status.success());
+    assert!(dest_base.join("file.txt").exists());
+    assert!(dest_base.join(".gitignore").exists());
 
-    for i in 1..10 {
-        let dest_link = dest_base.join(format!("link-{i}.txt"));
-        assert!(dest_link.exists());
-        assert!(!dest_link.is_symlink());
-        assert!(dest_link.is_file());
-        assert!(file_contains(&dest_link, "data").unwrap());
-    }
+    assert!(!dest_base.join(".ignored").exists());
+
+    assert!(dest_base.join(".hidden").exists());
+    assert!(dest_base.join(".hidden/file.txt").exists());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
-fn test_deep_symlinks(drv: &str) {
+fn dir_with_nested_gitignore(drv: &str) {
     let dir = tempdir_rel().unwrap();
 
     let source_path = dir.path().join("mydir");
-    create_dir_all(&source_path).unwrap();
-
-    let file = source_path.join("link-0.txt");
-    create_file(&file, "data").unwrap();
-
-    for i in 1..100 {
-        let from = source_path.join(format!("link-{}.txt", i-1));
-        let to = source_path.join(format!("link-{i}.txt"));
-        symlink(from, to).unwrap();
+    create_dir_all(source_path.join(".git")).unwrap();
+    create_dir_all(source_path.join("sub/build")).unwrap();
+    create_file(&source_path.join(".git/HEAD"), "ref: refs/heads/main\n").unwrap();
+    create_file(&source_path.join(".gitignore"), "*.log\n").unwrap();
+    create_file(&source_path.join(".ignore"), "secret.txt\n").unwrap();
+    create_file(&source_path.join("sub/.gitignore"), "!keep.log\nbuild/\n").unwrap();
+    for file in ["file.txt", "secret.txt", "sub/keep.log", "sub/drop.log", "sub/build/out.o"] {
+        create_file(&source_path.join(file), "content").unwrap();
     }
 
-    let dest_base = dir.path().join("dest");
+    let dest_dir = dir.path().join("dest");
+    let dest_base = dest_dir.join("mydir");
+    create_dir_all(dest_base.join(".git")).unwrap();
+    create_dir_all(dest_base.join("sub/build")).unwrap();
+    create_file(&dest_base.join(".git/config"), "config").unwrap();
+    create_file(&dest_base.join("sub/build/old.o"), "old").unwrap();
+    create_file(&dest_base.join("stale.txt"), "stale").unwrap();
 
     let out = run(&[
-        "--driver", drv,
-        "--recursive",
-        "--dereference",
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        "--delete",
         source_path.to_str().unwrap(),
-        dest_base.to_str().unwrap(),
-    ]).unwrap();
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
 
-    assert!(!out.status.success());
-    let stderr = String::from_utf8(out.stderr).unwrap();
-    assert!(stderr.contains("Too many levels of symbolic links"));
+    assert!(dest_base.join("file.txt").exists());
+    assert!(dest_base.join("sub/keep.log").exists());
+    assert!(!dest_base.join("sub/drop.log").exists());
+    assert!(!dest_base.join("sub/build/out.o").exists());
+    assert!(!dest_base.join("secret.txt").exists());
+    assert!(!dest_base.join(".git/HEAD").exists());
+
+    // Ignored entries in the target are protected from deletion.
+    assert!(dest_base.join(".git/config").exists());
+    assert!(dest_base.join("sub/build/old.o").exists());
+    assert!(!dest_base.join("stale.txt").exists());
 }
 
 #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
 #[test_case("parfile"; "Test with parallel file driver")]
-#[cfg_attr(not(feature = "test_run_root"), ignore = "Not root, skipping")]
-fn file_copy_ownership(drv: &str) {
-    if rustix::process::geteuid() != rustix::process::Uid::ROOT {
-        panic!("Process is not root");
+fn dir_with_gitignore_in_repo(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("build")).unwrap();
+    let git = |args: &[&str]| Command::new("git")
+        .current_dir(&source_path)
+        .args(args)
+        .output()
+        .is_ok_and(|out| out.status.success());
+    if !git(&["init", "-q"]) {
+        // git is needed to create the index.
+        return;
+    }
+    // Outside of the repository, so not applied.
+    create_file(&dir.path().join(".gitignore"), "*.txt\n").unwrap();
+    create_file(&source_path.join(".gitignore"), "*.log\nbuild/\n").unwrap();
+    for file in ["file.txt", "tracked.log", "untracked.log", "build/tracked.o", "build/untracked.o"] {
+        create_file(&source_path.join(file), "content").unwrap();
     }
+    assert!(git(&["add", "-f", "tracked.log", "build/tracked.o"]));
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(dest_base.join("file.txt").exists());
+    assert!(dest_base.join("tracked.log").exists());
+    assert!(dest_base.join("build/tracked.o").exists());
+    assert!(!dest_base.join("untracked.log").exists());
+    assert!(!dest_base.join("build/untracked.o").exists());
+    assert!(!dest_base.join(".git").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_with_glob(drv: &str) {
     let dir = tempdir_rel().unwrap();
-    let source_dir = dir.path().join("srcdir");
-    let src_int_dir = source_dir.join("inter");
-    create_dir_all(&src_int_dir).unwrap();
-    let source_file = src_int_dir.join("source.txt");
-    let text = "This is a test file.";
-    create_file(&source_file, text).unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
 
+    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
+    create_file(&f1, "test").unwrap();
+    create_file(&f2, "test").unwrap();
 
-    let dest_dir = dir.path().join("dstdir");
-    let dest_int_dir = dest_dir.join("inter");
-    let dest_file = dest_int_dir.join("source.txt");
+    let out = run(&[
+        "--driver",
+        drv,
+        "--glob",
+        dir.path().join("file*.txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
 
-    let id = Some(1);
-    chown(&source_dir, id, id).unwrap();
-    chown(&src_int_dir, id, id).unwrap();
-    chown(&source_file, id, id).unwrap();
+    assert!(out.status.success());
+    assert!(dest.join("file1.txt").exists());
+    assert!(dest.join("file2.txt").exists());
+}
 
-    assert_eq!(1, source_dir.metadata().unwrap().uid());
-    assert_eq!(1, source_dir.metadata().unwrap().gid());
-    assert_eq!(1, src_int_dir.metadata().unwrap().uid());
-    assert_eq!(1, src_int_dir.metadata().unwrap().gid());
-    assert_eq!(1, source_file.metadata().unwrap().uid());
-    assert_eq!(1, source_file.metadata().unwrap().gid());
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_pattern_no_glob(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    let f1 = dir.path().join("a [b] c.txt");
+    create_file(&f1, "test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        dir.path().join("a [b] c.txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest.join("a [b] c.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn glob_pattern_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let dest = dir.path().join("dest");
+    create_dir_all(&dest).unwrap();
+
+    let (f1, f2) = (dir.path().join("file1.txt"), dir.path().join("file2.txt"));
+    create_file(&f1, "test").unwrap();
+    create_file(&f2, "test").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--glob",
+        dir.path().join("file***.txt").to_str().unwrap(),
+        dest.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Pattern syntax error"));
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
+fn test_socket_file(drv: &str) {
+
+    let dir = tempdir_rel().unwrap();
+    let from = dir.path().join("from.sock");
+    let to = dir.path().join("to.sock");
+
+    let _sock = UnixListener::bind(&from).unwrap();
+    let ftype = from.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
 
     let out = run(&[
         "--driver", drv,
-        "--ownership",
-        "--recursive",
-        source_dir.to_str().unwrap(),
-        dest_dir.to_str().unwrap(),
+        from.to_str().unwrap(),
+        to.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    assert!(to.exists());
+    let ftype = to.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_sockets")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_sockets", ignore = "No FS support")]
+fn test_sockets_dir(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let src_dir = dir.path().join("fromdir");
+    create_dir_all(&src_dir).unwrap();
+
+    let from = src_dir.join("from.sock");
+    let _sock = UnixListener::bind(&from).unwrap();
+    let ftype = from.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+
+    let to_dir = dir.path().join("todir");
+    let to = to_dir.join("from.sock");
+
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        src_dir.to_str().unwrap(),
+        to_dir.to_str().unwrap(),
     ]).unwrap();
+    assert!(out.status.success());
+
+    assert!(to.exists());
+    let ftype = to.metadata().unwrap().file_type();
+    assert!(!ftype.is_file() && !ftype.is_dir() && !ftype.is_symlink());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+#[cfg_attr(feature = "test_no_root", ignore = "Not root compatible")]
+fn unreadable_file_error(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    let text = "This is a test file.";
+
+    create_file(&source_path, text).unwrap();
+
+    let perms = Permissions::from_mode(0o0);
+    set_permissions(&source_path, perms).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(all(feature = "parblock", not(feature = "test_no_perms")), test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_perms", ignore = "No FS support")]
+#[cfg_attr(feature = "test_no_root", ignore = "Not root compatible")]
+fn dest_file_exists_not_writable(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+
+    {
+        create_file(&source_path, "falskjdfa;lskdjfa").unwrap();
+        File::create(&dest_path).unwrap();
+    }
+    set_permissions(&dest_path, Permissions::from_mode(0o0)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn copy_dirs_backup(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(&dest_base).unwrap();
+    let dummy = dest_base.join("mydir/dummy_dir"); // Non-file member test
+    create_dir_all(&dummy).unwrap();
+    let dest_file = dest_base.join("mydir/file.txt");
+
+    let mut out = run(&[
+        "--driver", drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
 
     assert!(out.status.success());
-    assert!(file_contains(&dest_file, text).unwrap());
+    assert!(file_contains(&dest_file, "orig").unwrap());
 
-    assert_eq!(1, dest_dir.metadata().unwrap().uid());
-    assert_eq!(1, dest_dir.metadata().unwrap().gid());
-    assert_eq!(1, dest_int_dir.metadata().unwrap().uid());
-    assert_eq!(1, dest_int_dir.metadata().unwrap().gid());
-    assert_eq!(1, dest_file.metadata().unwrap().uid());
-    assert_eq!(1, dest_file.metadata().unwrap().gid());
+    // -- //
+
+    out = run(&[
+        "--driver", drv,
+        "-r",
+        "--backup=auto",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let backup1 = dest_base.join("mydir/file.txt.~1~");
+    assert!(!backup1.exists());
+
+    write(&source_file, "new content").unwrap();
+    assert!(file_contains(&source_file, "new content").unwrap());
+
+    out = run(&[
+        "--driver", drv,
+        "-r",
+        "--backup=numbered",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "new content").unwrap());
+    assert!(backup1.exists());
+    assert!(files_match(&source_file, &dest_file));
+
+    // -- //
+    let backup2 = dest_base.join("mydir/file.txt.~2~");
+    assert!(!backup2.exists());
+
+    write(&source_file, "new content 2").unwrap();
+    assert!(file_contains(&source_file, "new content 2").unwrap());
+
+    out = run(&[
+        "--driver", drv,
+        "-r",
+        "--backup=auto",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, "new content 2").unwrap());
+    assert!(backup1.exists());
+    assert!(backup2.exists());
+    assert!(files_match(&source_file, &dest_file));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    let source_file = source_path.join("file.txt");
+    let source_rlink = source_path.join("link.txt");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_file, "orig").unwrap();
+    symlink("file.txt", source_rlink).unwrap();
+    symlink("/etc/hosts", source_path.join("hosts")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let dest_file = dest_base.join("file.txt");
+    let dest_rlink = dest_base.join("link.txt");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(dest_file.exists());
+    assert!(! dest_rlink
+        .symlink_metadata()
+        .unwrap()
+        .file_type()
+        .is_symlink());
+    assert!(! dest_base
+        .join("hosts")
+        .symlink_metadata()
+        .unwrap()
+        .file_type()
+        .is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn test_nested_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let file = source_path.join("link-0.txt");
+    create_file(&file, "data").unwrap();
+
+    for i in 1..10 {
+        let from = source_path.join(format!("link-{}.txt", i-1));
+        let to = source_path.join(format!("link-{i}.txt"));
+        symlink(from, to).unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+
+    for i in 1..10 {
+        let dest_link = dest_base.join(format!("link-{i}.txt"));
+        assert!(dest_link.exists());
+        assert!(!dest_link.is_symlink());
+        assert!(dest_link.is_file());
+        assert!(file_contains(&dest_link, "data").unwrap());
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn test_deep_symlinks(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+
+    let file = source_path.join("link-0.txt");
+    create_file(&file, "data").unwrap();
+
+    for i in 1..100 {
+        let from = source_path.join(format!("link-{}.txt", i-1));
+        let to = source_path.join(format!("link-{i}.txt"));
+        symlink(from, to).unwrap();
+    }
+
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver", drv,
+        "--recursive",
+        "--dereference",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("Too many levels of symbolic links"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_args(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let real_path = dir.path().join("real");
+    create_dir_all(&real_path).unwrap();
+    create_file(&real_path.join("file.txt"), "orig").unwrap();
+    symlink("file.txt", real_path.join("link.txt")).unwrap();
+    let source_path = dir.path().join("mydir");
+    symlink("real", &source_path).unwrap();
+
+    // The source link is copied as-is with '-P'.
+    let dest_base = dir.path().join("dest-p");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "-L",
+        "-P",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(dest_base.is_symlink());
+
+    // '-H' follows it, but not the link inside.
+    let dest_base = dir.path().join("dest-h");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "-H",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(!dest_base.is_symlink());
+    assert!(file_contains(&dest_base.join("file.txt"), "orig").unwrap());
+    assert!(dest_base.join("link.txt").is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_loop(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    symlink("..", source_path.join("sub/parent")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "-L",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(!out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("File system loop found"));
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_deref_twice(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let outside_path = dir.path().join("outside");
+    create_dir_all(&outside_path).unwrap();
+    create_file(&outside_path.join("file.txt"), "orig").unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    symlink("../outside", source_path.join("one")).unwrap();
+    symlink("../outside", source_path.join("two")).unwrap();
+
+    for (args, dest) in [(["-r", "-L"], "dest-l"), (["-r", "--copy-unsafe-links"], "dest-unsafe")] {
+        let dest_base = dir.path().join(dest);
+        let out = run(&[
+            &["--driver", drv],
+            args.as_slice(),
+            &[source_path.to_str().unwrap(), dest_base.to_str().unwrap()],
+        ].concat()).unwrap();
+        assert!(out.status.success());
+
+        // Only one of the links is walked.
+        let copies = ["one", "two"].iter()
+            .filter(|l| dest_base.join(l).join("file.txt").exists())
+            .count();
+        assert_eq!(1, copies);
+    }
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(feature = "test_no_symlinks", ignore = "No FS support")]
+fn dir_copy_unsafe_links(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let outside_path = dir.path().join("outside");
+    create_dir_all(&outside_path).unwrap();
+    create_file(&outside_path.join("other.txt"), "other").unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    symlink("../file.txt", source_path.join("sub/safe.txt")).unwrap();
+    symlink("../../outside/other.txt", source_path.join("sub/unsafe.txt")).unwrap();
+    symlink("../outside", source_path.join("unsafe-dir")).unwrap();
+
+    let dest_base = dir.path().join("dest-copy");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--copy-unsafe-links",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(dest_base.join("sub/safe.txt").is_symlink());
+    assert!(!dest_base.join("sub/unsafe.txt").is_symlink());
+    assert!(file_contains(&dest_base.join("sub/unsafe.txt"), "other").unwrap());
+    assert!(!dest_base.join("unsafe-dir").is_symlink());
+    assert!(file_contains(&dest_base.join("unsafe-dir/other.txt"), "other").unwrap());
+
+    let dest_base = dir.path().join("dest-safe");
+    let out = run(&[
+        "--driver", drv,
+        "-r",
+        "--safe-links",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ]).unwrap();
+    assert!(out.status.success());
+    assert!(dest_base.join("sub/safe.txt").is_symlink());
+    assert!(dest_base.join("sub/unsafe.txt").symlink_metadata().is_err());
+    assert!(dest_base.join("unsafe-dir").symlink_metadata().is_err());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(not(feature = "test_run_root"), ignore = "Not root, skipping")]
+fn file_copy_ownership(drv: &str) {
+    if rustix::process::geteuid() != rustix::process::Uid::ROOT {
+        panic!("Process is not root");
+    }
+    let dir = tempdir_rel().unwrap();
+    let source_dir = dir.path().join("srcdir");
+    let src_int_dir = source_dir.join("inter");
+    create_dir_all(&src_int_dir).unwrap();
+    let source_file = src_int_dir.join("source.txt");
+    let text = "This is a test file.";
+    create_file(&source_file, text).unwrap();
+
+
+    let dest_dir = dir.path().join("dstdir");
+    let dest_int_dir = dest_dir.join("inter");
+    let dest_file = dest_int_dir.join("source.txt");
+
+    let id = Some(1);
+    chown(&source_dir, id, id).unwrap();
+    chown(&src_int_dir, id, id).unwrap();
+    chown(&source_file, id, id).unwrap();
+
+    assert_eq!(1, source_dir.metadata().unwrap().uid());
+    assert_eq!(1, source_dir.metadata().unwrap().gid());
+    assert_eq!(1, src_int_dir.metadata().unwrap().uid());
+    assert_eq!(1, src_int_dir.metadata().unwrap().gid());
+    assert_eq!(1, source_file.metadata().unwrap().uid());
+    assert_eq!(1, source_file.metadata().unwrap().gid());
+
+    let out = run(&[
+        "--driver", drv,
+        "--ownership",
+        "--recursive",
+        source_dir.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_file, text).unwrap());
+
+    assert_eq!(1, dest_dir.metadata().unwrap().uid());
+    assert_eq!(1, dest_dir.metadata().unwrap().gid());
+    assert_eq!(1, dest_int_dir.metadata().unwrap().uid());
+    assert_eq!(1, dest_int_dir.metadata().unwrap().gid());
+    assert_eq!(1, dest_file.metadata().unwrap().uid());
+    assert_eq!(1, dest_file.metadata().unwrap().gid());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+#[cfg_attr(not(feature = "test_run_root"), ignore = "Not root, skipping")]
+fn symlink_copy_ownership(drv: &str) {
+    if rustix::process::geteuid() != rustix::process::Uid::ROOT {
+        panic!("Process is not root");
+    }
+    let dir = tempdir_rel().unwrap();
+    let source_dir = dir.path().join("srcdir");
+    create_dir_all(&source_dir).unwrap();
+    let source_file = source_dir.join("source.txt");
+    let source_link = source_dir.join("link.txt");
+    create_file(&source_file, "orig").unwrap();
+    symlink("source.txt", &source_link).unwrap();
+
+    let id = Some(1);
+    lchown(&source_link, id, id).unwrap();
+    assert_eq!(0, source_file.metadata().unwrap().uid());
+
+    let dest_dir = dir.path().join("dstdir");
+    let dest_file = dest_dir.join("source.txt");
+    let dest_link = dest_dir.join("link.txt");
+
+    let out = run(&[
+        "--driver", drv,
+        "--ownership",
+        "--recursive",
+        source_dir.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ]).unwrap();
+
+    assert!(out.status.success());
+    let lmeta = dest_link.symlink_metadata().unwrap();
+    assert!(lmeta.file_type().is_symlink());
+    assert_eq!(1, lmeta.uid());
+    assert_eq!(1, lmeta.gid());
+    assert_eq!(0, dest_file.metadata().unwrap().uid());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_resume_skips_completed(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+    symlink("file.txt", source_path.join("symlink.txt")).unwrap();
+
+    // Copy into an existing directory so reruns have the same target.
+    let dest_dir = dir.path().join("dest");
+    create_dir_all(&dest_dir).unwrap();
+    let dest_base = dest_dir.join("mydir");
+    let journal = dir.path().join("journal");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--journal", journal.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    // Completed files are not touched on resume, changed sources
+    // are recopied.
+    create_file(&dest_base.join("file.txt"), "xxxx").unwrap();
+    create_file(&dest_base.join("sub/other.txt"), "xxxxx").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "changed").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--journal", journal.to_str().unwrap(),
+        "--resume",
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_base.join("file.txt"), "xxxx").unwrap());
+    assert!(file_contains(&dest_base.join("sub/other.txt"), "changed").unwrap());
+    assert!(dest_base.join("symlink.txt").symlink_metadata().unwrap().file_type().is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_resume_partial(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    let journal = dir.path().join("journal");
+    let data = rand_data(64 * 1024);
+    std::fs::write(&source_path, &data).unwrap();
+
+    // Simulate an interrupted copy with the first block committed.
+    // The committed range is marked so we can tell it was not
+    // recopied.
+    let mut partial = vec![b'x'; 4096];
+    partial.resize(data.len(), 0);
+    std::fs::write(&dest_path, &partial).unwrap();
+    let meta = source_path.metadata().unwrap();
+    let dest = dest_path.to_str().unwrap();
+    std::fs::write(&journal, format!(
+        "xcp-journal 1\nS {} {} {} {dest}\nR 0 4096 {dest}\n",
+        meta.len(), meta.mtime(), meta.mtime_nsec(),
+    )).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--block-size", "4096",
+        "--journal", journal.to_str().unwrap(),
+        "--resume",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let copied = std::fs::read(&dest_path).unwrap();
+    assert_eq!(&partial[..4096], &copied[..4096]);
+    assert_eq!(&data[4096..], &copied[4096..]);
+}
+
+#[test]
+fn resume_requires_journal() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "orig").unwrap();
+
+    let out = run(&[
+        "--resume",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+    assert!(!dest_path.exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_atomic(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--atomic",
+        "--fsync",
+        "--verify",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_base.join("file.txt"), "orig").unwrap());
+    assert!(file_contains(&dest_base.join("sub/other.txt"), "other").unwrap());
+    // No temporary files left behind
+    assert_eq!(2, dest_base.read_dir().unwrap().count());
+    assert_eq!(1, dest_base.join("sub").read_dir().unwrap().count());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_atomic_backup(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "old").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--atomic",
+        "--backup=numbered",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_path, "new").unwrap());
+    assert!(file_contains(&dir.path().join("dest.txt.~1~"), "old").unwrap());
+    assert_eq!(3, dir.path().read_dir().unwrap().count());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_update_older(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    let dest_dir = dir.path().join("dest");
+    let dest_base = dest_dir.join("mydir");
+    create_dir_all(&dest_base).unwrap();
+
+    // Target newer; skipped
+    create_file(&source_path.join("newer.txt"), "orig").unwrap();
+    set_time_past(&source_path.join("newer.txt")).unwrap();
+    create_file(&dest_base.join("newer.txt"), "dest").unwrap();
+    // Target older; replaced
+    create_file(&dest_base.join("older.txt"), "dest").unwrap();
+    set_time_past(&dest_base.join("older.txt")).unwrap();
+    create_file(&source_path.join("older.txt"), "orig").unwrap();
+    // Different size; replaced
+    create_file(&source_path.join("size.txt"), "orig").unwrap();
+    set_time_past(&source_path.join("size.txt")).unwrap();
+    create_file(&dest_base.join("size.txt"), "longer").unwrap();
+    // Missing; copied
+    create_file(&source_path.join("new.txt"), "orig").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--update",
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_base.join("newer.txt"), "dest").unwrap());
+    assert!(file_contains(&dest_base.join("older.txt"), "orig").unwrap());
+    assert!(file_contains(&dest_base.join("size.txt"), "orig").unwrap());
+    assert!(file_contains(&dest_base.join("new.txt"), "orig").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_update_none(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&dest_path, "old").unwrap();
+    set_time_past(&dest_path).unwrap();
+    create_file(&source_path, "new").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--update=none",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "old").unwrap());
+
+    // An explicit mode overrides '-u'.
+    let out = run(&[
+        "--driver",
+        drv,
+        "-u",
+        "--update=none",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "old").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_update_checksum(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let same_path = dir.path().join("same.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&same_path, "orig").unwrap();
+    set_time_past(&same_path).unwrap();
+    create_file(&dest_path, "dest").unwrap();
+    create_file(&source_path, "orig").unwrap();
+    set_time_past(&source_path).unwrap();
+
+    // Same contents, but the target is older; not replaced.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--checksum",
+        "--no-timestamps",
+        source_path.to_str().unwrap(),
+        same_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    let mtime = same_path.metadata().unwrap().modified().unwrap();
+    assert!(timestamps_same(&mtime, &source_path.metadata().unwrap().modified().unwrap()));
+
+    // An explicit '--update=all' always replaces.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--checksum",
+        "--update=all",
+        "--no-timestamps",
+        source_path.to_str().unwrap(),
+        same_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    let mtime = same_path.metadata().unwrap().modified().unwrap();
+    assert!(!timestamps_same(&mtime, &source_path.metadata().unwrap().modified().unwrap()));
+
+    // Different contents, but the target is newer; replaced.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--checksum",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "orig").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_modify_window(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&dest_path, "dest").unwrap();
+    create_file(&source_path, "orig").unwrap();
+    // Source one second newer than the target.
+    let mtime = dest_path.metadata().unwrap().modified().unwrap();
+    File::options().write(true).open(&source_path).unwrap()
+        .set_modified(mtime + std::time::Duration::from_secs(1)).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-u",
+        "--modify-window", "2",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "dest").unwrap());
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-u",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "orig").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_delete(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    create_file(&source_path.join(".gitignore"), "*.log\n").unwrap();
+
+    let dest_dir = dir.path().join("dest");
+    let dest_base = dest_dir.join("mydir");
+    create_dir_all(dest_base.join("sub")).unwrap();
+    create_dir_all(dest_base.join("old/deeper")).unwrap();
+    create_file(&dest_base.join("sub/stale.txt"), "stale").unwrap();
+    create_file(&dest_base.join("old/deeper/stale.txt"), "stale").unwrap();
+    create_file(&dest_base.join("excluded.log"), "log").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        "--delete-dry-run",
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert!(stdout.contains("sub/stale.txt"));
+    assert!(dest_base.join("sub/stale.txt").exists());
+    assert!(dest_base.join("old").exists());
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--gitignore",
+        "--delete",
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_base.join("file.txt"), "orig").unwrap());
+    assert!(dest_base.join("sub").is_dir());
+    assert!(!dest_base.join("sub/stale.txt").exists());
+    assert!(!dest_base.join("old").exists());
+    // Excluded files are protected
+    assert!(dest_base.join("excluded.log").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_delete_backup_dir(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+
+    let dest_dir = dir.path().join("dest");
+    let dest_base = dest_dir.join("mydir");
+    create_dir_all(dest_base.join("sub")).unwrap();
+    create_file(&dest_base.join("sub/stale.txt"), "stale").unwrap();
+    let backup_dir = dir.path().join("deleted");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--delete",
+        "--backup-dir", backup_dir.to_str().unwrap(),
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(!dest_base.join("sub").exists());
+    assert!(file_contains(&backup_dir.join("sub/stale.txt"), "stale").unwrap());
+}
+
+#[test]
+fn dir_copy_dry_run() {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "orig").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "-r",
+        "--dry-run",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(!dest_base.exists());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let copy = format!("copy     {} -> {} (4 bytes)",
+                       source_path.join("file.txt").display(), dest_base.join("file.txt").display());
+    assert!(stdout.contains(&copy));
+    assert!(stdout.contains(&format!("mkdir    {} -> {}", source_path.display(), dest_base.display())));
+    assert!(stdout.contains("Total: 4 operations, 9 bytes to copy, 0 conflicts"));
+}
+
+#[test]
+fn file_copy_dry_run_json() {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "old").unwrap();
+
+    let out = run(&[
+        "--dry-run=json",
+        "--backup=numbered",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(file_contains(&dest_path, "old").unwrap());
+
+    let plan: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(3, plan["total_bytes"]);
+    let op = &plan["operations"][0];
+    assert_eq!(source_path.to_str().unwrap(), op["operation"]["copy"][0]);
+    assert_eq!(dest_path.to_str().unwrap(), op["operation"]["copy"][1]);
+    assert_eq!("overwrite", op["conflict"]);
+    assert_eq!(dir.path().join("dest.txt.~1~").to_str().unwrap(), op["backup"]);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_move(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+
+    let dest_dir = dir.path().join("dest");
+    create_dir_all(&dest_dir).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--move",
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(!source_path.exists());
+    assert!(file_contains(&dest_dir.join("mydir/file.txt"), "file").unwrap());
+    assert!(file_contains(&dest_dir.join("mydir/sub/other.txt"), "other").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_move_cross_filesystem(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    // Needs a tmpfs to move to; skip if one isn't available.
+    let Ok(other) = tempfile::tempdir_in("/dev/shm") else {
+        return;
+    };
+    if dir.path().metadata().unwrap().dev() == other.path().metadata().unwrap().dev() {
+        return;
+    }
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+    symlink("file.txt", source_path.join("link.txt")).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--move",
+        source_path.to_str().unwrap(),
+        other.path().to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let dest_base = other.path().join("mydir");
+    assert!(!source_path.exists());
+    assert!(file_contains(&dest_base.join("file.txt"), "file").unwrap());
+    assert!(file_contains(&dest_base.join("sub/other.txt"), "other").unwrap());
+    assert!(dest_base.join("link.txt").is_symlink());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_move_no_clobber(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.txt");
+    let dest_path = dir.path().join("dest.txt");
+    create_file(&source_path, "new").unwrap();
+    create_file(&dest_path, "old").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--move",
+        "--no-clobber",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+
+    assert!(file_contains(&source_path, "new").unwrap());
+    assert!(file_contains(&dest_path, "old").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_keep_going(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+
+    // A file in place of the target directory causes the
+    // sub-directory copy to fail.
+    let dest_base = dir.path().join("dest/mydir");
+    create_dir_all(&dest_base).unwrap();
+    create_file(&dest_base.join("sub"), "blocker").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--keep-going",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert_eq!(Some(23), out.status.code());
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("operations failed"));
+    assert!(stderr.contains("sub/other.txt"));
+    assert!(file_contains(&dest_base.join("file.txt"), "file").unwrap());
+
+    // Moves that are copied also summarise the failures.
+    let out = run(&[
+        "--driver",
+        drv,
+        "--move",
+        "--keep-going",
+        "--exclude=*.o",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert_eq!(Some(23), out.status.code());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("operations failed"));
+    assert!(stderr.contains("sub/other.txt"));
+    assert!(source_path.join("sub/other.txt").exists());
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert_eq!(Some(1), out.status.code());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_progress_json(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    create_file(&source_path.join("sub/other.txt"), "other").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--progress=json",
+        source_path.to_str().unwrap(),
+        dir.path().join("dest").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    let events = String::from_utf8(out.stdout).unwrap()
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .collect::<Vec<_>>();
+    let count = |name: &str| events.iter().filter(|e| e["event"] == name).count();
+    assert_eq!(2, count("file_started"));
+    assert_eq!(2, count("file_finished"));
+    assert_eq!(2, count("dir_created"));
+
+    let summary = events.last().unwrap();
+    assert_eq!("summary", summary["event"]);
+    assert_eq!(true, summary["success"]);
+    assert_eq!(9, summary["size"]);
+    assert_eq!(9, summary["copied"]);
+    assert_eq!(2, summary["files"]);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn file_copy_bwlimit(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("source.bin");
+    let dest_path = dir.path().join("dest.bin");
+    write(&source_path, rand_data(256 * 1024)).unwrap();
+
+    let start = std::time::Instant::now();
+    let out = run(&[
+        "--driver",
+        drv,
+        "--reflink=never",
+        "--bwlimit",
+        "256KB",
+        source_path.to_str().unwrap(),
+        dest_path.to_str().unwrap(),
+    ])
+    .unwrap();
+    let elapsed = start.elapsed();
+
+    assert!(out.status.success());
+    assert!(files_match(&source_path, &dest_path));
+    // Around a second at the limit.
+    assert!(elapsed >= std::time::Duration::from_millis(750), "{elapsed:?}");
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_background(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    write(source_path.join("sub/data.bin"), rand_data(64 * 1024)).unwrap();
+    let dest_base = dir.path().join("dest");
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--background",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+
+    assert!(out.status.success());
+    assert!(file_contains(&dest_base.join("file.txt"), "file").unwrap());
+    assert!(files_match(&source_path.join("sub/data.bin"), &dest_base.join("sub/data.bin")));
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--ionice",
+        "idle:9",
+        source_path.join("file.txt").to_str().unwrap(),
+        dest_base.join("other.txt").to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(!out.status.success());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_filters(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("src/empty")).unwrap();
+    create_dir_all(source_path.join("target/release")).unwrap();
+    create_file(&source_path.join("src/main.rs"), "main").unwrap();
+    create_file(&source_path.join("src/main.o"), "object").unwrap();
+    create_file(&source_path.join("src/notes.txt"), "notes").unwrap();
+    create_file(&source_path.join("target/release/xcp"), "binary").unwrap();
+    create_file(&source_path.join("target/other"), "other").unwrap();
+
+    let excludes = dir.path().join("excludes");
+    create_file(&excludes, "# Objects\n*.o\n").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--prune-empty-dirs",
+        "--exclude-from",
+        excludes.to_str().unwrap(),
+        "--filter",
+        "+ /target/release/",
+        "--exclude=/target/*",
+        "--exclude=*.txt",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_base.join("src/main.rs"), "main").unwrap());
+    assert!(file_contains(&dest_base.join("target/release/xcp"), "binary").unwrap());
+    assert!(!dest_base.join("src/main.o").exists());
+    assert!(!dest_base.join("src/notes.txt").exists());
+    assert!(!dest_base.join("src/empty").exists());
+    assert!(!dest_base.join("target/other").exists());
+
+    // Rules are checked in order, so an earlier include wins.
+    let dest_base = dir.path().join("dest2");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--include=notes.txt",
+        "--exclude=*.txt",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+    assert!(dest_base.join("src/notes.txt").exists());
+    assert!(dest_base.join("src/empty").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_predicates(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("old")).unwrap();
+    let big = "x".repeat(2000);
+    create_file(&source_path.join("big.json"), &big).unwrap();
+    create_file(&source_path.join("small.json"), "{}").unwrap();
+    create_file(&source_path.join("big.csv"), &big).unwrap();
+    create_file(&source_path.join("old/big.json"), &big).unwrap();
+    set_time_past(&source_path.join("old/big.json")).unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "--progress=json",
+        "--min-size=1KB",
+        "--newer-than=30days",
+        "--type=json",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(files_match(&source_path.join("big.json"), &dest_base.join("big.json")));
+    assert!(!dest_base.join("small.json").exists());
+    assert!(!dest_base.join("big.csv").exists());
+    assert!(dest_base.join("old").is_dir());
+    assert!(!dest_base.join("old/big.json").exists());
+
+    // Only the selected files are counted.
+    let summary = String::from_utf8(out.stdout).unwrap()
+        .lines()
+        .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+        .next_back()
+        .unwrap();
+    assert_eq!(2000, summary["size"]);
+    assert_eq!(1, summary["files"]);
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_depth_limits(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("a/b")).unwrap();
+    create_file(&source_path.join("top.txt"), "top").unwrap();
+    create_file(&source_path.join("a/one.txt"), "one").unwrap();
+    create_file(&source_path.join("a/b/two.txt"), "two").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "-x",
+        "--min-depth=2",
+        "--max-depth=2",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    assert!(file_contains(&dest_base.join("a/one.txt"), "one").unwrap());
+    assert!(!dest_base.join("top.txt").exists());
+    assert!(dest_base.join("a/b").is_dir());
+    assert!(!dest_base.join("a/b/two.txt").exists());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_copy_delete_excluded(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+    let source_path = dir.path().join("mydir");
+    create_dir_all(&source_path).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+
+    let dest_base = dir.path().join("dest");
+    create_dir_all(dest_base.join("cache")).unwrap();
+    create_file(&dest_base.join("cache/data"), "cached").unwrap();
+    create_file(&dest_base.join("extra.txt"), "extra").unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "-r",
+        "-T",
+        "--delete",
+        "--exclude=cache/",
+        source_path.to_str().unwrap(),
+        dest_base.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    // Excluded entries in the target are not deleted.
+    assert!(dest_base.join("cache/data").exists());
+    assert!(!dest_base.join("extra.txt").exists());
+    assert!(file_contains(&dest_base.join("file.txt"), "file").unwrap());
+}
+
+#[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+#[test_case("parfile"; "Test with parallel file driver")]
+fn dir_move_excluded(drv: &str) {
+    let dir = tempdir_rel().unwrap();
+
+    let source_path = dir.path().join("mydir");
+    create_dir_all(source_path.join("sub")).unwrap();
+    create_file(&source_path.join("file.txt"), "file").unwrap();
+    create_file(&source_path.join("sub/other.o"), "other").unwrap();
+
+    let dest_dir = dir.path().join("dest");
+    create_dir_all(&dest_dir).unwrap();
+
+    let out = run(&[
+        "--driver",
+        drv,
+        "--move",
+        "--exclude=*.o",
+        source_path.to_str().unwrap(),
+        dest_dir.to_str().unwrap(),
+    ])
+    .unwrap();
+    assert!(out.status.success());
+
+    // Excluded files are left behind.
+    assert!(!source_path.join("file.txt").exists());
+    assert!(source_path.join("sub/other.o").exists());
+    assert!(file_contains(&dest_dir.join("mydir/file.txt"), "file").unwrap());
+    assert!(!dest_dir.join("mydir/sub/other.o").exists());
 }
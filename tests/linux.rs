@@ -126,6 +126,46 @@ mod test {
         assert_eq!(from_data, to_data);
     }
 
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_resume(drv: &str) {
+        use std::fs::read;
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+        let journal = dir.path().join("journal");
+
+        let slen = create_sparse(&from, 0, 0).unwrap();
+        assert!(probably_sparse(&from).unwrap());
+
+        // Simulate an interrupted copy with a hole committed; the
+        // remaining holes should not be filled in.
+        File::create(&to).unwrap().set_len(slen).unwrap();
+        let meta = from.metadata().unwrap();
+        let dest = to.to_str().unwrap();
+        std::fs::write(&journal, format!(
+            "xcp-journal 1\nS {} {} {} {dest}\nR 4096 8192 {dest}\n",
+            meta.len(), meta.mtime(), meta.mtime_nsec(),
+        )).unwrap();
+
+        let out = run(&[
+            "--driver", drv,
+            "--block-size", "4096",
+            "--journal", journal.to_str().unwrap(),
+            "--resume",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(probably_sparse(&to).unwrap());
+        assert_eq!(quickstat(&from).unwrap(), quickstat(&to).unwrap());
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
     #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
     #[test_case("parfile"; "Test with parallel file driver")]
     #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
@@ -250,6 +290,167 @@ mod test {
     }
 
 
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_always_zeros(drv: &str) {
+        use std::fs::read;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("zeros.bin");
+        let to = dir.path().join("target.bin");
+        let len = 8 * 1024 * 1024;
+
+        {
+            let mut data = vec![0u8; len];
+            data[..4096].copy_from_slice(&rand_data(4096));
+            data[len / 2..len / 2 + 4096].copy_from_slice(&rand_data(4096));
+            let mut fd = File::create(&from).unwrap();
+            fd.write_all(&data).unwrap();
+        }
+        assert!(!probably_sparse(&from).unwrap());
+
+        let out = run(&[
+            "--driver", drv,
+            "--sparse=always",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(probably_sparse(&to).unwrap());
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_always_preserves_holes(drv: &str) {
+        use std::fs::read;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        create_sparse(&from, 1024, 1024).unwrap();
+        assert!(probably_sparse(&from).unwrap());
+
+        let out = run(&[
+            "--driver", drv,
+            "--sparse=always",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(probably_sparse(&to).unwrap());
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_never(drv: &str) {
+        use std::fs::read;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        create_sparse(&from, 0, 0).unwrap();
+        assert!(probably_sparse(&from).unwrap());
+
+        let out = run(&[
+            "--driver", drv,
+            "--sparse=never",
+            from.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+
+        assert!(!probably_sparse(&to).unwrap());
+        assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_verify(drv: &str) {
+        use std::fs::read;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        let to = dir.path().join("target.bin");
+
+        create_sparse(&from, 0, 0).unwrap();
+
+        for sparse in ["--sparse=auto", "--sparse=always"] {
+            let out = run(&[
+                "--driver", drv,
+                "--verify",
+                sparse,
+                from.to_str().unwrap(),
+                to.to_str().unwrap(),
+            ]).unwrap();
+            assert!(out.status.success());
+
+            assert!(probably_sparse(&to).unwrap());
+            assert_eq!(read(&from).unwrap(), read(&to).unwrap());
+        }
+    }
+
+    #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
+    #[test_case("parfile"; "Test with parallel file driver")]
+    #[cfg_attr(feature = "test_no_sparse", ignore = "No FS support")]
+    fn test_sparse_manifest(drv: &str) {
+        use std::fs::read_to_string;
+
+        let dir = tempdir_rel().unwrap();
+        let from = dir.path().join("sparse.bin");
+        create_sparse(&from, 0, 0).unwrap();
+
+        // Read-back with 'auto', inline hashing otherwise.
+        let mut digests = Vec::new();
+        for sparse in ["auto", "always", "never"] {
+            let to = dir.path().join(sparse);
+            let manifest = dir.path().join(format!("{sparse}.sha256"));
+            let out = run(&[
+                "--driver", drv,
+                "--sparse", sparse,
+                "--block-size", "4096",
+                "--manifest", manifest.to_str().unwrap(),
+                from.to_str().unwrap(),
+                to.to_str().unwrap(),
+            ]).unwrap();
+            assert!(out.status.success());
+            assert!(files_match(&from, &to));
+            assert_eq!(sparse != "never", probably_sparse(&to).unwrap());
+
+            let line = read_to_string(&manifest).unwrap();
+            let (digest, path) = line.trim_end().split_once("  ").unwrap();
+            assert_eq!(sparse, path);
+            digests.push(digest.to_string());
+        }
+        assert_eq!(64, digests[0].len());
+        assert!(digests.iter().all(|d| *d == digests[0]));
+
+        // Zeros are skipped when hashing inline.
+        let zeros = dir.path().join("zeros.bin");
+        std::fs::write(&zeros, vec![0; 1024 * 1024]).unwrap();
+        let to = dir.path().join("zeros-always");
+        let manifest = dir.path().join("zeros.sha256");
+        let out = run(&[
+            "--driver", drv,
+            "--sparse=always",
+            "--manifest", manifest.to_str().unwrap(),
+            zeros.to_str().unwrap(),
+            to.to_str().unwrap(),
+        ]).unwrap();
+        assert!(out.status.success());
+        assert!(files_match(&zeros, &to));
+        assert!(probably_sparse(&to).unwrap());
+    }
+
     #[cfg_attr(feature = "parblock", test_case("parblock"; "Test with parallel block driver"))]
     #[test_case("parfile"; "Test with parallel file driver")]
     #[cfg_attr(not(feature = "test_run_expensive"), ignore = "Stress test")]
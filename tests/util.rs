@@ -245,7 +245,7 @@ pub fn gen_subtree(base: &Path, rng: &mut dyn RngCore, depth: u64, with_sparse:
         let fsize = rng.sample(distf) as u64;
         let fname = gen_file_name(rng, fnlen);
         let path = base.join(fname);
-        let sparse = with_sparse && nfiles % 3 == 0;
+        let sparse = with_sparse && nfiles.is_multiple_of(3);
         gen_file(&path, rng, fsize as usize, sparse)?;
     }
 